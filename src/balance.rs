@@ -1,20 +1,104 @@
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 use near_sdk::json_types::U128;
-use near_sdk::{collections::UnorderedMap, AccountId};
-use std::collections::HashMap;
+use near_sdk::{collections::UnorderedMap, ext_contract, AccountId, Gas, Promise};
 
 use crate::errors::{TOKEN_HAS_NOT_BEEN_DEPOSITED, YOU_HAVE_NOT_ADDED_LIQUIDITY_TO_THIS_POOL};
-use crate::pool::CollectedFee;
 
 pub const GAS_FOR_FT_TRANSFER: u64 = 20_000_000_000_000;
+pub const GAS_FOR_RESOLVE_WITHDRAW: Gas = 10_000_000_000_000;
+pub const GAS_FOR_FT_BALANCE_OF: Gas = 10_000_000_000_000;
+pub const GAS_FOR_RESOLVE_RESCUE: Gas = 30_000_000_000_000;
 
 pub type BalancesMap = UnorderedMap<AccountId, Balance>;
 type Balance = UnorderedMap<AccountId, u128>;
 
 pub use crate::*;
 
+#[ext_contract(ext_withdraw_resolver)]
+trait WithdrawResolver {
+    fn resolve_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128);
+}
+
+#[ext_contract(ext_protocol_fee_resolver)]
+trait ProtocolFeeResolver {
+    fn resolve_protocol_fee(&mut self, pool_id: usize, token: AccountId, amount: U128);
+}
+
+#[ext_contract(ext_rescue_resolver)]
+trait RescueResolver {
+    fn resolve_rescue_stuck_tokens(&mut self, token: AccountId, amount: U128, to: AccountId);
+}
+
 impl Contract {
+    // Tokens the contract owes: account balances, transfers out not resolved yet, tokens locked
+    // in pools, protocol and LP fees and reward budgets held there, whatever it holds above
+    // that was sent to it without a deposit
+    pub fn accounted_amount(&self, token: &AccountId) -> u128 {
+        let mut amount = self.balance_totals.get(token).unwrap_or(0)
+            + self.pending_transfers.get(token).unwrap_or(0);
+        for pool in &self.pools {
+            if &pool.token0 == token {
+                amount += pool.token0_locked + pool.protocol_fees_token0 + pool.lp_fees_token0;
+            } else if &pool.token1 == token {
+                amount += pool.token1_locked + pool.protocol_fees_token1 + pool.lp_fees_token1;
+            }
+            if pool.reward_token.as_ref() == Some(token) {
+                amount += pool.reward_budget;
+            }
+        }
+        amount
+    }
+
+    // Sends protocol fees to the owner, resolve_protocol_fee accrues them back if it fails
+    pub fn transfer_protocol_fee(
+        &mut self,
+        pool_id: usize,
+        token: &AccountId,
+        amount: u128,
+    ) -> Promise {
+        self.add_pending_transfer(token, amount);
+        ext_fungible_token::ft_transfer(
+            self.owner_id.clone(),
+            U128(amount),
+            None,
+            token,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_protocol_fee_resolver::resolve_protocol_fee(
+            pool_id,
+            token.clone(),
+            U128(amount),
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_WITHDRAW,
+        ))
+    }
+
+    // Transfer amounts stay accounted until their resolve callback runs, the token may still
+    // give them back
+    fn add_pending_transfer(&mut self, token: &AccountId, amount: u128) {
+        let pending = self.pending_transfers.get(token).unwrap_or(0);
+        self.pending_transfers.insert(token, &(pending + amount));
+    }
+
+    pub fn remove_pending_transfer(&mut self, token: &AccountId, amount: u128) {
+        let pending = self.pending_transfers.get(token).unwrap_or(0);
+        self.pending_transfers.insert(token, &(pending - amount));
+    }
+
+    pub fn add_to_balance_total(&mut self, token: &AccountId, amount: u128) {
+        let total = self.balance_totals.get(token).unwrap_or(0);
+        self.balance_totals.insert(token, &(total + amount));
+    }
+
+    fn subtract_from_balance_total(&mut self, token: &AccountId, amount: u128) {
+        let total = self.balance_totals.get(token).unwrap_or(0);
+        self.balance_totals.insert(token, &(total - amount));
+    }
+
     pub fn deposit_ft(&mut self, account_id: &AccountId, token_in: &AccountId, amount: u128) {
+        self.add_to_balance_total(token_in, amount);
         if let Some(mut balance) = self.balances_map.get(account_id) {
             let current_value = balance.get(token_in).unwrap_or(0);
             let new_value = current_value + amount;
@@ -27,7 +111,15 @@ impl Contract {
         }
     }
 
-    pub fn balance_withdraw(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
+    // Balance is decreased before the transfer, so the same tokens can't be withdrawn twice
+    // while it is in flight. resolve_withdraw gives them back if the transfer fails
+    pub fn balance_withdraw(
+        &mut self,
+        account_id: &AccountId,
+        token: &AccountId,
+        amount: u128,
+    ) -> Promise {
+        self.assert_no_flash_loan(account_id);
         if let Some(mut balance) = self.balances_map.get(account_id) {
             if let Some(current_amount) = balance.get(token) {
                 let message = format!(
@@ -37,28 +129,41 @@ impl Contract {
                 assert!(amount <= current_amount, "{}", message);
                 balance.insert(token, &(current_amount - amount));
                 self.balances_map.insert(account_id, &balance);
-                ext_fungible_token::ft_transfer(
+                self.subtract_from_balance_total(token, amount);
+                self.add_pending_transfer(token, amount);
+                return ext_fungible_token::ft_transfer(
                     account_id.to_string(),
                     U128(amount),
                     None,
-                    &token,
+                    token,
                     1,
                     GAS_FOR_FT_TRANSFER,
-                );
-                return;
+                )
+                .then(ext_withdraw_resolver::resolve_withdraw(
+                    account_id.clone(),
+                    token.clone(),
+                    U128(amount),
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_RESOLVE_WITHDRAW,
+                ));
             }
         }
         panic!("{}", TOKEN_HAS_NOT_BEEN_DEPOSITED);
     }
 
     pub fn decrease_balance(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
+        self.assert_no_flash_loan(account_id);
         if let Some(mut balance) = self.balances_map.get(account_id) {
-            if let Some(current_amount) = balance.get(token) {
-                let message = format!("Not enough tokens. You want to decrease your balance on {} of {} but only have {}", amount, token, current_amount);
-                assert!(amount <= current_amount, "{}", message);
-                balance.insert(token, &(current_amount - amount));
-                self.balances_map.insert(account_id, &balance);
-            }
+            let current_amount = balance.get(token).unwrap_or(0);
+            let message = format!(
+                "Not enough tokens. You want to decrease your balance on {} of {} but only have {}",
+                amount, token, current_amount
+            );
+            assert!(amount <= current_amount, "{}", message);
+            balance.insert(token, &(current_amount - amount));
+            self.balances_map.insert(account_id, &balance);
+            self.subtract_from_balance_total(token, amount);
         } else {
             let message = format!(
                 "Not enough tokens. You want to decrease your balance on {} of {} but only have {}",
@@ -70,6 +175,7 @@ impl Contract {
 
     pub fn increase_balance(&mut self, account_id: &AccountId, token: &AccountId, amount: u128) {
         if let Some(mut balance) = self.balances_map.get(account_id) {
+            self.add_to_balance_total(token, amount);
             if let Some(current_amount) = balance.get(token) {
                 balance.insert(token, &(current_amount + amount));
                 self.balances_map.insert(account_id, &balance);
@@ -81,18 +187,4 @@ impl Contract {
             panic!("{}", YOU_HAVE_NOT_ADDED_LIQUIDITY_TO_THIS_POOL);
         }
     }
-
-    pub fn apply_collected_fees(
-        &mut self,
-        collected_fees: &HashMap<u128, CollectedFee>,
-        token: &AccountId,
-    ) {
-        for (_, collected_fee) in collected_fees {
-            self.increase_balance(
-                &collected_fee.account_id,
-                token,
-                collected_fee.amount.round() as u128,
-            );
-        }
-    }
 }
@@ -0,0 +1,50 @@
+use near_sdk::json_types::U128;
+use near_sdk::{ext_contract, Gas};
+
+use crate::pool::SwapDirection;
+
+pub const GAS_FOR_COMPLIANCE_CHECK: Gas = 10_000_000_000_000;
+pub const GAS_FOR_RESOLVE_SWAP: Gas = 30_000_000_000_000;
+
+#[ext_contract(ext_compliance_hook)]
+trait ComplianceHook {
+    fn is_allowed(&self, account_id: AccountId) -> bool;
+}
+
+#[ext_contract(ext_self)]
+trait SwapResolver {
+    fn resolve_compliance_swap(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    ) -> U128;
+
+    fn resolve_compliance_swap_exact_out(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        token_out: AccountId,
+        amount_out: U128,
+        max_amount_in: U128,
+    ) -> U128;
+
+    fn resolve_compliance_batch_swap(
+        &mut self,
+        account_id: AccountId,
+        swaps: Vec<(usize, AccountId, U128, SwapDirection)>,
+    ) -> Vec<SwapResult>;
+
+    fn resolve_compliance_close_to_single_token(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        position_id: u128,
+        desired_token: AccountId,
+        min_out: U128,
+    ) -> U128;
+}
@@ -20,3 +20,71 @@ pub const _NO_VALID_OWNER_ID: &str = "No valid owner id";
 pub const _WRONG_TOKEN_AMOUNT: &str = "Wrong token amount chosen";
 pub const INCORRECT_TOKEN: &str = "Incorrect token";
 pub const NOT_ENOUGH_LIQUIDITY_IN_POOL: &str = "Not enough liquidity in pool to cover this swap";
+pub const BAD_FEE_TIER: &str = "Bad fee tier. Allowed values are 5, 30 and 100 basis points";
+pub const MIN_AMOUNT_OUT_NOT_REACHED: &str = "Output amount is less than min_out";
+pub const LIQUIDITY_TOO_SMALL: &str = "Position liquidity is too small";
+pub const POOL_ALREADY_EXISTS: &str = "Pool for this token pair and fee tier already exists";
+pub const SAME_TOKENS: &str = "Pool tokens must be different";
+pub const BAD_SQRT_PRICE_BOUNDS: &str =
+    "Sqrt price bounds must be positive and lower bound must be less than upper bound";
+pub const PRICE_RANGE_TOO_NARROW: &str = "Price range collapses to a single tick";
+pub const TICK_OUT_OF_BOUNDS: &str = "Price bound is outside of the [MIN_TICK, MAX_TICK] range";
+pub const SWAP_NOT_ALLOWED: &str = "Swap is not allowed by the compliance hook";
+pub const TOKEN_NOT_WHITELISTED: &str = "Token is not whitelisted";
+pub const POOL_PAUSED: &str = "Pool is paused";
+pub const LIQUIDITY_CAP_REACHED: &str = "Pool total liquidity cap reached";
+pub const BAD_BUCKET_SIZE: &str = "Bucket size must be a positive multiple of an hour";
+pub const UNKNOWN_TOKEN: &str = "Token is not accepted by this contract";
+pub const BAD_TRANSFER_MESSAGE: &str = "Bad ft_transfer_call message";
+pub const POSITIONS_NOT_MERGEABLE: &str =
+    "Only different positions of one owner with the same price range can be merged";
+pub const FLASH_LOAN_NOT_REPAID: &str = "Flash loan of this account is not repaid";
+pub const NO_FLASH_LOAN: &str = "No flash loan to repay";
+pub const LIMIT_ORDER_CONTAINS_PRICE: &str =
+    "Limit order range must be entirely on one side of the current price";
+pub const LIMIT_ORDER_FILLED: &str = "Limit order is filled, close it to claim the tokens";
+pub const NO_POOL_TO_SWAP: &str = "None of the candidate pools can execute the swap";
+pub const TOKEN_NOT_IN_POOL: &str = "Token does not belong to the pool";
+pub const TOO_MANY_TICKS_CROSSED: &str = "Swap crosses more ticks than one call can process";
+pub const SWAP_BELOW_MINIMUM: &str = "Swap amount is below the pool minimum";
+pub const AMOUNT_TOO_LARGE: &str = "Token amount is too large to be handled precisely";
+pub const BAD_SQRT_PRICE: &str = "Sqrt price must be a finite positive number";
+pub const LOCKED_AMOUNT_NOT_FINITE: &str = "Position locked amounts are not finite";
+pub const TICK_NOT_ALIGNED: &str = "Ticks must be multiples of the pool tick spacing";
+pub const NON_FINITE_BOUNDS: &str = "Position bounds are not finite";
+pub const TICKS_MISMATCH_BOUNDS: &str = "Position ticks don't match its sqrt price bounds";
+pub const NEGATIVE_LIQUIDITY: &str = "Position liquidity is negative";
+pub const NEGATIVE_LOCKED_AMOUNT: &str = "Position locked amounts are negative";
+pub const LOCKED_AMOUNT_EXCEEDS_LIQUIDITY: &str =
+    "Position locked amounts exceed what its liquidity can hold";
+pub const FEE_TOO_HIGH: &str = "Protocol fee and rewards must be at most 1000 basis points each";
+pub const NOT_OWNER: &str = "Only the owner can call this method";
+pub const NO_SHARED_TOKEN: &str = "Pools must have exactly one token in common";
+pub const BAD_BUCKETS_NUMBER: &str = "Number of buckets must be from 1 to 1000";
+pub const BAD_TICK_SAMPLING: &str =
+    "Tick range must be ordered and step positive, sampling at most 1000 ticks";
+pub const IMPACT_CAP_TOO_LOW: &str = "Even the smallest swap exceeds the price impact cap";
+pub const TOO_MANY_SWAP_SLICES: &str =
+    "Swap needs more than 100 slices to stay under the price impact cap";
+pub const NOT_POSITION_OWNER: &str = "Only the position owner can collect its fees";
+pub const TOKEN_BALANCE_UNAVAILABLE: &str = "Failed to get the contract's balance of the token";
+pub const AMOUNT_EXCEEDS_SURPLUS: &str =
+    "Amount exceeds the tokens the contract holds above its balances";
+pub const BAD_REWARD_RATE: &str =
+    "Reward per liquidity second must be a finite non-negative number";
+pub const NOT_ENOUGH_REWARD_BUDGET: &str =
+    "Owner's balance of the reward token is less than the budget";
+pub const NOT_ENOUGH_REWARD_FUNDS: &str = "Owner's balance of token1 is less than the reward";
+pub const BAD_DYNAMIC_FEE: &str =
+    "Dynamic fee floor must not exceed its cap of at most 1000 basis points, cap tick move must be positive";
+pub const MAX_AMOUNT_IN_EXCEEDED: &str = "Input amount is more than max_amount_in";
+pub const BAD_SQRT_PRICE_LIMIT: &str =
+    "Sqrt price limit must be beyond the current price in the swap direction and within the tick range";
+pub const BAD_TICK_BASE: &str = "Tick base must be from 1.00001 to 1.0005";
+pub const POOL_HAS_POSITIONS: &str = "Tick base can only change while the pool has no positions";
+pub const TICK_BASE_MISMATCH: &str = "Position ticks use another tick base than the pool";
+pub const OLD_STATE_NOT_FOUND: &str = "No contract state in the old layout to migrate";
+pub const BAD_VALUE_PARAMETERS: &str =
+    "Total value and reference price of token0 must be finite positive numbers";
+pub const ZERO_VALUE_PER_LIQUIDITY: &str = "Position bounds hold no value at the reference prices";
+pub const POSITION_NOT_FOUND: &str = "Position not found in this pool";
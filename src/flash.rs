@@ -0,0 +1,26 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{ext_contract, AccountId, Gas};
+
+pub const GAS_FOR_FLASH_CALLBACK: Gas = 50_000_000_000_000;
+pub const GAS_FOR_RESOLVE_FLASH: Gas = 20_000_000_000_000;
+
+// Tokens lent to an account that it still has to return to the pool
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FlashLoan {
+    pub pool_id: usize,
+    pub token: AccountId,
+    pub amount: u128,
+    pub fee: u128,
+}
+
+// Borrower has to leave `amount + fee` of `token` on its balance in this contract
+#[ext_contract(ext_flash_borrower)]
+trait FlashBorrower {
+    fn on_flash_loan(&mut self, token: AccountId, amount: U128, fee: U128, msg: String);
+}
+
+#[ext_contract(ext_flash_resolver)]
+trait FlashResolver {
+    fn resolve_flash(&mut self, account_id: AccountId);
+}
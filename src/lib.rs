@@ -1,23 +1,31 @@
 use balance::BalancesMap;
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::UnorderedMap;
-use near_sdk::{env, near_bindgen};
+use near_sdk::{env, near_bindgen, PromiseResult};
 use near_sdk::{AccountId, PanicOnDefault};
 use nft::metadata::{NFTContractMetadata, Token, TokenId, TokenMetadata};
-use pool::Pool;
+use pool::{DynamicFee, FeeTier, Pool, ProtocolFeeMode, SwapDirection, SwapResult};
 
 pub use crate::balance::*;
+use crate::compliance::*;
 use crate::errors::*;
-use crate::position::Position;
+use crate::flash::*;
+use crate::position::{amount_to_f64, Position, PositionSpec, RangeStatus, RemovedLiquidity};
 
 pub mod balance;
+// ext_contract expands resolve_compliance_swap with receiver, deposit and gas arguments
+#[allow(clippy::too_many_arguments)]
+mod compliance;
 mod errors;
+mod flash;
+pub mod migration;
 pub mod pool;
 mod position;
 mod token_receiver;
 
 use near_sdk::collections::{LazyOption, LookupMap, UnorderedSet};
-use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::json_types::{Base64VecU8, I128, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{Balance, CryptoHash, Promise, PromiseOrValue};
 use std::collections::HashMap;
@@ -31,12 +39,53 @@ pub enum StorageKey {
     TokensById,
     TokenMetadataById,
     NFTContractMetadata,
+    PoolIdsByTokens,
+    WhitelistedTokens,
+    FlashLoans,
+    BalanceTotals,
+    PendingTransfers,
 }
 
 pub const NFT_METADATA_SPEC: &str = "1.0.0";
 pub const NFT_STANDARD_NAME: &str = "nep171";
 pub const BASIS_POINT: f64 = 1.0001;
+// Range of tick bases a pool can use, prices at MIN_TICK and MAX_TICK have to stay finite
+pub const MIN_TICK_BASE: f64 = 1.00001;
+pub const MAX_TICK_BASE: f64 = 1.0005;
 pub const BASIS_POINT_TO_PERCENT: f64 = 10000.0;
+// Positions below it are rejected as dust, each position costs the swap loop an iteration
+pub const MIN_POSITION_LIQUIDITY: f64 = 20.0;
+// Upper limit on the protocol fee and LP rewards set by the owner, in basis points
+pub const MAX_FEE_BPS: u16 = 1000;
+// 2^53, above this f64 can't represent every integer
+pub const MAX_EXACT_F64_INTEGER: f64 = 9007199254740992.0;
+// 2^100, token amounts above it lose more than 2^47 units when cast to f64
+pub const MAX_F64_AMOUNT: u128 = 1 << 100;
+// Ticks whose BASIS_POINT sqrt prices are the closest to 2^-64 and 2^64
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = 887272;
+// Distance in ticks from a tick boundary within which a price is treated as on it
+pub const TICK_EPSILON: f64 = 1e-6;
+// Remaining swap amount below this is treated as fully consumed
+pub const SWAP_EPSILON: f64 = 1e-6;
+// Relative error of liquidity summed over ticks, below which it is treated as zero
+pub const LIQUIDITY_ROUNDING_ERROR: f64 = 1e-12;
+// Swap volume of a pool is stored in hourly buckets for the last 30 days
+pub const VOLUME_BUCKET_NS: u64 = 3_600_000_000_000;
+pub const MAX_VOLUME_BUCKETS: usize = 24 * 30;
+// Most price buckets get_liquidity_distribution can split the liquidity into
+pub const MAX_LIQUIDITY_BUCKETS: u32 = 1000;
+// Used to annualize position returns
+pub const SECONDS_IN_YEAR: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+// Every iteration of the swap loop either consumes the amount or reaches a position bound,
+// a swap crossing more of them doesn't fit the gas of one call
+pub const MAX_SWAP_ITERATIONS: u32 = 500;
+// Number of last swaps whose price moves set a dynamic LP fee
+pub const DYNAMIC_FEE_WINDOW: usize = 8;
+// Most slices suggest_swap_slices can split a swap into
+pub const MAX_SWAP_SLICES: u32 = 100;
+// Most cash flows a position keeps, the one that opened it and the latest ones
+pub const MAX_CASHFLOWS: usize = 100;
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
@@ -50,6 +99,18 @@ pub struct Contract {
     pub token_metadata_by_id: UnorderedMap<TokenId, TokenMetadata>,
     pub metadata: LazyOption<NFTContractMetadata>,
     pub positions_opened: u128,
+    // Keyed by the sorted token pair and the fee tier in basis points
+    pub pool_ids_by_tokens_and_fee: LookupMap<(AccountId, AccountId, u16), usize>,
+    // Contract asked whether an account may swap
+    pub compliance_hook: Option<AccountId>,
+    // Tokens new pools can be created with
+    pub whitelisted_tokens: UnorderedSet<AccountId>,
+    // Flash loans not returned yet, withdrawals of their borrowers are blocked
+    pub flash_loans: LookupMap<AccountId, FlashLoan>,
+    // Sum of all account balances of a token, kept up to date with balances_map
+    pub balance_totals: LookupMap<AccountId, u128>,
+    // Tokens of withdrawals and protocol fee transfers sent out and not resolved yet
+    pub pending_transfers: LookupMap<AccountId, u128>,
 }
 
 #[near_bindgen]
@@ -79,32 +140,397 @@ impl Contract {
                 Some(&metadata),
             ),
             positions_opened: 0,
+            pool_ids_by_tokens_and_fee: LookupMap::new(
+                StorageKey::PoolIdsByTokens.try_to_vec().unwrap(),
+            ),
+            compliance_hook: None,
+            whitelisted_tokens: UnorderedSet::new(
+                StorageKey::WhitelistedTokens.try_to_vec().unwrap(),
+            ),
+            flash_loans: LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+            balance_totals: LookupMap::new(StorageKey::BalanceTotals.try_to_vec().unwrap()),
+            pending_transfers: LookupMap::new(StorageKey::PendingTransfers.try_to_vec().unwrap()),
+        }
+    }
+
+    pub fn set_compliance_hook(&mut self, compliance_hook: Option<AccountId>) {
+        self.assert_owner();
+        self.compliance_hook = compliance_hook;
+    }
+
+    // Applies to swaps from now on, fees already earned by positions stay as they are
+    pub fn set_pool_fees(&mut self, pool_id: usize, protocol_fee: u16, rewards: u16) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        assert!(
+            protocol_fee <= MAX_FEE_BPS && rewards <= MAX_FEE_BPS,
+            "{}",
+            FEE_TOO_HIGH
+        );
+        let pool = &mut self.pools[pool_id];
+        pool.protocol_fee = protocol_fee;
+        pool.rewards = rewards;
+    }
+
+    // None goes back to the static `rewards` LP fee
+    pub fn set_dynamic_fee(&mut self, pool_id: usize, dynamic_fee: Option<DynamicFee>) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        if let Some(dynamic_fee) = dynamic_fee {
+            assert!(
+                dynamic_fee.floor_bps <= dynamic_fee.cap_bps
+                    && dynamic_fee.cap_bps <= MAX_FEE_BPS
+                    && dynamic_fee.cap_tick_move > 0,
+                "{}",
+                BAD_DYNAMIC_FEE
+            );
+        }
+        let pool = &mut self.pools[pool_id];
+        pool.dynamic_fee = dynamic_fee;
+        pool.recent_tick_moves.clear();
+    }
+
+    // Price ratio between neighbouring ticks, finer for stable pairs and coarser for
+    // volatile ones. Only a pool without positions can change it
+    pub fn set_pool_tick_base(&mut self, pool_id: usize, tick_base: f64) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].set_tick_base(tick_base);
+    }
+
+    // LP fee in basis points the pool's swaps pay now
+    pub fn get_lp_fee_bps(&self, pool_id: usize) -> u16 {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].lp_fee_bps()
+    }
+
+    pub fn set_pool_paused(&mut self, pool_id: usize, paused: bool) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].is_paused = paused;
+    }
+
+    // None removes the cap
+    pub fn set_max_total_liquidity(&mut self, pool_id: usize, max_total_liquidity: Option<f64>) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].max_total_liquidity = max_total_liquidity;
+    }
+
+    // Time-based LP rewards in token1, paid from the owner's balance
+    pub fn set_reward_tokens_per_second(&mut self, pool_id: usize, reward_tokens_per_second: U128) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].reward_tokens_per_second = reward_tokens_per_second.into();
+    }
+
+    // LP rewards in `token` instead of token1, `reward_per_liquidity_second` of it for every unit
+    // of liquidity and second a position is active. `budget` is taken from the owner's balance
+    // and what is left of the previous budget is given back
+    pub fn set_reward_token(
+        &mut self,
+        pool_id: usize,
+        token: AccountId,
+        reward_per_liquidity_second: f64,
+        budget: U128,
+    ) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        assert!(
+            reward_per_liquidity_second.is_finite() && reward_per_liquidity_second >= 0.0,
+            "{}",
+            BAD_REWARD_RATE
+        );
+        let owner_id = self.owner_id.clone();
+        let pool = &mut self.pools[pool_id];
+        let left = pool.reward_budget;
+        if let Some(old_token) = pool.reward_token.take() {
+            if left > 0 {
+                self.deposit_ft(&owner_id, &old_token, left);
+            }
+        }
+        assert!(
+            self.get_balance(&owner_id, &token).0 >= budget.0,
+            "{}",
+            NOT_ENOUGH_REWARD_BUDGET
+        );
+        self.decrease_balance(&owner_id, &token, budget.0);
+        let pool = &mut self.pools[pool_id];
+        pool.reward_token = Some(token);
+        pool.reward_per_liquidity_second = reward_per_liquidity_second;
+        pool.reward_budget = budget.0;
+    }
+
+    // Pays the (token0, token1) fees the position earned since the last collect to the
+    // caller's balance, the liquidity stays
+    pub fn collect_position_fees(&mut self, pool_id: usize, position_id: U128) -> (U128, U128) {
+        self.assert_pool_exists(pool_id);
+        let account_id = env::predecessor_account_id();
+        let token = self.tokens_by_id.get(&position_id.0.to_string()).unwrap();
+        Self::assert_account_owns_nft(&account_id, &token.owner_id);
+        let pool = &mut self.pools[pool_id];
+        let (fees0, fees1) = pool.collect_position_fees(&account_id, position_id.0);
+        let position = pool.positions.get_mut(&position_id.0).unwrap();
+        position.record_cashflow(env::block_timestamp(), fees0 as i128, fees1 as i128);
+        let token0 = pool.token0.clone();
+        let token1 = pool.token1.clone();
+        self.increase_balance(&account_id, &token0, fees0);
+        self.increase_balance(&account_id, &token1, fees1);
+        (fees0.into(), fees1.into())
+    }
+
+    pub fn claim_rewards(&mut self, pool_id: usize, position_id: U128) -> U128 {
+        self.assert_pool_exists(pool_id);
+        let account_id = env::predecessor_account_id();
+        let token = self.tokens_by_id.get(&position_id.0.to_string()).unwrap();
+        Self::assert_account_owns_nft(&account_id, &token.owner_id);
+        let pool = &mut self.pools[pool_id];
+        pool.refresh(env::block_timestamp());
+        let reward = pool.claim_rewards(position_id.0);
+        let token1 = pool.token1.clone();
+        if reward > 0 {
+            match pool.reward_token.clone() {
+                // paid out of the budget the owner has already set aside
+                Some(reward_token) => self.deposit_ft(&account_id, &reward_token, reward),
+                None => {
+                    let owner_id = self.owner_id.clone();
+                    assert!(
+                        self.get_balance(&owner_id, &token1).0 >= reward,
+                        "{}",
+                        NOT_ENOUGH_REWARD_FUNDS
+                    );
+                    self.decrease_balance(&owner_id, &token1, reward);
+                    self.deposit_ft(&account_id, &token1, reward);
+                }
+            }
         }
+        reward.into()
+    }
+
+    // Claims the rewards of all the caller's positions in the pool, returns their sum
+    pub fn claim_all_rewards(&mut self, pool_id: usize) -> U128 {
+        self.assert_pool_exists(pool_id);
+        let account_id = env::predecessor_account_id();
+        let position_ids: Vec<u128> = match self.tokens_per_owner.get(&account_id) {
+            Some(token_ids) => token_ids
+                .iter()
+                .map(|token_id| token_id.parse().unwrap())
+                .filter(|position_id| self.pools[pool_id].positions.contains_key(position_id))
+                .collect(),
+            None => Vec::new(),
+        };
+        let reward: u128 = position_ids
+            .into_iter()
+            .map(|position_id| self.claim_rewards(pool_id, U128(position_id)).0)
+            .sum();
+        reward.into()
+    }
+
+    // None allows swaps of any size
+    pub fn set_min_swap_amount(&mut self, pool_id: usize, min_swap_amount: Option<U128>) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].min_swap_amount = min_swap_amount.map(|amount| amount.0);
+    }
+
+    pub fn set_protocol_fee_mode(&mut self, pool_id: usize, protocol_fee_mode: ProtocolFeeMode) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].protocol_fee_mode = protocol_fee_mode;
+    }
+
+    // Transfers protocol fees accrued by the pool to the owner
+    pub fn collect_protocol_fees(&mut self, pool_id: usize) {
+        self.assert_owner();
+        self.assert_pool_exists(pool_id);
+        let pool = &mut self.pools[pool_id];
+        let fees = [
+            (pool.token0.clone(), pool.protocol_fees_token0),
+            (pool.token1.clone(), pool.protocol_fees_token1),
+        ];
+        pool.protocol_fees_token0 = 0;
+        pool.protocol_fees_token1 = 0;
+        for (token, amount) in fees {
+            if amount > 0 {
+                self.transfer_protocol_fee(pool_id, &token, amount);
+            }
+        }
+    }
+
+    #[private]
+    pub fn resolve_protocol_fee(&mut self, pool_id: usize, token: AccountId, amount: U128) {
+        self.remove_pending_transfer(&token, amount.0);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        self.pools[pool_id].accrue_protocol_fee(&token, amount.0);
+    }
+
+    // Sends `amount` of `token` held by the contract above what it owes to `to`,
+    // the contract's balance is asked from the token first
+    pub fn rescue_stuck_tokens(
+        &mut self,
+        token: AccountId,
+        amount: U128,
+        to: AccountId,
+    ) -> Promise {
+        self.assert_owner();
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            &token,
+            0,
+            GAS_FOR_FT_BALANCE_OF,
+        )
+        .then(ext_rescue_resolver::resolve_rescue_stuck_tokens(
+            token,
+            amount,
+            to,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_RESCUE,
+        ))
+    }
+
+    #[private]
+    pub fn resolve_rescue_stuck_tokens(
+        &mut self,
+        token: AccountId,
+        amount: U128,
+        to: AccountId,
+    ) -> Promise {
+        let held = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<U128>(&value)
+                    .expect(TOKEN_BALANCE_UNAVAILABLE)
+                    .0
+            }
+            _ => panic!("{}", TOKEN_BALANCE_UNAVAILABLE),
+        };
+        let surplus = held.saturating_sub(self.accounted_amount(&token));
+        assert!(amount.0 <= surplus, "{}", AMOUNT_EXCEEDS_SURPLUS);
+        ext_fungible_token::ft_transfer(to, amount, None, &token, 1, GAS_FOR_FT_TRANSFER)
+    }
+
+    // Protocol and LP fees of (token0, token1) generated since the pool creation
+    pub fn get_pool_total_fees_generated(&self, pool_id: usize) -> (U128, U128) {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        (
+            U128(pool.lifetime_protocol_fees_token0 + pool.lifetime_lp_fees_token0),
+            U128(pool.lifetime_protocol_fees_token1 + pool.lifetime_lp_fees_token1),
+        )
+    }
+
+    pub fn get_total_liquidity(&self, pool_id: usize) -> f64 {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].total_liquidity()
+    }
+
+    pub fn add_whitelisted_token(&mut self, token: AccountId) {
+        self.assert_owner();
+        self.whitelisted_tokens.insert(&token);
+    }
+
+    // Existing pools of the token keep working, only new pools are blocked
+    pub fn remove_whitelisted_token(&mut self, token: AccountId) {
+        self.assert_owner();
+        self.whitelisted_tokens.remove(&token);
+    }
+
+    pub fn get_whitelisted_tokens(&self) -> Vec<AccountId> {
+        self.whitelisted_tokens.to_vec()
     }
 
     #[private]
+    #[allow(clippy::too_many_arguments)]
     pub fn create_pool(
         &mut self,
         token1: AccountId,
         token2: AccountId,
         initial_price: f64,
         protocol_fee: u16,
-        rewards: u16,
+        fee_tier: u16,
+        token1_decimals: u8,
+        token2_decimals: u8,
     ) -> usize {
+        assert!(token1 != token2, "{}", SAME_TOKENS);
+        assert!(
+            self.whitelisted_tokens.contains(&token1) && self.whitelisted_tokens.contains(&token2),
+            "{}",
+            TOKEN_NOT_WHITELISTED
+        );
+        assert!(protocol_fee <= MAX_FEE_BPS, "{}", FEE_TOO_HIGH);
+        let fee_tier = FeeTier::from_bps(fee_tier);
+        let key = Self::pool_key(&token1, &token2, fee_tier);
+        assert!(
+            !self.pool_ids_by_tokens_and_fee.contains_key(&key),
+            "{}",
+            POOL_ALREADY_EXISTS
+        );
+        let pool_id = self.pools.len();
+        self.pool_ids_by_tokens_and_fee.insert(&key, &pool_id);
         self.pools.push(Pool::new(
             token1,
             token2,
             initial_price,
             protocol_fee,
-            rewards,
+            fee_tier,
+            token1_decimals,
+            token2_decimals,
         ));
-        self.pools.len() - 1
+        pool_id
     }
 
     #[private]
     pub fn remove_pool(&mut self, pool_id: usize) {
         self.assert_pool_exists(pool_id);
-        self.pools.remove(pool_id);
+        let pool = self.pools.remove(pool_id);
+        self.pool_ids_by_tokens_and_fee.remove(&Self::pool_key(
+            &pool.token0,
+            &pool.token1,
+            pool.fee_tier,
+        ));
+        for (id, pool) in self.pools.iter().enumerate().skip(pool_id) {
+            self.pool_ids_by_tokens_and_fee.insert(
+                &Self::pool_key(&pool.token0, &pool.token1, pool.fee_tier),
+                &id,
+            );
+        }
+    }
+
+    // Pool of the pair with the lowest fee tier when there are several
+    pub fn get_pool_by_tokens(&self, token_a: AccountId, token_b: AccountId) -> Option<usize> {
+        [FeeTier::Low, FeeTier::Medium, FeeTier::High]
+            .iter()
+            .find_map(|fee_tier| {
+                self.pool_ids_by_tokens_and_fee
+                    .get(&Self::pool_key(&token_a, &token_b, *fee_tier))
+            })
+    }
+
+    pub fn get_pool_by_tokens_and_fee(
+        &self,
+        token_a: AccountId,
+        token_b: AccountId,
+        fee_tier: u16,
+    ) -> Option<usize> {
+        self.pool_ids_by_tokens_and_fee.get(&Self::pool_key(
+            &token_a,
+            &token_b,
+            FeeTier::from_bps(fee_tier),
+        ))
+    }
+
+    fn pool_key(
+        token_a: &AccountId,
+        token_b: &AccountId,
+        fee_tier: FeeTier,
+    ) -> (AccountId, AccountId, u16) {
+        if token_a < token_b {
+            (token_a.clone(), token_b.clone(), fee_tier.bps())
+        } else {
+            (token_b.clone(), token_a.clone(), fee_tier.bps())
+        }
     }
 
     pub fn get_pools(&self) -> Vec<Pool> {
@@ -115,6 +541,52 @@ impl Contract {
         assert!(pool_id < self.pools.len(), "{}", BAD_POOL_ID);
     }
 
+    fn assert_owner(&self) {
+        assert!(
+            env::predecessor_account_id() == self.owner_id,
+            "{}",
+            NOT_OWNER
+        );
+    }
+
+    fn assert_pool_not_paused(&self, pool_id: usize) {
+        assert!(!self.pools[pool_id].is_paused, "{}", POOL_PAUSED);
+    }
+
+    // is_allowed call to the compliance hook for the account, None when no hook is set.
+    // Every entry point that swaps executes the swap in a callback after it
+    fn ask_compliance_hook(&self, account_id: &AccountId) -> Option<Promise> {
+        self.compliance_hook.as_ref().map(|compliance_hook| {
+            ext_compliance_hook::is_allowed(
+                account_id.clone(),
+                compliance_hook,
+                0,
+                GAS_FOR_COMPLIANCE_CHECK,
+            )
+        })
+    }
+
+    // Answer of the compliance hook a resolve callback follows, anything but `true` denies
+    fn assert_allowed_by_compliance_hook(&self) {
+        let is_allowed = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<bool>(&value).unwrap_or(false)
+            }
+            _ => false,
+        };
+        assert!(is_allowed, "{}", SWAP_NOT_ALLOWED);
+    }
+
+    // Borrowed tokens only exist on the borrower's balance, so nothing can be taken from its
+    // balances until the loan is repaid
+    fn assert_no_flash_loan(&self, account_id: &AccountId) {
+        assert!(
+            !self.flash_loans.contains_key(account_id),
+            "{}",
+            FLASH_LOAN_NOT_REPAID
+        );
+    }
+
     fn assert_account_owns_nft(account_id: &AccountId, nft_owner: &AccountId) {
         assert!(account_id == nft_owner);
     }
@@ -148,10 +620,101 @@ impl Contract {
         }
     }
 
-    pub fn withdraw(&mut self, token: AccountId, amount: U128) {
+    pub fn withdraw(&mut self, token: AccountId, amount: U128) -> Promise {
         let account_id = env::predecessor_account_id();
         let amount: u128 = amount.into();
-        self.balance_withdraw(&account_id, &token, amount);
+        self.balance_withdraw(&account_id, &token, amount)
+    }
+
+    #[private]
+    pub fn resolve_withdraw(&mut self, account_id: AccountId, token: AccountId, amount: U128) {
+        self.remove_pending_transfer(&token, amount.0);
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        self.deposit_ft(&account_id, &token, amount.into());
+    }
+
+    // Lends `amount` of `token` from the pool by crediting it to the caller's balance and calls
+    // `on_flash_loan` of the caller, which has to leave the amount plus the LP fee on its balance.
+    // Until then the caller's balances can only grow: withdrawals, swaps and positions fail
+    pub fn flash(
+        &mut self,
+        pool_id: usize,
+        token: AccountId,
+        amount: U128,
+        callback_msg: String,
+    ) -> Promise {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let account_id = env::predecessor_account_id();
+        self.assert_no_flash_loan(&account_id);
+        let pool = &self.pools[pool_id];
+        let token_locked = if token == pool.token0 {
+            pool.token0_locked
+        } else if token == pool.token1 {
+            pool.token1_locked
+        } else {
+            panic!("{}", INCORRECT_TOKEN);
+        };
+        let amount: u128 = amount.into();
+        assert!(amount <= token_locked, "{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
+        let fee = (amount_to_f64(amount) * pool.lp_fee_bps() as f64 / BASIS_POINT_TO_PERCENT).ceil()
+            as u128;
+        self.deposit_ft(&account_id, &token, amount);
+        self.flash_loans.insert(
+            &account_id,
+            &FlashLoan {
+                pool_id,
+                token: token.clone(),
+                amount,
+                fee,
+            },
+        );
+        ext_flash_borrower::on_flash_loan(
+            token,
+            U128(amount),
+            U128(fee),
+            callback_msg,
+            &account_id,
+            0,
+            GAS_FOR_FLASH_CALLBACK,
+        )
+        .then(ext_flash_resolver::resolve_flash(
+            account_id,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_FLASH,
+        ))
+    }
+
+    // The loan is settled from the borrower's balance after its callback, it fails if the
+    // balance falls short and the borrower stays locked until repay_flash_loan
+    #[private]
+    pub fn resolve_flash(&mut self, account_id: AccountId) {
+        self.settle_flash_loan(&account_id);
+    }
+
+    // Returns a flash loan the callback didn't cover after the missing tokens are deposited
+    pub fn repay_flash_loan(&mut self) {
+        let account_id = env::predecessor_account_id();
+        self.settle_flash_loan(&account_id);
+    }
+
+    // Takes the loan and the fee from the borrower's balance, all of it or nothing
+    fn settle_flash_loan(&mut self, account_id: &AccountId) {
+        let loan = self.flash_loans.remove(account_id).expect(NO_FLASH_LOAN);
+        let balance: u128 = self.get_balance(account_id, &loan.token).into();
+        assert!(
+            balance >= loan.amount + loan.fee,
+            "{}",
+            FLASH_LOAN_NOT_REPAID
+        );
+        self.decrease_balance(account_id, &loan.token, loan.amount + loan.fee);
+        if loan.fee > 0 && !self.pools[loan.pool_id].distribute_fee(&loan.token, loan.fee) {
+            let owner_id = self.owner_id.clone();
+            self.deposit_ft(&owner_id, &loan.token, loan.fee);
+        }
     }
 
     pub fn get_return(&self, pool_id: usize, token_in: &AccountId, amount_in: U128) -> U128 {
@@ -161,6 +724,19 @@ impl Contract {
         (swap_result.amount.round() as u128).into()
     }
 
+    // Quote of a swap that stops where the pool liquidity ends or after MAX_SWAP_ITERATIONS steps
+    // instead of failing, `remaining` of the result is the part of `amount` that can't be swapped
+    pub fn get_partial_swap_result(
+        &self,
+        pool_id: usize,
+        token: AccountId,
+        amount: U128,
+        direction: SwapDirection,
+    ) -> SwapResult {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].get_partial_swap_result(&token, amount.0, direction)
+    }
+
     pub fn get_expense(&self, pool_id: usize, token_out: &AccountId, amount_out: U128) -> U128 {
         let pool = self.get_pool(pool_id);
         let amount_out: u128 = amount_out.into();
@@ -168,35 +744,688 @@ impl Contract {
         (swap_result.amount.round() as u128).into()
     }
 
+    // Advisory split of a swap of `total_amount` into slices that each move the price by at most
+    // `max_impact_bps` basis points, assuming the depth recovers between the slices
+    pub fn suggest_swap_slices(
+        &self,
+        pool_id: usize,
+        token: AccountId,
+        total_amount: U128,
+        direction: SwapDirection,
+        max_impact_bps: u16,
+    ) -> Vec<U128> {
+        self.assert_pool_exists(pool_id);
+        let total_amount: u128 = total_amount.into();
+        let slice = self.pools[pool_id].max_swap_within_impact(
+            &token,
+            total_amount,
+            direction,
+            max_impact_bps,
+        );
+        assert!(slice > 0, "{}", IMPACT_CAP_TOO_LOW);
+        let slices_number = total_amount.div_ceil(slice);
+        assert!(
+            slices_number <= MAX_SWAP_SLICES as u128,
+            "{}",
+            TOO_MANY_SWAP_SLICES
+        );
+        let mut slices = vec![U128(slice); slices_number as usize - 1];
+        slices.push(U128(total_amount - slice * (slices_number - 1)));
+        slices
+    }
+
     pub fn get_price(&self, pool_id: usize) -> f64 {
         let pool = self.get_pool(pool_id);
         let sqrt_price = pool.get_sqrt_price();
         sqrt_price * sqrt_price
     }
 
+    pub fn get_spot_price(&self, pool_id: usize) -> f64 {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].get_spot_price()
+    }
+
+    // Price of the token of `pool_ab` that is not in `pool_bc` (A) in the token of `pool_bc`
+    // that is not in `pool_ab` (C), implied by swapping A to B and then B to C
+    pub fn implied_price_through(&self, pool_ab: usize, pool_bc: usize) -> f64 {
+        self.assert_pool_exists(pool_ab);
+        self.assert_pool_exists(pool_bc);
+        let (ab, bc) = (&self.pools[pool_ab], &self.pools[pool_bc]);
+        let is_shared = |token: &AccountId| token == &bc.token0 || token == &bc.token1;
+        let token_b = match (is_shared(&ab.token0), is_shared(&ab.token1)) {
+            (true, false) => &ab.token0,
+            (false, true) => &ab.token1,
+            _ => panic!("{}", NO_SHARED_TOKEN),
+        };
+        // spot price is token1 per token0
+        let a_in_b = if token_b == &ab.token1 {
+            ab.get_spot_price()
+        } else {
+            1.0 / ab.get_spot_price()
+        };
+        let b_in_c = if token_b == &bc.token0 {
+            bc.get_spot_price()
+        } else {
+            1.0 / bc.get_spot_price()
+        };
+        a_in_b * b_in_c
+    }
+
+    pub fn get_tick(&self, pool_id: usize) -> i32 {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].tick
+    }
+
+    pub fn tick_to_price(&self, pool_id: usize, tick: i32) -> f64 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        pool.to_human_price(position::tick_to_price(tick, pool.tick_base))
+    }
+
+    pub fn price_to_tick(&self, pool_id: usize, price: f64) -> i32 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        position::price_to_tick(pool.to_raw_price(price), pool.tick_base)
+    }
+
+    pub fn price_to_nearest_tick(&self, pool_id: usize, price: f64) -> i32 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        position::price_to_nearest_tick(pool.to_raw_price(price), pool.tick_base)
+    }
+
+    // Whether a position with these bounds, snapped to the tick spacing, would be in range now
+    pub fn quote_position_active(
+        &self,
+        pool_id: usize,
+        lower_price: f64,
+        upper_price: f64,
+    ) -> bool {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        let (tick_lower, tick_upper) = pool.bounds_to_ticks(
+            pool.to_raw_price(lower_price),
+            pool.to_raw_price(upper_price),
+        );
+        pool.tick_to_sqrt_price(tick_lower) <= pool.sqrt_price
+            && pool.sqrt_price <= pool.tick_to_sqrt_price(tick_upper)
+    }
+
+    pub fn project_fee_income(
+        &self,
+        pool_id: usize,
+        spec: PositionSpec,
+        projected_volume: U128,
+    ) -> (U128, U128) {
+        let pool = self.get_pool(pool_id);
+        let position = Position::from_spec(
+            String::new(),
+            &spec,
+            pool.sqrt_price,
+            pool.tick_spacing,
+            pool.tick_base,
+        );
+        let projected_volume: u128 = projected_volume.into();
+        let (fee0, fee1) = pool.project_fee_income(&position, amount_to_f64(projected_volume));
+        ((fee0.round() as u128).into(), (fee1.round() as u128).into())
+    }
+
+    pub fn fee_shares_after_swap(
+        &self,
+        pool_id: usize,
+        token: AccountId,
+        amount: U128,
+        direction: pool::SwapDirection,
+    ) -> HashMap<AccountId, f64> {
+        let pool = self.get_pool(pool_id);
+        let swap_result = pool.get_swap_result(&token, amount.into(), direction);
+        pool.fee_shares(swap_result.new_sqrt_price)
+    }
+
+    pub fn get_position_precision_warning(
+        &self,
+        pool_id: usize,
+        position_id: U128,
+    ) -> Option<position::PrecisionWarning> {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id]
+            .positions
+            .get(&position_id.0)
+            .expect(POSITION_NOT_FOUND)
+            .precision_warning()
+    }
+
+    pub fn get_position_full_fill_price(&self, pool_id: usize, position_id: U128) -> f64 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        let position = pool
+            .positions
+            .get(&position_id.0)
+            .expect(POSITION_NOT_FOUND);
+        pool.to_human_price(position.full_fill_price())
+    }
+
+    // In terms of the pool's internal price, `Below` means the position holds only token0
+    pub fn get_position_range_status(&self, pool_id: usize, position_id: U128) -> RangeStatus {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        let position = pool
+            .positions
+            .get(&position_id.0)
+            .expect(POSITION_NOT_FOUND);
+        position.range_status(pool.sqrt_price)
+    }
+
+    // Percentage the position with earned fees is ahead or behind holding its entry tokens
+    pub fn get_position_vs_hodl(&self, pool_id: usize, position_id: U128) -> f64 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        let position = pool.position_with_fees(position_id.0);
+        position.vs_hodl(pool.sqrt_price, position.entry_sqrt_price)
+    }
+
+    // Fee APR of a position over the last `lookback_ns` with its impermanent loss annualized
+    // over the same window subtracted, in percent, negative when holding would have been better
+    pub fn get_position_net_apr(&self, pool_id: usize, position_id: U128, lookback_ns: u64) -> f64 {
+        self.assert_pool_exists(pool_id);
+        let pool = &self.pools[pool_id];
+        let position = pool.position_with_fees(position_id.0);
+        let elapsed_seconds = lookback_ns / 1_000_000_000;
+        if elapsed_seconds == 0 {
+            return 0.0;
+        }
+        let fee_apr = position.estimated_apr(pool.sqrt_price, elapsed_seconds);
+        let impermanent_loss =
+            position.impermanent_loss(position.entry_sqrt_price, pool.sqrt_price);
+        fee_apr + impermanent_loss * SECONDS_IN_YEAR / elapsed_seconds as f64
+    }
+
+    pub fn simulate_swap_sequence(
+        &self,
+        pool_id: usize,
+        swaps: Vec<(AccountId, U128, pool::SwapDirection)>,
+    ) -> Vec<pool::PoolState> {
+        self.assert_pool_exists(pool_id);
+        let swaps: Vec<(AccountId, u128, pool::SwapDirection)> = swaps
+            .into_iter()
+            .map(|(token, amount, direction)| (token, amount.into(), direction))
+            .collect();
+        self.pools[pool_id].simulate_swap_sequence(&swaps, env::block_timestamp())
+    }
+
+    pub fn get_position_cashflow(
+        &self,
+        pool_id: usize,
+        position_id: U128,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> (I128, I128) {
+        self.assert_pool_exists(pool_id);
+        let position = self.pools[pool_id]
+            .positions
+            .get(&position_id.0)
+            .expect(POSITION_NOT_FOUND);
+        let (token0, token1) = position.cashflow(from_ts, to_ts);
+        (token0.into(), token1.into())
+    }
+
+    pub fn get_total_locked(&self, pool_id: usize) -> (U128, U128) {
+        self.assert_pool_exists(pool_id);
+        let (token0_locked, token1_locked) = self.pools[pool_id].total_locked();
+        (
+            U128(token0_locked.round() as u128),
+            U128(token1_locked.round() as u128),
+        )
+    }
+
+    pub fn get_tvl_in_token1(&self, pool_id: usize) -> U128 {
+        self.assert_pool_exists(pool_id);
+        U128(self.pools[pool_id].tvl_in_token1().round() as u128)
+    }
+
+    pub fn get_current_segment_k(&self, pool_id: usize) -> f64 {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].current_segment_k()
+    }
+
+    pub fn get_volume_buckets(
+        &self,
+        pool_id: usize,
+        bucket_size_ns: u64,
+        limit: u64,
+    ) -> Vec<pool::VolumeBucket> {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].volume_buckets(bucket_size_ns, limit as usize)
+    }
+
+    pub fn get_liquidity_distribution(
+        &self,
+        pool_id: usize,
+        buckets: u32,
+    ) -> Vec<pool::LiquidityBucket> {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].liquidity_histogram(buckets)
+    }
+
+    // Liquidity active at every `step`-th tick of the range, for depth charts
+    pub fn get_liquidity_profile(
+        &self,
+        pool_id: usize,
+        tick_lower: i32,
+        tick_upper: i32,
+        step: i32,
+    ) -> Vec<(i32, f64)> {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id].liquidity_distribution(tick_lower, tick_upper, step)
+    }
+
+    pub fn tokens_for_target_liquidity(
+        &self,
+        pool_id: usize,
+        target_liquidity: f64,
+        lower_price: f64,
+        upper_price: f64,
+    ) -> (U128, U128) {
+        let (x, y) = self.get_pool(pool_id).amounts_for_liquidity(
+            target_liquidity,
+            lower_price,
+            upper_price,
+        );
+        (U128(x.round() as u128), U128(y.round() as u128))
+    }
+
+    // Reverts if less than `min_out` of token_out would be received after fees. With
+    // `sqrt_price_limit` the swap stops at that price or where the liquidity ends before it,
+    // and the rest of amount_in stays unswapped
+    #[allow(clippy::too_many_arguments)]
     pub fn swap(
         &mut self,
         pool_id: usize,
         token_in: AccountId,
         amount_in: U128,
         token_out: AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    ) -> PromiseOrValue<U128> {
+        let account_id = env::predecessor_account_id();
+        self.swap_for_account(
+            account_id,
+            pool_id,
+            token_in,
+            amount_in,
+            token_out,
+            min_out,
+            sqrt_price_limit,
+        )
+    }
+
+    // Swaps for exactly `amount_out` of token_out, the other token is taken with fees on top.
+    // Reverts if that is more than `max_amount_in`, returns the amount taken
+    pub fn swap_exact_out(
+        &mut self,
+        pool_id: usize,
+        token_out: AccountId,
+        amount_out: U128,
+        max_amount_in: U128,
+    ) -> PromiseOrValue<U128> {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let account_id = env::predecessor_account_id();
+        match self.ask_compliance_hook(&account_id) {
+            Some(is_allowed) => is_allowed
+                .then(ext_self::resolve_compliance_swap_exact_out(
+                    account_id,
+                    pool_id,
+                    token_out,
+                    amount_out,
+                    max_amount_in,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_RESOLVE_SWAP,
+                ))
+                .into(),
+            None => PromiseOrValue::Value(self.execute_swap_exact_out(
+                &account_id,
+                pool_id,
+                &token_out,
+                amount_out.0,
+                max_amount_in.0,
+            )),
+        }
+    }
+
+    #[private]
+    pub fn resolve_compliance_swap_exact_out(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        token_out: AccountId,
+        amount_out: U128,
+        max_amount_in: U128,
     ) -> U128 {
+        self.assert_allowed_by_compliance_hook();
         self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        self.execute_swap_exact_out(
+            &account_id,
+            pool_id,
+            &token_out,
+            amount_out.0,
+            max_amount_in.0,
+        )
+    }
+
+    fn execute_swap_exact_out(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: usize,
+        token_out: &AccountId,
+        amount_out: u128,
+        max_amount_in: u128,
+    ) -> U128 {
+        let pool = &self.pools[pool_id];
+        pool.assert_can_pay_out(token_out, amount_out);
+        let token_in = pool.toggle_token(token_out);
+        let (swap_result, fees_amount) =
+            self.apply_swap(pool_id, token_out, amount_out, SwapDirection::Expense, None);
+        let amount_in = swap_result.amount.round() as u128 + fees_amount.round() as u128;
+        assert!(amount_in <= max_amount_in, "{}", MAX_AMOUNT_IN_EXCEEDED);
+        if let Some(min_swap_amount) = self.pools[pool_id].min_swap_amount {
+            assert!(amount_in >= min_swap_amount, "{}", SWAP_BELOW_MINIMUM);
+        }
+        self.decrease_balance(account_id, &token_in, amount_in);
+        self.increase_balance(account_id, token_out, amount_out);
+        amount_in.into()
+    }
+
+    // Swaps through the candidate pool that gives the most of the other token after fees
+    // at execution time, paused candidates are skipped
+    pub fn swap_best_of(
+        &mut self,
+        candidate_pool_ids: Vec<usize>,
+        token_in: AccountId,
+        amount_in: U128,
+        min_out: U128,
+    ) -> PromiseOrValue<U128> {
+        let mut best: Option<(usize, f64)> = None;
+        let mut token_out: Option<AccountId> = None;
+        for pool_id in candidate_pool_ids {
+            self.assert_pool_exists(pool_id);
+            let pool = &self.pools[pool_id];
+            let pool_token_out = if token_in == pool.token0 {
+                &pool.token1
+            } else if token_in == pool.token1 {
+                &pool.token0
+            } else {
+                panic!("{}", INCORRECT_TOKEN);
+            };
+            let token_out = token_out.get_or_insert_with(|| pool_token_out.clone());
+            assert!(token_out == pool_token_out, "{}", INCORRECT_TOKEN);
+            if pool.is_paused {
+                continue;
+            }
+            let swap_result =
+                pool.get_swap_result(&token_in, amount_in.0, pool::SwapDirection::Return);
+            let (amount_out, _) = pool.apply_fee(swap_result.amount);
+            let is_better = match best {
+                Some((_, best_amount_out)) => amount_out > best_amount_out,
+                None => true,
+            };
+            if is_better {
+                best = Some((pool_id, amount_out));
+            }
+        }
+        let (pool_id, amount_out) = best.expect(NO_POOL_TO_SWAP);
+        assert!(
+            amount_out.round() as u128 >= min_out.0,
+            "{}",
+            MIN_AMOUNT_OUT_NOT_REACHED
+        );
         let account_id = env::predecessor_account_id();
-        let amount_in: u128 = amount_in.into();
-        self.decrease_balance(&account_id, &token_in, amount_in);
-        let pool = &mut self.pools[pool_id];
-        let swap_result = pool.get_swap_result(&token_in, amount_in, pool::SwapDirection::Return);
-        self.apply_collected_fees(&swap_result.collected_fees, &token_out);
-        self.increase_balance(&account_id, &token_out, swap_result.amount.round() as u128);
+        self.swap_for_account(
+            account_id,
+            pool_id,
+            token_in,
+            amount_in,
+            token_out.unwrap(),
+            Some(min_out),
+            None,
+        )
+    }
+
+    // Executes the swaps in order for the caller's balance, all of them or none: they are tried
+    // on copies of the pools first, so a failing swap leaves every pool untouched. `token` is
+    // the input token for SwapDirection::Return and the output token for SwapDirection::Expense
+    pub fn batch_swap(
+        &mut self,
+        swaps: Vec<(usize, AccountId, U128, SwapDirection)>,
+    ) -> PromiseOrValue<Vec<SwapResult>> {
+        let account_id = env::predecessor_account_id();
+        match self.ask_compliance_hook(&account_id) {
+            Some(is_allowed) => {
+                let gas = GAS_FOR_RESOLVE_SWAP * swaps.len() as u64;
+                is_allowed
+                    .then(ext_self::resolve_compliance_batch_swap(
+                        account_id,
+                        swaps,
+                        &env::current_account_id(),
+                        0,
+                        gas,
+                    ))
+                    .into()
+            }
+            None => PromiseOrValue::Value(self.execute_batch_swap(&account_id, swaps)),
+        }
+    }
+
+    #[private]
+    pub fn resolve_compliance_batch_swap(
+        &mut self,
+        account_id: AccountId,
+        swaps: Vec<(usize, AccountId, U128, SwapDirection)>,
+    ) -> Vec<SwapResult> {
+        self.assert_allowed_by_compliance_hook();
+        self.execute_batch_swap(&account_id, swaps)
+    }
+
+    fn execute_batch_swap(
+        &mut self,
+        account_id: &AccountId,
+        swaps: Vec<(usize, AccountId, U128, SwapDirection)>,
+    ) -> Vec<SwapResult> {
+        // a swap that fails panics and reverts the ones before it
+        swaps
+            .into_iter()
+            .map(|(pool_id, token, amount, direction)| {
+                self.assert_pool_exists(pool_id);
+                self.assert_pool_not_paused(pool_id);
+                let other_token = self.pools[pool_id].toggle_token(&token);
+                let (swap_result, fees_amount) =
+                    self.apply_swap(pool_id, &token, amount.0, direction, None);
+                let amount_swapped = swap_result.amount.round() as u128;
+                let fees_amount = fees_amount.round() as u128;
+                if let Some(min_swap_amount) = self.pools[pool_id].min_swap_amount {
+                    let amount_in = match direction {
+                        SwapDirection::Return => amount.0,
+                        SwapDirection::Expense => amount_swapped,
+                    };
+                    assert!(amount_in >= min_swap_amount, "{}", SWAP_BELOW_MINIMUM);
+                }
+                match direction {
+                    SwapDirection::Return => {
+                        self.decrease_balance(account_id, &token, amount.0);
+                        self.increase_balance(account_id, &other_token, amount_swapped);
+                        self.decrease_balance(account_id, &other_token, fees_amount);
+                    }
+                    SwapDirection::Expense => {
+                        self.decrease_balance(
+                            account_id,
+                            &other_token,
+                            amount_swapped + fees_amount,
+                        );
+                        self.increase_balance(account_id, &token, amount.0);
+                    }
+                }
+                swap_result
+            })
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn swap_for_account(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    ) -> PromiseOrValue<U128> {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        if let Some(min_swap_amount) = self.pools[pool_id].min_swap_amount {
+            assert!(amount_in.0 >= min_swap_amount, "{}", SWAP_BELOW_MINIMUM);
+        }
+        match self.ask_compliance_hook(&account_id) {
+            Some(is_allowed) => is_allowed
+                .then(ext_self::resolve_compliance_swap(
+                    account_id,
+                    pool_id,
+                    token_in,
+                    amount_in,
+                    token_out,
+                    min_out,
+                    sqrt_price_limit,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_RESOLVE_SWAP,
+                ))
+                .into(),
+            None => PromiseOrValue::Value(self.execute_swap(
+                &account_id,
+                pool_id,
+                &token_in,
+                amount_in.into(),
+                &token_out,
+                min_out,
+                sqrt_price_limit,
+            )),
+        }
+    }
+
+    #[private]
+    #[allow(clippy::too_many_arguments)]
+    pub fn resolve_compliance_swap(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    ) -> U128 {
+        self.assert_allowed_by_compliance_hook();
+        self.assert_pool_exists(pool_id);
+        // the pool might get paused while the hook was called
+        self.assert_pool_not_paused(pool_id);
+        self.execute_swap(
+            &account_id,
+            pool_id,
+            &token_in,
+            amount_in.into(),
+            &token_out,
+            min_out,
+            sqrt_price_limit,
+        )
+    }
+
+    // The output is checked against `min_out` when the swap is executed, so a price
+    // moved by other swaps since it was quoted reverts the swap
+    #[allow(clippy::too_many_arguments)]
+    fn execute_swap(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: usize,
+        token_in: &AccountId,
+        amount_in: u128,
+        token_out: &AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    ) -> U128 {
+        let (swap_result, fees_amount) = self.apply_swap(
+            pool_id,
+            token_in,
+            amount_in,
+            SwapDirection::Return,
+            sqrt_price_limit,
+        );
+        // the part of the amount left at the price limit stays on the balance
+        let amount_in = amount_in - swap_result.remaining.round() as u128;
+        self.decrease_balance(account_id, token_in, amount_in);
+        let amount = swap_result.amount;
+        let amount_out = amount.round() as u128 - fees_amount.round() as u128;
+        if let Some(min_out) = min_out {
+            assert!(amount_out >= min_out.0, "{}", MIN_AMOUNT_OUT_NOT_REACHED);
+        }
+        self.increase_balance(account_id, token_out, amount.round() as u128);
+        self.decrease_balance(account_id, token_out, fees_amount.round() as u128);
+        (amount.round() as u128).into()
+    }
+
+    // Returns amount of token_out the pool gives away and the part of it taken as fees
+    fn internal_swap(
+        &mut self,
+        pool_id: usize,
+        token_in: &AccountId,
+        amount_in: u128,
+    ) -> (f64, f64) {
+        let (swap_result, fees_amount) =
+            self.apply_swap(pool_id, token_in, amount_in, SwapDirection::Return, None);
+        (swap_result.amount, fees_amount)
+    }
+
+    // Applies the swap to the pool and pays its fees, returns the swap result and the fee
+    // taken from its amount. Fees are paid in the token other than `token`
+    fn apply_swap(
+        &mut self,
+        pool_id: usize,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+        sqrt_price_limit: Option<f64>,
+    ) -> (SwapResult, f64) {
         let pool = &self.pools[pool_id];
-        let fees_amount = swap_result.amount * (pool.protocol_fee as f64 + pool.rewards as f64)
-            / BASIS_POINT_TO_PERCENT;
-        self.decrease_balance(&account_id, &token_out, fees_amount.round() as u128);
+        let swap_result = match sqrt_price_limit {
+            Some(limit) => pool.get_swap_result_with_limit(token, amount, direction, limit),
+            None => pool.get_swap_result(token, amount, direction),
+        };
+        let other_token = pool.toggle_token(token);
         let pool = &mut self.pools[pool_id];
+        let (_, fees_amount) = pool.apply_fee(swap_result.amount);
+        let protocol_fee = pool.protocol_fee_part(swap_result.amount).round() as u128;
+        let immediate_fee = pool.take_protocol_fee(&other_token, protocol_fee);
+        // positions collect their part of it by the fee growth of the swap
+        pool.accrue_lp_fee(
+            &other_token,
+            (fees_amount.round() as u128).saturating_sub(protocol_fee),
+        );
         pool.apply_swap_result(&swap_result);
+        let swapped = swap_result.amount.round() as u128;
+        let (token_in, amount_in, amount_out) = match direction {
+            SwapDirection::Return => {
+                let unswapped = swap_result.remaining.round() as u128;
+                (token, amount - unswapped, swapped)
+            }
+            SwapDirection::Expense => (&other_token, swapped, amount),
+        };
+        pool.record_volume(token_in, amount_in, amount_out, env::block_timestamp());
         pool.refresh(env::block_timestamp());
-        (swap_result.amount.round() as u128).into()
+        if immediate_fee > 0 {
+            self.transfer_protocol_fee(pool_id, &other_token, immediate_fee);
+        }
+        (swap_result, fees_amount)
     }
 
     pub fn open_position(
@@ -208,23 +1437,138 @@ impl Contract {
         upper_bound_price: f64,
     ) -> u128 {
         self.assert_pool_exists(pool_id);
-        let position_id = self.positions_opened;
-        self.positions_opened += 1;
+        self.assert_pool_not_paused(pool_id);
         let pool = &self.pools[pool_id];
         let account_id = env::predecessor_account_id();
-        let position = Position::new(
+        let (tick_lower, tick_upper) = pool.bounds_to_ticks(
+            pool.to_raw_price(lower_bound_price),
+            pool.to_raw_price(upper_bound_price),
+        );
+        let position = Position::new_with_ticks(
             account_id.clone(),
             token0_liquidity,
             token1_liquidity,
-            lower_bound_price,
-            upper_bound_price,
+            tick_lower,
+            tick_upper,
             pool.sqrt_price,
+            pool.tick_base,
+        );
+        self.insert_position(pool_id, account_id, position)
+    }
+
+    // Same as open_position with the range given as exact ticks, both must be multiples
+    // of the pool tick spacing
+    pub fn open_position_by_ticks(
+        &mut self,
+        pool_id: usize,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        tick_lower: i32,
+        tick_upper: i32,
+    ) -> u128 {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let pool = &self.pools[pool_id];
+        assert!(
+            tick_lower % pool.tick_spacing == 0 && tick_upper % pool.tick_spacing == 0,
+            "{}",
+            TICK_NOT_ALIGNED
+        );
+        let account_id = env::predecessor_account_id();
+        let position = Position::new_with_ticks(
+            account_id.clone(),
+            token0_liquidity,
+            token1_liquidity,
+            tick_lower,
+            tick_upper,
+            pool.sqrt_price,
+            pool.tick_base,
+        );
+        self.insert_position(pool_id, account_id, position)
+    }
+
+    // Same as open_position with the range from MIN_TICK to MAX_TICK, narrowed to the ticks
+    // of the pool tick spacing
+    pub fn open_full_range_position(
+        &mut self,
+        pool_id: usize,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+    ) -> u128 {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let pool = &self.pools[pool_id];
+        let account_id = env::predecessor_account_id();
+        let position = Position::full_range(
+            account_id.clone(),
+            token0_liquidity,
+            token1_liquidity,
+            pool.sqrt_price,
+            pool.tick_spacing,
+            pool.tick_base,
+        );
+        self.insert_position(pool_id, account_id, position)
+    }
+
+    // Opens a position one tick spacing wide starting at `price` with a single token,
+    // it is filled once the price crosses it and can be closed to claim the other token
+    pub fn open_limit_order(
+        &mut self,
+        pool_id: usize,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        price: f64,
+    ) -> u128 {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let pool = &self.pools[pool_id];
+        let account_id = env::predecessor_account_id();
+        let position = Position::limit_order(
+            account_id.clone(),
+            token0_liquidity,
+            token1_liquidity,
+            pool.to_raw_price(price),
+            pool.sqrt_price,
+            pool.tick_spacing,
+            pool.tick_base,
+        );
+        self.insert_position(pool_id, account_id, position)
+    }
+
+    pub fn get_filled_limit_orders(&self, pool_id: usize) -> Vec<U128> {
+        self.assert_pool_exists(pool_id);
+        self.pools[pool_id]
+            .check_filled_orders()
+            .into_iter()
+            .map(U128)
+            .collect()
+    }
+
+    fn insert_position(
+        &mut self,
+        pool_id: usize,
+        account_id: AccountId,
+        mut position: Position,
+    ) -> u128 {
+        let position_id = self.positions_opened;
+        self.positions_opened += 1;
+        let pool = &self.pools[pool_id];
+        assert!(
+            position.liquidity >= MIN_POSITION_LIQUIDITY,
+            "{}",
+            LIQUIDITY_TOO_SMALL
+        );
+        position.record_cashflow(
+            env::block_timestamp(),
+            -(position.token0_locked.round() as i128),
+            -(position.token1_locked.round() as i128),
         );
         let token0 = pool.token0.clone();
         let token1 = pool.token1.clone();
         self.decrease_balance(&account_id, &token0, position.token0_locked.round() as u128);
         self.decrease_balance(&account_id, &token1, position.token1_locked.round() as u128);
         let pool = &mut self.pools[pool_id];
+        position.last_update = env::block_timestamp();
         pool.open_position(position_id, position.clone());
         pool.refresh(env::block_timestamp());
         let metadata = TokenMetadata::new(pool_id, position_id, &position);
@@ -232,21 +1576,191 @@ impl Contract {
         position_id
     }
 
+    // Opens a position worth `total_value`, where one base unit of token0 is worth
+    // `reference_price_token0` and token1 is valued at the current pool price
+    pub fn add_liquidity_by_value(
+        &mut self,
+        pool_id: usize,
+        total_value: f64,
+        reference_price_token0: f64,
+        lower_bound_price: f64,
+        upper_bound_price: f64,
+    ) -> u128 {
+        self.assert_pool_exists(pool_id);
+        assert!(
+            total_value.is_finite()
+                && total_value > 0.0
+                && reference_price_token0.is_finite()
+                && reference_price_token0 > 0.0,
+            "{}",
+            BAD_VALUE_PARAMETERS
+        );
+        let pool = &self.pools[pool_id];
+        let reference_price_token1 = reference_price_token0 / (pool.sqrt_price * pool.sqrt_price);
+        let (x, y) = pool.amounts_for_liquidity(1.0, lower_bound_price, upper_bound_price);
+        let value_per_liquidity = x * reference_price_token0 + y * reference_price_token1;
+        assert!(value_per_liquidity > 0.0, "{}", ZERO_VALUE_PER_LIQUIDITY);
+        let liquidity = total_value / value_per_liquidity;
+        let (x, y) = (x * liquidity, y * liquidity);
+        if x.round() > 0.0 {
+            self.open_position(
+                pool_id,
+                Some(U128(x.round() as u128)),
+                None,
+                lower_bound_price,
+                upper_bound_price,
+            )
+        } else {
+            self.open_position(
+                pool_id,
+                None,
+                Some(U128(y.round() as u128)),
+                lower_bound_price,
+                upper_bound_price,
+            )
+        }
+    }
+
+    // Liquidity of open positions whose NFTs are owned by `account_id`, summed over all pools
+    pub fn get_account_total_liquidity(&self, account_id: AccountId) -> f64 {
+        let position_ids = match self.tokens_per_owner.get(&account_id) {
+            Some(position_ids) => position_ids,
+            None => return 0.0,
+        };
+        position_ids
+            .iter()
+            .map(|token_id| {
+                let position_id: u128 = token_id.parse().unwrap();
+                self.pools
+                    .iter()
+                    .filter_map(|pool| pool.positions.get(&position_id))
+                    .map(|position| position.liquidity)
+                    .sum::<f64>()
+            })
+            .sum()
+    }
+
     pub fn close_position(&mut self, pool_id: usize, position_id: u128) {
         self.assert_pool_exists(pool_id);
         let pool = &self.pools[pool_id];
         let account_id = env::predecessor_account_id();
         let token = self.tokens_by_id.get(&position_id.to_string()).unwrap();
         Self::assert_account_owns_nft(&account_id, &token.owner_id);
-        let position = pool.positions.get(&position_id).expect("Not found");
+        let position = pool.positions.get(&position_id).expect(POSITION_NOT_FOUND);
         let amount0 = position.token0_locked.round() as u128;
         let amount1 = position.token1_locked.round() as u128;
         let token0 = pool.token0.clone();
         let token1 = pool.token1.clone();
-        self.increase_balance(&account_id, &token0, amount0);
-        self.increase_balance(&account_id, &token1, amount1);
         let pool = &mut self.pools[pool_id];
+        let (fees0, fees1) = pool.collect_position_fees(&account_id, position_id);
+        pool.close_position(position_id);
+        self.increase_balance(&account_id, &token0, amount0 + fees0);
+        self.increase_balance(&account_id, &token1, amount1 + fees1);
+    }
+
+    pub fn close_to_single_token(
+        &mut self,
+        pool_id: usize,
+        position_id: u128,
+        desired_token: AccountId,
+        min_out: U128,
+    ) -> PromiseOrValue<U128> {
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        let account_id = env::predecessor_account_id();
+        match self.ask_compliance_hook(&account_id) {
+            Some(is_allowed) => is_allowed
+                .then(ext_self::resolve_compliance_close_to_single_token(
+                    account_id,
+                    pool_id,
+                    position_id,
+                    desired_token,
+                    min_out,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_RESOLVE_SWAP,
+                ))
+                .into(),
+            None => PromiseOrValue::Value(self.execute_close_to_single_token(
+                &account_id,
+                pool_id,
+                position_id,
+                &desired_token,
+                min_out,
+            )),
+        }
+    }
+
+    #[private]
+    pub fn resolve_compliance_close_to_single_token(
+        &mut self,
+        account_id: AccountId,
+        pool_id: usize,
+        position_id: u128,
+        desired_token: AccountId,
+        min_out: U128,
+    ) -> U128 {
+        self.assert_allowed_by_compliance_hook();
+        self.assert_pool_exists(pool_id);
+        self.assert_pool_not_paused(pool_id);
+        self.execute_close_to_single_token(
+            &account_id,
+            pool_id,
+            position_id,
+            &desired_token,
+            min_out,
+        )
+    }
+
+    fn execute_close_to_single_token(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: usize,
+        position_id: u128,
+        desired_token: &AccountId,
+        min_out: U128,
+    ) -> U128 {
+        let pool = &self.pools[pool_id];
+        let token = self.tokens_by_id.get(&position_id.to_string()).unwrap();
+        Self::assert_account_owns_nft(account_id, &token.owner_id);
+        assert!(
+            desired_token == &pool.token0 || desired_token == &pool.token1,
+            "{}",
+            INCORRECT_TOKEN
+        );
+        let position = pool.positions.get(&position_id).expect(POSITION_NOT_FOUND);
+        let (amount, other_amount, other_token) = if desired_token == &pool.token0 {
+            (
+                position.token0_locked.round() as u128,
+                position.token1_locked.round() as u128,
+                pool.token1.clone(),
+            )
+        } else {
+            (
+                position.token1_locked.round() as u128,
+                position.token0_locked.round() as u128,
+                pool.token0.clone(),
+            )
+        };
+        let token0 = pool.token0.clone();
+        let token1 = pool.token1.clone();
+        let pool = &mut self.pools[pool_id];
+        // earned fees are paid as they are, only the locked tokens are swapped
+        let (fees0, fees1) = pool.collect_position_fees(account_id, position_id);
         pool.close_position(position_id);
+        self.increase_balance(account_id, &token0, fees0);
+        self.increase_balance(account_id, &token1, fees1);
+        let mut total = amount;
+        if other_amount > 0 {
+            if let Some(min_swap_amount) = self.pools[pool_id].min_swap_amount {
+                assert!(other_amount >= min_swap_amount, "{}", SWAP_BELOW_MINIMUM);
+            }
+            let (swapped, fees_amount) = self.internal_swap(pool_id, &other_token, other_amount);
+            total += swapped.round() as u128 - fees_amount.round() as u128;
+        }
+        assert!(total >= min_out.0, "{}", MIN_AMOUNT_OUT_NOT_REACHED);
+        self.increase_balance(account_id, desired_token, total);
+        total.into()
     }
 
     pub fn add_liquidity(
@@ -257,34 +1771,38 @@ impl Contract {
         token1_liquidity: Option<U128>,
     ) {
         self.assert_pool_exists(pool_id);
-        let pool = &mut self.pools[pool_id];
+        self.assert_pool_not_paused(pool_id);
         let account_id = env::predecessor_account_id();
+        let pool = &mut self.pools[pool_id];
         let token = self.tokens_by_id.get(&position_id.0.to_string()).unwrap();
         Self::assert_account_owns_nft(&account_id, &token.owner_id);
         let mut position = pool
             .positions
             .get(&position_id.0)
-            .expect("Not found")
+            .expect(POSITION_NOT_FOUND)
             .clone();
-        let token0_locked_before = position.token0_locked as u128;
-        let token1_locked_before = position.token1_locked as u128;
+        pool.checkpoint_fee_growth(&mut position);
+        let (token0_locked_before, token1_locked_before) =
+            (position.token0_locked, position.token1_locked);
         position.add_liquidity(token0_liquidity, token1_liquidity, pool.sqrt_price);
-        let token0_locked_after = position.token0_locked as u128;
-        let token1_locked_after = position.token1_locked as u128;
-        pool.positions.insert(position_id.0, position);
+        // rounded against the caller: what is taken rounds up, what is paid out rounds down
+        let token0_in = (position.token0_locked - token0_locked_before)
+            .max(0.0)
+            .ceil() as u128;
+        let token1_in = (position.token1_locked - token1_locked_before)
+            .max(0.0)
+            .ceil() as u128;
+        position.record_cashflow(
+            env::block_timestamp(),
+            -(token0_in as i128),
+            -(token1_in as i128),
+        );
+        pool.update_position(position_id.0, position);
         pool.refresh(env::block_timestamp());
         let token0 = pool.token0.to_string();
         let token1 = pool.token1.to_string();
-        self.decrease_balance(
-            &account_id,
-            &token0,
-            token0_locked_after - token0_locked_before,
-        );
-        self.decrease_balance(
-            &account_id,
-            &token1,
-            token1_locked_after - token1_locked_before,
-        );
+        self.decrease_balance(&account_id, &token0, token0_in);
+        self.decrease_balance(&account_id, &token1, token1_in);
     }
 
     pub fn remove_liquidity(
@@ -293,7 +1811,7 @@ impl Contract {
         position_id: U128,
         token0_liquidity: Option<U128>,
         token1_liquidity: Option<U128>,
-    ) {
+    ) -> RemovedLiquidity {
         self.assert_pool_exists(pool_id);
         let pool = &mut self.pools[pool_id];
         let account_id = env::predecessor_account_id();
@@ -302,26 +1820,39 @@ impl Contract {
         let mut position = pool
             .positions
             .get(&position_id.0)
-            .expect("Not found")
+            .expect(POSITION_NOT_FOUND)
             .clone();
-        let token0_locked_before = position.token0_locked as u128;
-        let token1_locked_before = position.token1_locked as u128;
+        pool.checkpoint_fee_growth(&mut position);
+        let (token0_locked_before, token1_locked_before) =
+            (position.token0_locked, position.token1_locked);
         position.remove_liquidity(token0_liquidity, token1_liquidity, pool.sqrt_price);
-        let token0_locked_after = position.token0_locked as u128;
-        let token1_locked_after = position.token1_locked as u128;
-        pool.positions.insert(position_id.0, position);
+        // rounded down, against the caller as in add_liquidity
+        let token0_out = (token0_locked_before - position.token0_locked)
+            .max(0.0)
+            .floor() as u128;
+        let token1_out = (token1_locked_before - position.token1_locked)
+            .max(0.0)
+            .floor() as u128;
+        position.record_cashflow(
+            env::block_timestamp(),
+            token0_out as i128,
+            token1_out as i128,
+        );
+        pool.update_position(position_id.0, position);
         pool.refresh(env::block_timestamp());
         let token0 = pool.token0.to_string();
         let token1 = pool.token1.to_string();
-        self.increase_balance(
-            &account_id,
-            &token0,
-            token0_locked_before - token0_locked_after,
-        );
-        self.increase_balance(
-            &account_id,
-            &token1,
-            token1_locked_before - token1_locked_after,
-        );
+        self.increase_balance(&account_id, &token0, token0_out);
+        self.increase_balance(&account_id, &token1, token1_out);
+        let (requested, removed) = match token0_liquidity {
+            Some(requested) => (requested, token0_out),
+            None => (token1_liquidity.unwrap(), token1_out),
+        };
+        RemovedLiquidity {
+            requested,
+            removed: U128(removed),
+            token0_out: U128(token0_out),
+            token1_out: U128(token1_out),
+        }
     }
 }
@@ -0,0 +1,149 @@
+use crate::*;
+
+// State layout of the contract before fee tiers, fee growth and the fields added since,
+// read once by `migrate`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldContract {
+    pub owner_id: AccountId,
+    pub pools: Vec<OldPool>,
+    pub balances_map: BalancesMap,
+    pub tokens_per_owner: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub tokens_by_id: LookupMap<TokenId, Token>,
+    pub token_metadata_by_id: UnorderedMap<TokenId, TokenMetadata>,
+    pub metadata: LazyOption<NFTContractMetadata>,
+    pub positions_opened: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldPool {
+    pub token0: AccountId,
+    pub token1: AccountId,
+    pub liquidity: f64,
+    pub sqrt_price: f64,
+    pub token0_locked: u128,
+    pub token1_locked: u128,
+    pub tick: i32,
+    pub positions: HashMap<u128, OldPosition>,
+    pub protocol_fee: u16,
+    pub rewards: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldPosition {
+    pub owner_id: AccountId,
+    pub liquidity: f64,
+    pub token0_locked: f64,
+    pub token1_locked: f64,
+    pub tick_lower_bound_price: i32,
+    pub tick_upper_bound_price: i32,
+    pub sqrt_lower_bound_price: f64,
+    pub sqrt_upper_bound_price: f64,
+    pub is_active: bool,
+    pub last_update: u64,
+    pub rewards_for_time: u64,
+    pub fees_earned_token0: u128,
+    pub fees_earned_token1: u128,
+}
+
+#[near_bindgen]
+impl Contract {
+    // Converts the state written in the old layout, balance totals are summed from the
+    // balances since they weren't tracked before
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract = env::state_read().expect(OLD_STATE_NOT_FOUND);
+        let mut contract = Self {
+            owner_id: old.owner_id,
+            pools: Vec::new(),
+            balances_map: old.balances_map,
+            tokens_per_owner: old.tokens_per_owner,
+            tokens_by_id: old.tokens_by_id,
+            token_metadata_by_id: old.token_metadata_by_id,
+            metadata: old.metadata,
+            positions_opened: old.positions_opened,
+            pool_ids_by_tokens_and_fee: LookupMap::new(
+                StorageKey::PoolIdsByTokens.try_to_vec().unwrap(),
+            ),
+            compliance_hook: None,
+            whitelisted_tokens: UnorderedSet::new(
+                StorageKey::WhitelistedTokens.try_to_vec().unwrap(),
+            ),
+            flash_loans: LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+            balance_totals: LookupMap::new(StorageKey::BalanceTotals.try_to_vec().unwrap()),
+            pending_transfers: LookupMap::new(StorageKey::PendingTransfers.try_to_vec().unwrap()),
+        };
+        for old_pool in old.pools {
+            let pool = migrate_pool(old_pool);
+            let key = Self::pool_key(&pool.token0, &pool.token1, pool.fee_tier);
+            if !contract.pool_ids_by_tokens_and_fee.contains_key(&key) {
+                contract
+                    .pool_ids_by_tokens_and_fee
+                    .insert(&key, &contract.pools.len());
+            }
+            contract.pools.push(pool);
+        }
+        let balances: Vec<(AccountId, u128)> = contract
+            .balances_map
+            .values()
+            .flat_map(|balance| balance.iter().collect::<Vec<_>>())
+            .collect();
+        for (token, amount) in balances {
+            contract.add_to_balance_total(&token, amount);
+        }
+        contract
+    }
+}
+
+// Old pools charged any LP fee, they get the closest tier for lookups and keep their fee.
+// Positions keep their ticks, so the pool spacing is 1 whatever the tier
+fn migrate_pool(old: OldPool) -> Pool {
+    let fee_tier = [FeeTier::Low, FeeTier::Medium, FeeTier::High]
+        .iter()
+        .copied()
+        .min_by_key(|tier| (tier.bps() as i32 - old.rewards as i32).abs())
+        .unwrap();
+    let mut pool = Pool::new(
+        old.token0.clone(),
+        old.token1.clone(),
+        1.0,
+        old.protocol_fee,
+        fee_tier,
+        24,
+        24,
+    );
+    // old pools didn't order their tokens, the price is token1 per token0 as stored
+    pool.token0 = old.token0;
+    pool.token1 = old.token1;
+    pool.sqrt_price = old.sqrt_price;
+    pool.tick = old.tick;
+    pool.refreshed_tick = old.tick;
+    pool.rewards = old.rewards;
+    pool.tick_spacing = 1;
+    let mut positions: Vec<(u128, OldPosition)> = old.positions.into_iter().collect();
+    positions.sort_by_key(|(id, _)| *id);
+    for (id, old_position) in positions {
+        let position = Position {
+            owner_id: old_position.owner_id,
+            liquidity: old_position.liquidity,
+            token0_locked: old_position.token0_locked,
+            token1_locked: old_position.token1_locked,
+            tick_lower_bound_price: old_position.tick_lower_bound_price,
+            tick_upper_bound_price: old_position.tick_upper_bound_price,
+            sqrt_lower_bound_price: old_position.sqrt_lower_bound_price,
+            sqrt_upper_bound_price: old_position.sqrt_upper_bound_price,
+            is_active: old_position.is_active,
+            last_update: old_position.last_update,
+            rewards_for_time: old_position.rewards_for_time,
+            fees_earned_token0: old_position.fees_earned_token0,
+            fees_earned_token1: old_position.fees_earned_token1,
+            // the entry price wasn't stored, returns are counted from the migration
+            entry_sqrt_price: old.sqrt_price,
+            ..Position::default()
+        };
+        pool.open_position(id, position);
+    }
+    // open_position summed the locked totals from the positions
+    pool.liquidity = old.liquidity;
+    pool
+}
@@ -1,42 +1,145 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    serde::Serialize,
+    json_types::U128,
+    serde::{Deserialize, Serialize},
     AccountId,
 };
 
 use crate::{
-    errors::NOT_ENOUGH_LIQUIDITY_IN_POOL,
-    position::{sqrt_price_to_tick, tick_to_sqrt_price, Position},
-    BASIS_POINT_TO_PERCENT,
+    errors::{
+        BAD_BUCKETS_NUMBER, BAD_BUCKET_SIZE, BAD_FEE_TIER, BAD_SQRT_PRICE, BAD_SQRT_PRICE_LIMIT,
+        BAD_TICK_BASE, BAD_TICK_SAMPLING, LIMIT_ORDER_FILLED, LIQUIDITY_CAP_REACHED,
+        NOT_ENOUGH_LIQUIDITY_IN_POOL, NOT_POSITION_OWNER, POOL_HAS_POSITIONS,
+        POSITIONS_NOT_MERGEABLE, POSITION_NOT_FOUND, TICK_BASE_MISMATCH, TICK_NOT_ALIGNED,
+        TOKEN_NOT_IN_POOL, TOO_MANY_TICKS_CROSSED,
+    },
+    position::{
+        amount_to_f64, amounts_for_liquidity, bounds_to_ticks, sqrt_price_to_tick,
+        tick_to_sqrt_price, Position,
+    },
+    BASIS_POINT, BASIS_POINT_TO_PERCENT, DYNAMIC_FEE_WINDOW, LIQUIDITY_ROUNDING_ERROR,
+    MAX_LIQUIDITY_BUCKETS, MAX_SWAP_ITERATIONS, MAX_TICK, MAX_TICK_BASE, MAX_VOLUME_BUCKETS,
+    MIN_TICK, MIN_TICK_BASE, SWAP_EPSILON, VOLUME_BUCKET_NS,
 };
 
-#[derive(Clone)]
-pub struct CollectedFee {
-    pub account_id: AccountId,
-    pub amount: f64,
-    pub token: AccountId,
-}
-
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct SwapResult {
     pub amount: f64,
     pub new_liquidity: f64,
     pub new_sqrt_price: f64,
-    pub collected_fees: HashMap<u128, CollectedFee>,
+    pub remaining: f64, // part of the amount left unswapped
+    pub fee_growth_global_0: f64,
+    pub fee_growth_global_1: f64,
+    pub fee_growth_outside: BTreeMap<i32, (f64, f64)>, // updated values of crossed ticks
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub enum SwapDirection {
     Return,
     Expense,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize)]
+// Snapshot of the pool price and reserves
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolState {
+    pub sqrt_price: f64,
+    pub tick: i32,
+    pub liquidity: f64,
+    pub token0_locked: u128,
+    pub token1_locked: u128,
+}
+
+// Amounts of tokens swapped in the pool during [start_ts, start_ts + bucket size),
+// `saturated` is set once a volume stopped at u128::MAX instead of wrapping around
+#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VolumeBucket {
+    pub start_ts: u64,
+    pub volume_token0: u128,
+    pub volume_token1: u128,
+    pub saturated: bool,
+}
+
+impl VolumeBucket {
+    fn add(&mut self, volume_token0: u128, volume_token1: u128) {
+        let (sum0, overflow0) = self.volume_token0.overflowing_add(volume_token0);
+        let (sum1, overflow1) = self.volume_token1.overflowing_add(volume_token1);
+        self.volume_token0 = if overflow0 { u128::MAX } else { sum0 };
+        self.volume_token1 = if overflow1 { u128::MAX } else { sum1 };
+        self.saturated |= overflow0 || overflow1;
+    }
+}
+
+// Liquidity of positions within [lower_price, upper_price), human-readable prices
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidityBucket {
+    pub lower_price: f64,
+    pub upper_price: f64,
+    pub liquidity: f64,
+}
+
+// LP fee following the average tick move of the last DYNAMIC_FEE_WINDOW swaps, from `floor_bps`
+// when the price stands still up to `cap_bps` at `cap_tick_move` ticks per swap and more
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DynamicFee {
+    pub floor_bps: u16,
+    pub cap_bps: u16,
+    pub cap_tick_move: u32,
+}
+
+// How the protocol part of swap fees gets to the owner
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ProtocolFeeMode {
+    // Kept on the pool until the owner collects it
+    Accrue,
+    // Transferred to the owner with every swap
+    Immediate,
+}
+
+// LP fee in basis points
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FeeTier {
+    Low = 5,
+    Medium = 30,
+    High = 100,
+}
+
+impl FeeTier {
+    pub fn from_bps(bps: u16) -> FeeTier {
+        match bps {
+            5 => FeeTier::Low,
+            30 => FeeTier::Medium,
+            100 => FeeTier::High,
+            _ => panic!("{}", BAD_FEE_TIER),
+        }
+    }
+
+    pub fn bps(&self) -> u16 {
+        *self as u16
+    }
+
+    pub fn tick_spacing(&self) -> i32 {
+        match self {
+            FeeTier::Low => 1,
+            FeeTier::Medium => 10,
+            FeeTier::High => 50,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Pool {
-    pub token0: AccountId,
+    pub token0: AccountId, // always lexicographically less than token1
     pub token1: AccountId,
     pub liquidity: f64,
     pub sqrt_price: f64,
@@ -44,8 +147,72 @@ pub struct Pool {
     pub token1_locked: u128,
     pub tick: i32,
     pub positions: HashMap<u128, Position>,
+    // (lower bound tick, id) of every position, iterated where the order changes float results
+    pub position_order: BTreeSet<(i32, u128)>,
+    // Ids of the unfilled positions bounded by each tick
+    pub tick_positions: BTreeMap<i32, BTreeSet<u128>>,
+    // Positions active at the last refresh and the ones opened since, the others can only
+    // change when the price crosses one of their bounds
+    pub active_positions: BTreeSet<u128>,
+    // Tick of the price at the last refresh
+    pub refreshed_tick: i32,
     pub protocol_fee: u16,
     pub rewards: u16,
+    pub fee_tier: FeeTier,
+    pub tick_spacing: i32,
+    // Price ratio between neighbouring ticks, BASIS_POINT unless set for the pool
+    pub tick_base: f64,
+    pub token0_decimals: u8,
+    pub token1_decimals: u8,
+    // LP fees per unit of liquidity earned since the pool creation
+    pub fee_growth_global_0: f64,
+    pub fee_growth_global_1: f64,
+    // Fee growth on the other side of each position bound tick from the current price
+    pub fee_growth_outside: BTreeMap<i32, (f64, f64)>,
+    // Liquidity added (removed) when the price moves up across a position lower (upper) bound tick
+    pub liquidity_net: BTreeMap<i32, f64>,
+    // Liquidity of positions with the upper bound at the tick, still active exactly at its price
+    pub liquidity_upper: BTreeMap<i32, f64>,
+    // Last MAX_VOLUME_BUCKETS hourly buckets with swaps, oldest first
+    pub volume_buckets: Vec<VolumeBucket>,
+    // Limit on the liquidity of all positions summed, set by the owner
+    pub max_total_liquidity: Option<f64>,
+    // Swaps and liquidity adds are blocked, closing positions is still allowed
+    pub is_paused: bool,
+    // token1 paid per second to the liquidity active at the current price
+    pub reward_tokens_per_second: u128,
+    // Token paid as LP rewards instead of token1, `reward_per_liquidity_second` of it for every
+    // unit of liquidity and second a position is active, out of `reward_budget` set aside by the owner
+    pub reward_token: Option<AccountId>,
+    pub reward_per_liquidity_second: f64,
+    pub reward_budget: u128,
+    // Smaller swaps are rejected, in units of the input token
+    pub min_swap_amount: Option<u128>,
+    pub protocol_fee_mode: ProtocolFeeMode,
+    // Replaces `rewards` as the LP fee when set
+    pub dynamic_fee: Option<DynamicFee>,
+    // Absolute tick moves of the last swaps, oldest first, recorded with a dynamic fee only
+    pub recent_tick_moves: Vec<u32>,
+    // Protocol fees accrued and not collected by the owner yet
+    pub protocol_fees_token0: u128,
+    pub protocol_fees_token1: u128,
+    // LP fees earned by positions and not paid out yet
+    pub lp_fees_token0: u128,
+    pub lp_fees_token1: u128,
+    // Fees generated since the pool creation, collected or not
+    pub lifetime_protocol_fees_token0: u128,
+    pub lifetime_protocol_fees_token1: u128,
+    pub lifetime_lp_fees_token0: u128,
+    pub lifetime_lp_fees_token1: u128,
+}
+
+// Pool and its positions sorted by id, for exporting it from one contract version and importing
+// into another. Tick liquidity and the position order are left out, they follow from the positions
+#[derive(BorshDeserialize, BorshSerialize, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolSnapshot {
+    pub pool: Pool,
+    pub positions: Vec<(u128, Position)>,
 }
 
 impl Pool {
@@ -54,9 +221,24 @@ impl Pool {
         token1: AccountId,
         price: f64,
         protocol_fee: u16,
-        rewards: u16,
+        fee_tier: FeeTier,
+        token0_decimals: u8,
+        token1_decimals: u8,
     ) -> Pool {
-        let tick = sqrt_price_to_tick(price.sqrt());
+        // `price` is token1 per token0 in the order given, so it is inverted if tokens get swapped
+        let (token0, token1, price, token0_decimals, token1_decimals) = if token0 < token1 {
+            (token0, token1, price, token0_decimals, token1_decimals)
+        } else {
+            (
+                token1,
+                token0,
+                1.0 / price,
+                token1_decimals,
+                token0_decimals,
+            )
+        };
+        let price = price * decimals_factor(token0_decimals, token1_decimals);
+        let tick = sqrt_price_to_tick(price.sqrt(), BASIS_POINT);
         Pool {
             token0,
             token1,
@@ -65,10 +247,120 @@ impl Pool {
             token0_locked: 0,
             token1_locked: 0,
             positions: HashMap::new(),
+            position_order: BTreeSet::new(),
+            tick_positions: BTreeMap::new(),
+            active_positions: BTreeSet::new(),
+            refreshed_tick: tick,
             tick,
             protocol_fee,
-            rewards,
+            rewards: fee_tier.bps(),
+            fee_tier,
+            tick_spacing: fee_tier.tick_spacing(),
+            tick_base: BASIS_POINT,
+            token0_decimals,
+            token1_decimals,
+            fee_growth_global_0: 0.0,
+            fee_growth_global_1: 0.0,
+            fee_growth_outside: BTreeMap::new(),
+            liquidity_net: BTreeMap::new(),
+            liquidity_upper: BTreeMap::new(),
+            volume_buckets: Vec::new(),
+            max_total_liquidity: None,
+            is_paused: false,
+            reward_tokens_per_second: 0,
+            reward_token: None,
+            reward_per_liquidity_second: 0.0,
+            reward_budget: 0,
+            min_swap_amount: None,
+            protocol_fee_mode: ProtocolFeeMode::Accrue,
+            dynamic_fee: None,
+            recent_tick_moves: Vec::new(),
+            protocol_fees_token0: 0,
+            protocol_fees_token1: 0,
+            lp_fees_token0: 0,
+            lp_fees_token1: 0,
+            lifetime_protocol_fees_token0: 0,
+            lifetime_protocol_fees_token1: 0,
+            lifetime_lp_fees_token0: 0,
+            lifetime_lp_fees_token1: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        let mut pool = self.clone();
+        let mut positions: Vec<(u128, Position)> = pool.positions.drain().collect();
+        positions.sort_by_key(|(id, _)| *id);
+        pool.clear_position_indexes();
+        PoolSnapshot { pool, positions }
+    }
+
+    // Tick liquidity, the position indexes and the liquidity are rebuilt from the positions
+    // instead of taken from the snapshot
+    pub fn restore(snapshot: PoolSnapshot) -> Pool {
+        let mut pool = snapshot.pool;
+        pool.positions.clear();
+        pool.clear_position_indexes();
+        for (id, position) in snapshot.positions {
+            // liquidity of filled limit orders is already withdrawn from their ticks
+            if !position.is_filled {
+                pool.update_liquidity_net(&position, position.liquidity);
+            }
+            pool.index_position(id, &position);
+            pool.positions.insert(id, position);
         }
+        pool.refresh_liquidity();
+        pool
+    }
+
+    fn clear_position_indexes(&mut self) {
+        self.position_order.clear();
+        self.tick_positions.clear();
+        self.active_positions.clear();
+        self.liquidity_net.clear();
+        self.liquidity_upper.clear();
+    }
+
+    // Converts human-readable price (token1 per token0) to price in base units
+    pub fn to_raw_price(&self, price: f64) -> f64 {
+        price * decimals_factor(self.token0_decimals, self.token1_decimals)
+    }
+
+    pub fn to_human_price(&self, raw_price: f64) -> f64 {
+        raw_price / decimals_factor(self.token0_decimals, self.token1_decimals)
+    }
+
+    pub fn tick_to_sqrt_price(&self, tick: i32) -> f64 {
+        tick_to_sqrt_price(tick, self.tick_base)
+    }
+
+    pub fn sqrt_price_to_tick(&self, sqrt_price: f64) -> i32 {
+        sqrt_price_to_tick(sqrt_price, self.tick_base)
+    }
+
+    // Ticks of raw bound prices, lower rounded down and upper up to the pool tick spacing
+    pub fn bounds_to_ticks(&self, lower_bound_price: f64, upper_bound_price: f64) -> (i32, i32) {
+        bounds_to_ticks(
+            lower_bound_price,
+            upper_bound_price,
+            self.tick_spacing,
+            self.tick_base,
+        )
+    }
+
+    // Ticks of positions under different bases can't be compared, so the base only
+    // changes while the pool has no positions
+    pub fn set_tick_base(&mut self, tick_base: f64) {
+        assert!(
+            (MIN_TICK_BASE..=MAX_TICK_BASE).contains(&tick_base),
+            "{}",
+            BAD_TICK_BASE
+        );
+        assert!(self.positions.is_empty(), "{}", POOL_HAS_POSITIONS);
+        self.tick_base = tick_base;
+        self.tick = self.sqrt_price_to_tick(self.sqrt_price);
+        self.refreshed_tick = self.tick;
+        self.fee_growth_outside.clear();
+        self.recent_tick_moves.clear();
     }
 
     pub fn get_swap_result(
@@ -77,7 +369,169 @@ impl Pool {
         amount: u128,
         direction: SwapDirection,
     ) -> SwapResult {
-        if direction == SwapDirection::Return {
+        self.run_swap(token, amount, direction, true, false, None)
+    }
+
+    // Same as get_swap_result, but when the pool runs out of liquidity the swap stops there
+    // instead of panicking and `remaining` of the result is the part of the amount left unswapped
+    pub fn get_partial_swap_result(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+    ) -> SwapResult {
+        self.run_swap(token, amount, direction, true, true, None)
+    }
+
+    // Same as get_swap_result, but the price stops at `sqrt_price_limit` or where the liquidity
+    // ends before it, `remaining` of the result is the part of the amount left unswapped there
+    pub fn get_swap_result_with_limit(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+        sqrt_price_limit: f64,
+    ) -> SwapResult {
+        self.run_swap(token, amount, direction, true, true, Some(sqrt_price_limit))
+    }
+
+    // Token to swap in and its amount (before the swap fee) that moves the pool's sqrt price to
+    // `target_sqrt_price`, walking the ticks the same way a swap does
+    pub fn amount_to_reach_price(&self, target_sqrt_price: f64) -> (AccountId, u128) {
+        assert!(
+            target_sqrt_price.is_finite() && target_sqrt_price > 0.0,
+            "{}",
+            BAD_SQRT_PRICE
+        );
+        let price_goes_up = target_sqrt_price > self.sqrt_price;
+        let token_in = if price_goes_up {
+            self.token1.clone()
+        } else {
+            self.token0.clone()
+        };
+        let amount_in = |from: f64, to: f64, liquidity: f64| {
+            if price_goes_up {
+                (to - from) * liquidity
+            } else {
+                (1.0 / to - 1.0 / from) * liquidity
+            }
+        };
+        let mut tick = self.sqrt_price_to_tick(self.sqrt_price);
+        let mut price = self.sqrt_price;
+        let mut liquidity_below = self.liquidity_below(price);
+        let mut liquidity = self.liquidity_at(price, liquidity_below);
+        let mut amount = 0.0;
+        let mut iterations = 0;
+        while price != target_sqrt_price {
+            assert!(
+                iterations < MAX_SWAP_ITERATIONS,
+                "{}",
+                TOO_MANY_TICKS_CROSSED
+            );
+            iterations += 1;
+            if liquidity == 0.0 && !self.check_available_liquidity(price, price_goes_up) {
+                panic!("{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
+            }
+            let price_before_step = price;
+            if liquidity > 0.0 {
+                // the whole tick is walked, only the prices it moves between are needed
+                let mut remaining = f64::INFINITY;
+                self.get_amount_out_within_tick(
+                    &mut tick,
+                    &mut price,
+                    &token_in,
+                    &mut remaining,
+                    liquidity,
+                );
+            } else {
+                tick = self.next_tick(tick, price_goes_up);
+                price = self.tick_to_sqrt_price(tick);
+            }
+            if price_goes_up && price >= target_sqrt_price
+                || !price_goes_up && price <= target_sqrt_price
+            {
+                amount += amount_in(price_before_step, target_sqrt_price, liquidity);
+                break;
+            }
+            amount += amount_in(price_before_step, price, liquidity);
+            liquidity_below = self.cross_liquidity(price_before_step, price, liquidity_below);
+            liquidity = self.liquidity_at(price, liquidity_below);
+        }
+        (token_in, amount.ceil() as u128)
+    }
+
+    // Sqrt price after the swap, fees are not computed
+    pub fn price_after_swap(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+    ) -> f64 {
+        self.run_swap(token, amount, direction, false, false, None)
+            .new_sqrt_price
+    }
+
+    // Largest part of `amount` that moves the price by at most `max_impact_bps` basis points
+    // and can be swapped with the current liquidity, 0 if even the smallest unit can't
+    pub fn max_swap_within_impact(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+        max_impact_bps: u16,
+    ) -> u128 {
+        let price = self.sqrt_price * self.sqrt_price;
+        let max_impact = max_impact_bps as f64 / BASIS_POINT_TO_PERCENT;
+        let fits = |amount: u128| {
+            let result = self.run_swap(token, amount, direction, false, true, None);
+            let new_price = result.new_sqrt_price * result.new_sqrt_price;
+            result.remaining <= SWAP_EPSILON && (new_price / price - 1.0).abs() <= max_impact
+        };
+        // price impact only grows with the amount
+        let (mut low, mut high) = (0, amount);
+        while low < high {
+            let middle = high - (high - low) / 2;
+            if fits(middle) {
+                low = middle;
+            } else {
+                high = middle - 1;
+            }
+        }
+        low
+    }
+
+    // Without `with_fees` the result has no fees collected or fee growth updated,
+    // with `allow_partial` the swap stops where the liquidity ends or after
+    // MAX_SWAP_ITERATIONS ticks and with `sqrt_price_limit` it stops at that price
+    fn run_swap(
+        &self,
+        token: &AccountId,
+        amount: u128,
+        direction: SwapDirection,
+        with_fees: bool,
+        allow_partial: bool,
+        sqrt_price_limit: Option<f64>,
+    ) -> SwapResult {
+        assert!(
+            token == &self.token0 || token == &self.token1,
+            "{}",
+            TOKEN_NOT_IN_POOL
+        );
+        let price_goes_up = direction == SwapDirection::Expense && *token == self.token0
+            || direction == SwapDirection::Return && *token == self.token1;
+        if let Some(limit) = sqrt_price_limit {
+            let (min, max) = (
+                self.tick_to_sqrt_price(MIN_TICK),
+                self.tick_to_sqrt_price(MAX_TICK),
+            );
+            assert!(
+                price_goes_up && self.sqrt_price < limit && limit <= max
+                    || !price_goes_up && min <= limit && limit < self.sqrt_price,
+                "{}",
+                BAD_SQRT_PRICE_LIMIT
+            );
+        }
+        if direction == SwapDirection::Return && !allow_partial {
             if token == &self.token0 {
                 if amount > self.token0_locked {
                     panic!("{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
@@ -89,15 +543,54 @@ impl Pool {
             }
         }
         let mut collected = 0.0;
-        let mut tick = sqrt_price_to_tick(self.sqrt_price);
+        let mut tick = self.sqrt_price_to_tick(self.sqrt_price);
         let mut price = self.sqrt_price;
-        let mut remaining = amount as f64;
-        let mut collected_fees: HashMap<u128, CollectedFee> = HashMap::new();
-        while remaining > 0.0 {
-            let liquidity = self.calculate_liquidity_within_tick(price);
-            if liquidity == 0.0 && !self.check_available_liquidity(price, token, direction) {
+        let mut remaining = amount_to_f64(amount);
+        let mut fee_growth_global = (self.fee_growth_global_0, self.fee_growth_global_1);
+        let mut fee_growth_outside = BTreeMap::new();
+        let mut liquidity_below = self.liquidity_below(price);
+        let mut liquidity = self.liquidity_at(price, liquidity_below);
+        let mut iterations = 0;
+        while remaining > SWAP_EPSILON {
+            // a partial swap leaves the rest of the amount for another one
+            if iterations == MAX_SWAP_ITERATIONS {
+                assert!(allow_partial, "{}", TOO_MANY_TICKS_CROSSED);
+                break;
+            }
+            iterations += 1;
+            // the price cannot move past the extreme ticks
+            if price_goes_up && tick >= MAX_TICK || !price_goes_up && tick <= MIN_TICK {
+                assert!(allow_partial, "{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
+                break;
+            }
+            let price_before_step = price;
+            if liquidity == 0.0 && !self.check_available_liquidity(price, price_goes_up) {
+                if allow_partial {
+                    break;
+                }
                 panic!("{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
             }
+            // the step that would cross the price limit only goes as far as the limit,
+            // `beyond_limit` is the part of the remaining amount left for after it
+            let mut beyond_limit = None;
+            if let Some(limit) = sqrt_price_limit {
+                let next_price = self.tick_to_sqrt_price(self.next_tick(tick, price_goes_up));
+                if price_goes_up && next_price >= limit || !price_goes_up && next_price <= limit {
+                    if liquidity == 0.0 {
+                        price = limit;
+                        break;
+                    }
+                    let to_limit = if *token == self.token1 {
+                        (limit - price).abs() * liquidity
+                    } else {
+                        (1.0 / limit - 1.0 / price).abs() * liquidity
+                    };
+                    if to_limit < remaining {
+                        beyond_limit = Some(remaining - to_limit);
+                        remaining = to_limit;
+                    }
+                }
+            }
             let temp = match direction {
                 SwapDirection::Expense => self.get_amount_in_within_tick(
                     &mut tick,
@@ -114,86 +607,342 @@ impl Pool {
                     liquidity,
                 ),
             };
-            self.collect_fees(liquidity, price, temp, token, &mut collected_fees);
+            // the step computes the limit price up to rounding
+            if beyond_limit.is_some() {
+                price = sqrt_price_limit.unwrap();
+            }
+            if with_fees && liquidity > 0.0 {
+                let fee_growth = self.lp_fee(temp) / liquidity;
+                if self.toggle_token(token) == self.token0 {
+                    fee_growth_global.0 += fee_growth;
+                } else {
+                    fee_growth_global.1 += fee_growth;
+                }
+            }
+            liquidity_below = self.cross_liquidity(price_before_step, price, liquidity_below);
+            liquidity = self.liquidity_at(price, liquidity_below);
+            if with_fees {
+                self.cross_ticks(
+                    price_before_step,
+                    price,
+                    fee_growth_global,
+                    &mut fee_growth_outside,
+                );
+            }
             collected += temp;
+            if let Some(beyond_limit) = beyond_limit {
+                remaining += beyond_limit;
+                break;
+            }
         }
-        let liquidity = self.calculate_liquidity_within_tick(price);
+        let liquidity = if with_fees {
+            self.calculate_liquidity_within_tick(price)
+        } else {
+            liquidity
+        };
         SwapResult {
             amount: collected,
             new_liquidity: liquidity,
             new_sqrt_price: price,
-            collected_fees,
+            remaining,
+            fee_growth_global_0: fee_growth_global.0,
+            fee_growth_global_1: fee_growth_global.1,
+            fee_growth_outside,
+        }
+    }
+
+    // `amount` of the result is the output amount
+    pub fn swap_exact_in(&self, token_in: &AccountId, amount_in: u128) -> SwapResult {
+        self.get_swap_result(token_in, amount_in, SwapDirection::Return)
+    }
+
+    // `amount` of the result is the input amount
+    pub fn swap_exact_out(&self, token_out: &AccountId, amount_out: u128) -> SwapResult {
+        self.assert_can_pay_out(token_out, amount_out);
+        self.get_swap_result(token_out, amount_out, SwapDirection::Expense)
+    }
+
+    // The pool can't give away more of a token than its positions lock
+    pub fn assert_can_pay_out(&self, token_out: &AccountId, amount_out: u128) {
+        let token_out_locked = if token_out == &self.token0 {
+            self.token0_locked
+        } else {
+            self.token1_locked
+        };
+        if amount_out > token_out_locked {
+            panic!("{}", NOT_ENOUGH_LIQUIDITY_IN_POOL);
+        }
+    }
+
+    // Liquidity of positions with the lower bound at or below `sqrt_price` and the upper bound above it
+    fn liquidity_below(&self, sqrt_price: f64) -> f64 {
+        let mut liquidity = 0.0;
+        let mut max_liquidity: f64 = 0.0;
+        for (tick, liquidity_net) in &self.liquidity_net {
+            if self.tick_to_sqrt_price(*tick) > sqrt_price {
+                break;
+            }
+            liquidity += liquidity_net;
+            max_liquidity = max_liquidity.max(liquidity.abs());
         }
+        if liquidity.abs() <= max_liquidity * LIQUIDITY_ROUNDING_ERROR {
+            liquidity = 0.0;
+        }
+        liquidity
     }
 
-    fn collect_fees(
+    // Liquidity of positions active at `sqrt_price`, given `liquidity_below` at it
+    fn liquidity_at(&self, sqrt_price: f64, liquidity_below: f64) -> f64 {
+        let tick = self.sqrt_price_to_tick(sqrt_price);
+        let upper = self
+            .liquidity_upper
+            .range(tick - 1..=tick + 1)
+            .filter(|(tick, _)| self.tick_to_sqrt_price(**tick) == sqrt_price)
+            .map(|(_, liquidity)| liquidity)
+            .sum::<f64>();
+        liquidity_below + upper
+    }
+
+    // Adjusts `liquidity_below` by the net liquidity of the ticks crossed between two prices
+    fn cross_liquidity(
         &self,
-        liquidity: f64,
-        sqrt_price: f64,
-        amount: f64,
-        token: &AccountId,
-        collected_fees: &mut HashMap<u128, CollectedFee>,
-    ) {
-        for (i, position) in &self.positions {
-            if position.is_active(sqrt_price) {
-                let share = (position.liquidity / liquidity)
-                    * amount
-                    * (self.rewards as f64 / BASIS_POINT_TO_PERCENT);
-                let old_collected_fee_option = collected_fees.get(&i);
-                let mut old_share = 0.0;
-                if let Some(old_collected_fee) = old_collected_fee_option {
-                    old_share = old_collected_fee.amount;
+        sqrt_price_before: f64,
+        sqrt_price_after: f64,
+        liquidity_below: f64,
+    ) -> f64 {
+        let price_goes_up = sqrt_price_before < sqrt_price_after;
+        let (low, high) = if price_goes_up {
+            (sqrt_price_before, sqrt_price_after)
+        } else {
+            (sqrt_price_after, sqrt_price_before)
+        };
+        let ticks_range = self.sqrt_price_to_tick(low) - 1..=self.sqrt_price_to_tick(high) + 1;
+        let mut new_liquidity = liquidity_below;
+        for (tick, liquidity_net) in self.liquidity_net.range(ticks_range) {
+            let tick_sqrt_price = self.tick_to_sqrt_price(*tick);
+            if low < tick_sqrt_price && tick_sqrt_price <= high {
+                if price_goes_up {
+                    new_liquidity += liquidity_net;
+                } else {
+                    new_liquidity -= liquidity_net;
                 }
-                let collected_fee = CollectedFee {
-                    account_id: position.owner_id.clone(),
-                    amount: share + old_share,
-                    token: self.toggle_token(token),
-                };
-                collected_fees.insert(*i, collected_fee);
             }
         }
+        // all positions are left behind, only the rounding error of the additions remains
+        if new_liquidity.abs() <= liquidity_below.abs() * LIQUIDITY_ROUNDING_ERROR {
+            new_liquidity = 0.0;
+        }
+        new_liquidity
     }
 
-    fn toggle_token(&self, token: &AccountId) -> AccountId {
-        if token == &self.token0 {
-            self.token1.to_string()
+    // Flips fee growth outside of the position bound ticks the price moved across
+    fn cross_ticks(
+        &self,
+        sqrt_price_before: f64,
+        sqrt_price_after: f64,
+        fee_growth_global: (f64, f64),
+        fee_growth_outside: &mut BTreeMap<i32, (f64, f64)>,
+    ) {
+        let (low, high) = if sqrt_price_before < sqrt_price_after {
+            (sqrt_price_before, sqrt_price_after)
         } else {
-            self.token0.to_string()
+            (sqrt_price_after, sqrt_price_before)
+        };
+        let ticks_range = self.sqrt_price_to_tick(low) - 1..=self.sqrt_price_to_tick(high) + 1;
+        for (tick, outside) in self.fee_growth_outside.range(ticks_range) {
+            let sqrt_price = self.tick_to_sqrt_price(*tick);
+            if low < sqrt_price && sqrt_price <= high {
+                let outside = *fee_growth_outside.get(tick).unwrap_or(outside);
+                fee_growth_outside.insert(
+                    *tick,
+                    (
+                        fee_growth_global.0 - outside.0,
+                        fee_growth_global.1 - outside.1,
+                    ),
+                );
+            }
         }
     }
 
-    fn check_available_liquidity(
+    // Fee growth per unit of liquidity between two ticks
+    pub fn fee_growth_inside(&self, tick_lower: i32, tick_upper: i32) -> (f64, f64) {
+        self.fee_growth_inside_at(
+            tick_lower,
+            tick_upper,
+            self.sqrt_price,
+            (self.fee_growth_global_0, self.fee_growth_global_1),
+            &BTreeMap::new(),
+        )
+    }
+
+    // Fee growth between two ticks as of a swap result, its crossed ticks taking precedence
+    fn fee_growth_inside_at(
         &self,
+        tick_lower: i32,
+        tick_upper: i32,
         sqrt_price: f64,
-        token: &AccountId,
-        direction: SwapDirection,
-    ) -> bool {
-        for (_, position) in &self.positions {
-            if direction == SwapDirection::Expense && *token == self.token1
-                || direction == SwapDirection::Return && *token == self.token0
-            {
-                // price goes down
-                if position.sqrt_upper_bound_price < sqrt_price {
-                    return true;
-                }
+        global: (f64, f64),
+        fee_growth_outside: &BTreeMap<i32, (f64, f64)>,
+    ) -> (f64, f64) {
+        let outside = |tick: i32| {
+            fee_growth_outside
+                .get(&tick)
+                .or_else(|| self.fee_growth_outside.get(&tick))
+                .copied()
+                .unwrap_or_default()
+        };
+        let lower_outside = outside(tick_lower);
+        let upper_outside = outside(tick_upper);
+        let below = if self.tick_to_sqrt_price(tick_lower) <= sqrt_price {
+            lower_outside
+        } else {
+            (global.0 - lower_outside.0, global.1 - lower_outside.1)
+        };
+        let above = if sqrt_price < self.tick_to_sqrt_price(tick_upper) {
+            upper_outside
+        } else {
+            (global.0 - upper_outside.0, global.1 - upper_outside.1)
+        };
+        (global.0 - below.0 - above.0, global.1 - below.1 - above.1)
+    }
+
+    // Moves fees earned by `position` since its last checkpoint to its earned fees, rounded
+    // down to whole units
+    pub fn checkpoint_fee_growth(&self, position: &mut Position) {
+        let inside = self.fee_growth_inside(
+            position.tick_lower_bound_price,
+            position.tick_upper_bound_price,
+        );
+        let fees0 = (inside.0 - position.fee_growth_inside_last_0) * position.liquidity;
+        let fees1 = (inside.1 - position.fee_growth_inside_last_1) * position.liquidity;
+        position.fees_earned_token0 += fees0.max(0.0) as u128;
+        position.fees_earned_token1 += fees1.max(0.0) as u128;
+        position.fee_growth_inside_last_0 = inside.0;
+        position.fee_growth_inside_last_1 = inside.1;
+    }
+
+    // Copy of a position with the fees it earned up to now checkpointed
+    pub fn position_with_fees(&self, id: u128) -> Position {
+        let mut position = self.positions.get(&id).expect(POSITION_NOT_FOUND).clone();
+        self.checkpoint_fee_growth(&mut position);
+        position
+    }
+
+    // (token0, token1) fees owed to a position according to fee growth
+    pub fn position_fees_owed(&self, id: u128) -> (u128, u128) {
+        let position = self.position_with_fees(id);
+        (position.fees_earned_token0, position.fees_earned_token1)
+    }
+
+    fn init_tick(&mut self, tick: i32) {
+        if !self.fee_growth_outside.contains_key(&tick) {
+            // fee growth so far is assumed to have happened below the current price
+            let outside = if self.tick_to_sqrt_price(tick) <= self.sqrt_price {
+                (self.fee_growth_global_0, self.fee_growth_global_1)
             } else {
-                // price goes up
-                if position.sqrt_lower_bound_price > sqrt_price {
-                    return true;
-                }
+                (0.0, 0.0)
+            };
+            self.fee_growth_outside.insert(tick, outside);
+        }
+    }
+
+    pub fn toggle_token(&self, token: &AccountId) -> AccountId {
+        if token == &self.token0 {
+            self.token1.to_string()
+        } else {
+            self.token0.to_string()
+        }
+    }
+
+    // Whether the price, with no liquidity at `sqrt_price`, can still move into some position's
+    // range: going up a lower bound (positive net) has to lie above it, going down an upper
+    // bound (negative net) below it. Net left at zero by closed positions does not count
+    fn check_available_liquidity(&self, sqrt_price: f64, price_goes_up: bool) -> bool {
+        let tick = self.sqrt_price_to_tick(sqrt_price);
+        if price_goes_up {
+            self.liquidity_net
+                .range(tick..)
+                .any(|(tick, net)| *net > 0.0 && self.tick_to_sqrt_price(*tick) > sqrt_price)
+        } else {
+            self.liquidity_net
+                .range(..=tick)
+                .any(|(tick, net)| *net < 0.0 && self.tick_to_sqrt_price(*tick) < sqrt_price)
+        }
+    }
+
+    // Tick a swap step from `tick` goes to: the adjacent one, or on to the next position bound
+    // if none lies in between, as the liquidity doesn't change until then. A step from a
+    // bound only goes to the adjacent tick, positions bounded exactly at the price count there
+    fn next_tick(&self, tick: i32, price_goes_up: bool) -> i32 {
+        let (adjacent, end) = if price_goes_up {
+            (tick.saturating_add(1).min(MAX_TICK), MAX_TICK)
+        } else {
+            (tick.saturating_sub(1).max(MIN_TICK), MIN_TICK)
+        };
+        let is_bound = |tick: &i32| {
+            self.liquidity_net.contains_key(tick) || self.fee_growth_outside.contains_key(tick)
+        };
+        if is_bound(&tick) {
+            return adjacent;
+        }
+        if price_goes_up {
+            let net = self.liquidity_net.range(adjacent..).next();
+            let outside = self.fee_growth_outside.range(adjacent..).next();
+            match (net.map(|(tick, _)| *tick), outside.map(|(tick, _)| *tick)) {
+                (Some(net), Some(outside)) => net.min(outside),
+                (net, outside) => net.or(outside).unwrap_or(end),
+            }
+        } else {
+            let net = self.liquidity_net.range(..=adjacent).next_back();
+            let outside = self.fee_growth_outside.range(..=adjacent).next_back();
+            match (net.map(|(tick, _)| *tick), outside.map(|(tick, _)| *tick)) {
+                (Some(net), Some(outside)) => net.max(outside),
+                (net, outside) => net.or(outside).unwrap_or(end),
             }
         }
-        false
     }
 
+    // Positions sorted by lower bound tick and id, independent of the insertion order
+    fn ordered_positions(&self) -> impl Iterator<Item = (&u128, &Position)> {
+        self.position_order
+            .iter()
+            .map(move |(_, id)| (id, self.positions.get(id).unwrap()))
+    }
+
+    // Summed from the liquidity net of initialized ticks, not from every position
     fn calculate_liquidity_within_tick(&self, sqrt_price: f64) -> f64 {
-        let mut liquidity = 0.0;
-        for (_, position) in &self.positions {
+        self.liquidity_at(sqrt_price, self.liquidity_below(sqrt_price))
+    }
+
+    // Positions that can be active anywhere between the price of the last refresh and
+    // `sqrt_price`: the ones active then and the ones bounded by a tick in between
+    fn positions_in_reach(&self, sqrt_price: f64) -> BTreeSet<u128> {
+        let tick = self.sqrt_price_to_tick(sqrt_price);
+        // a tick of margin on both sides covers prices right at a bound
+        let low = tick.min(self.refreshed_tick).saturating_sub(1);
+        let high = tick.max(self.refreshed_tick).saturating_add(1);
+        let mut ids = self.active_positions.clone();
+        for (_, bounded) in self.tick_positions.range(low..=high) {
+            ids.extend(bounded);
+        }
+        ids
+    }
+
+    // Fractional share of LP fees of every owner with positions active at `sqrt_price`
+    pub fn fee_shares(&self, sqrt_price: f64) -> HashMap<AccountId, f64> {
+        let liquidity = self.calculate_liquidity_within_tick(sqrt_price);
+        let mut shares = HashMap::new();
+        if liquidity == 0.0 {
+            return shares;
+        }
+        for id in self.positions_in_reach(sqrt_price) {
+            let position = &self.positions[&id];
             if position.is_active(sqrt_price) {
-                liquidity += position.liquidity;
+                *shares.entry(position.owner_id.clone()).or_insert(0.0) +=
+                    position.liquidity / liquidity;
             }
         }
-        liquidity
+        shares
     }
 
     fn get_amount_in_within_tick(
@@ -208,8 +957,8 @@ impl Pool {
         let mut amount_in;
         let amount_out;
         if token_out == &self.token1 {
-            let new_tick = *tick - 1;
-            new_sqrt_price = tick_to_sqrt_price(new_tick);
+            let new_tick = self.next_tick(*tick, false);
+            new_sqrt_price = self.tick_to_sqrt_price(new_tick);
             amount_in = (1.0 / new_sqrt_price - 1.0 / *sqrt_price) * liquidity;
             amount_out = (new_sqrt_price - *sqrt_price) * liquidity;
             if amount_out.abs() > *remaining {
@@ -219,11 +968,11 @@ impl Pool {
                 *remaining = 0.0;
             } else {
                 *remaining -= amount_out.abs();
-                *tick -= 1;
+                *tick = new_tick;
             }
         } else {
-            let new_tick = *tick + 1;
-            new_sqrt_price = tick_to_sqrt_price(new_tick);
+            let new_tick = self.next_tick(*tick, true);
+            new_sqrt_price = self.tick_to_sqrt_price(new_tick);
             amount_in = (new_sqrt_price - *sqrt_price) * liquidity;
             amount_out = (1.0 / new_sqrt_price - 1.0 / *sqrt_price) * liquidity;
             if amount_out.abs() > *remaining {
@@ -233,7 +982,7 @@ impl Pool {
                 *remaining = 0.0;
             } else {
                 *remaining -= amount_out.abs();
-                *tick += 1;
+                *tick = new_tick;
             }
         }
         *sqrt_price = new_sqrt_price;
@@ -252,8 +1001,8 @@ impl Pool {
         let mut amount_out;
         let amount_in;
         if token_in == &self.token1 {
-            let new_tick = *tick + 1;
-            new_sqrt_price = tick_to_sqrt_price(new_tick);
+            let new_tick = self.next_tick(*tick, true);
+            new_sqrt_price = self.tick_to_sqrt_price(new_tick);
             amount_in = (new_sqrt_price - *sqrt_price) * liquidity;
             amount_out = (1.0 / new_sqrt_price - 1.0 / *sqrt_price) * liquidity;
             assert!(new_sqrt_price > *sqrt_price);
@@ -266,96 +1015,2221 @@ impl Pool {
                 assert!(amount_out < 0.0);
                 *remaining = 0.0;
             } else {
-                *remaining -= amount_in.abs();
-                *tick += 1;
+                *remaining -= amount_in.abs();
+                *tick = new_tick;
+            }
+        } else {
+            let new_tick = self.next_tick(*tick, false);
+            new_sqrt_price = self.tick_to_sqrt_price(new_tick);
+            amount_in = (1.0 / new_sqrt_price - 1.0 / *sqrt_price) * liquidity;
+            amount_out = (new_sqrt_price - *sqrt_price) * liquidity;
+            assert!(new_sqrt_price < *sqrt_price);
+            assert!(amount_out < 0.0);
+            if amount_in > *remaining {
+                let delta_reversed_sqrt_price = *remaining / liquidity;
+                new_sqrt_price = *sqrt_price / (delta_reversed_sqrt_price * *sqrt_price + 1.0);
+                amount_out = (new_sqrt_price - *sqrt_price) * liquidity;
+                assert!(new_sqrt_price < *sqrt_price);
+                assert!(amount_out < 0.0);
+                *remaining = 0.0;
+            } else {
+                *remaining -= amount_in.abs();
+                *tick = new_tick;
+            }
+        }
+        *sqrt_price = new_sqrt_price;
+        amount_out.abs()
+    }
+
+    // (token0, token1) amounts backing `liquidity` between human-readable bound prices
+    // at the current pool price, with bounds snapped to the pool tick spacing
+    pub fn amounts_for_liquidity(
+        &self,
+        liquidity: f64,
+        lower_bound_price: f64,
+        upper_bound_price: f64,
+    ) -> (f64, f64) {
+        let (tick_lower, tick_upper) = self.bounds_to_ticks(
+            self.to_raw_price(lower_bound_price),
+            self.to_raw_price(upper_bound_price),
+        );
+        amounts_for_liquidity(
+            liquidity,
+            self.sqrt_price,
+            self.tick_to_sqrt_price(tick_lower),
+            self.tick_to_sqrt_price(tick_upper),
+        )
+    }
+
+    // (token0, token1) locked in all positions at the current price,
+    // out of range positions hold only one of the tokens
+    pub fn total_locked(&self) -> (f64, f64) {
+        (self.token0_locked as f64, self.token1_locked as f64)
+    }
+
+    // Value of the locked tokens in token1 at the current price
+    pub fn tvl_in_token1(&self) -> f64 {
+        let (token0_locked, token1_locked) = self.total_locked();
+        token0_locked * self.sqrt_price * self.sqrt_price + token1_locked
+    }
+
+    // x * y of the virtual reserves backing the active liquidity at the current price,
+    // the pool behaves as a constant product one with this k until a position bound is crossed
+    pub fn current_segment_k(&self) -> f64 {
+        let x_virtual = self.liquidity / self.sqrt_price;
+        let y_virtual = self.liquidity * self.sqrt_price;
+        x_virtual * y_virtual
+    }
+
+    // Estimates (token0, token1) LP fees a new position would earn if `projected_volume`
+    // (denominated in token1) is traded evenly in both directions while the position stays in range
+    pub fn project_fee_income(&self, position: &Position, projected_volume: f64) -> (f64, f64) {
+        let liquidity = self.calculate_liquidity_within_tick(self.sqrt_price) + position.liquidity;
+        let share = position.liquidity / liquidity;
+        let fees =
+            projected_volume / 2.0 * share * (self.lp_fee_bps() as f64 / BASIS_POINT_TO_PERCENT);
+        let price = self.sqrt_price * self.sqrt_price;
+        (fees / price, fees)
+    }
+
+    pub fn get_sqrt_price(&self) -> f64 {
+        self.sqrt_price
+    }
+
+    // Human-readable price of token0 in token1
+    pub fn get_spot_price(&self) -> f64 {
+        self.to_human_price(self.sqrt_price * self.sqrt_price)
+    }
+
+    // Liquidity of positions active at the current price
+    pub fn refresh_liquidity(&mut self) {
+        self.liquidity = self.calculate_liquidity_within_tick(self.sqrt_price);
+    }
+
+    // Only positions whose locked amounts or activity can have changed since the last refresh
+    // are refreshed, the pool totals move by the change of their rounded locked amounts
+    pub fn refresh(&mut self, current_timestamp: u64) {
+        let ids = self.positions_in_reach(self.sqrt_price);
+        for id in &ids {
+            let position = self.positions.get_mut(id).unwrap();
+            let before = rounded_locked(position);
+            position.refresh(self.sqrt_price, current_timestamp);
+            let after = rounded_locked(position);
+            self.token0_locked = self.token0_locked + after.0 - before.0;
+            self.token1_locked = self.token1_locked + after.1 - before.1;
+        }
+        self.fill_limit_orders(&ids);
+        let positions = &self.positions;
+        let sqrt_price = self.sqrt_price;
+        self.active_positions = ids
+            .into_iter()
+            .filter(|id| positions[id].is_active(sqrt_price))
+            .collect();
+        self.refreshed_tick = self.tick;
+        self.refresh_liquidity();
+    }
+
+    // Withdraws liquidity of limit orders among `ids` crossed by the price, so they keep the
+    // converted tokens even if the price comes back
+    fn fill_limit_orders(&mut self, ids: &BTreeSet<u128>) {
+        let crossed: Vec<u128> = ids
+            .iter()
+            .copied()
+            .filter(|id| {
+                let position = &self.positions[id];
+                !position.is_filled && position.is_crossed(self.sqrt_price)
+            })
+            .collect();
+        for id in crossed {
+            let mut position = self.positions.get(&id).unwrap().clone();
+            self.checkpoint_fee_growth(&mut position);
+            self.update_liquidity_net(&position, -position.liquidity);
+            position.liquidity = 0.0;
+            position.is_filled = true;
+            position.is_active = false;
+            let (tick_lower, tick_upper) = (
+                position.tick_lower_bound_price,
+                position.tick_upper_bound_price,
+            );
+            self.unindex_position(id, &position);
+            self.positions.insert(id, position);
+            self.clear_tick(tick_lower);
+            self.clear_tick(tick_upper);
+        }
+    }
+
+    // (token0, token1) fees the position earned since the last collect, resets them while
+    // the liquidity stays, `account_id` is the caller and has to own the position
+    pub fn collect_position_fees(&mut self, account_id: &AccountId, id: u128) -> (u128, u128) {
+        let mut position = self.position_with_fees(id);
+        assert!(&position.owner_id == account_id, "{}", NOT_POSITION_OWNER);
+        let fees = (position.fees_earned_token0, position.fees_earned_token1);
+        position.fees_earned_token0 = 0;
+        position.fees_earned_token1 = 0;
+        self.positions.insert(id, position);
+        // positions round their fees down, so they never earn more than the pool holds
+        self.lp_fees_token0 = self.lp_fees_token0.saturating_sub(fees.0);
+        self.lp_fees_token1 = self.lp_fees_token1.saturating_sub(fees.1);
+        fees
+    }
+
+    // Adds the fees the position earned back to its liquidity at the current price, the part
+    // that doesn't fit the position's token ratio (all of one token out of range) stays earned
+    pub fn compound_position(&mut self, id: u128) {
+        let mut position = self.positions.get(&id).expect(POSITION_NOT_FOUND).clone();
+        self.checkpoint_fee_growth(&mut position);
+        let before = rounded_locked(&position);
+        let (left0, left1) = position.add_liquidity_both(
+            U128(position.fees_earned_token0),
+            U128(position.fees_earned_token1),
+            self.sqrt_price,
+        );
+        position.fees_earned_token0 = left0.round() as u128;
+        position.fees_earned_token1 = left1.round() as u128;
+        // the reinvested fees move from the fees the pool holds for positions to its liquidity,
+        // update_position adds them to the locked totals
+        let after = rounded_locked(&position);
+        self.lp_fees_token0 = self.lp_fees_token0.saturating_sub(after.0 - before.0);
+        self.lp_fees_token1 = self.lp_fees_token1.saturating_sub(after.1 - before.1);
+        self.update_position(id, position);
+        self.refresh_liquidity();
+    }
+
+    // Pays the time a position was active at `reward_tokens_per_second` by its share of the
+    // liquidity active now and resets that time. With a reward token the time is paid by the
+    // position's liquidity at `reward_per_liquidity_second` while the budget lasts
+    pub fn claim_rewards(&mut self, id: u128) -> u128 {
+        if self.reward_token.is_some() {
+            let position = self.positions.get_mut(&id).unwrap();
+            let seconds = position.rewards_for_time as f64 / 1e9;
+            let reward = (seconds * position.liquidity * self.reward_per_liquidity_second).round();
+            let reward = (reward as u128).min(self.reward_budget);
+            self.reward_budget -= reward;
+            position.rewards_for_time = 0;
+            return reward;
+        }
+        let mut liquidity = self.calculate_liquidity_within_tick(self.sqrt_price);
+        let position = self.positions.get_mut(&id).unwrap();
+        if !position.is_active(self.sqrt_price) {
+            liquidity += position.liquidity;
+        }
+        if liquidity == 0.0 {
+            return 0;
+        }
+        let seconds = position.rewards_for_time as f64 / 1e9;
+        let reward =
+            seconds * self.reward_tokens_per_second as f64 * position.liquidity / liquidity;
+        position.rewards_for_time = 0;
+        reward.round() as u128
+    }
+
+    // Ids of limit orders the price has crossed, ready to be closed
+    pub fn check_filled_orders(&self) -> Vec<u128> {
+        self.ordered_positions()
+            .filter(|(_, position)| position.is_filled)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub fn state(&self) -> PoolState {
+        PoolState {
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            liquidity: self.liquidity,
+            token0_locked: self.token0_locked,
+            token1_locked: self.token1_locked,
+        }
+    }
+
+    // Applies `swaps` to a copy of the pool and returns its state after each of them
+    pub fn simulate_swap_sequence(
+        &self,
+        swaps: &[(AccountId, u128, SwapDirection)],
+        current_timestamp: u64,
+    ) -> Vec<PoolState> {
+        let mut pool = self.clone();
+        let mut states = Vec::new();
+        for (token, amount, direction) in swaps {
+            let swap_result = pool.get_swap_result(token, *amount, *direction);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(current_timestamp);
+            states.push(pool.state());
+        }
+        states
+    }
+
+    // Adds a swap to the volume of the hour its `timestamp` belongs to
+    pub fn record_volume(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: u128,
+        amount_out: u128,
+        timestamp: u64,
+    ) {
+        let (volume_token0, volume_token1) = if *token_in == self.token0 {
+            (amount_in, amount_out)
+        } else {
+            (amount_out, amount_in)
+        };
+        let start_ts = timestamp - timestamp % VOLUME_BUCKET_NS;
+        match self.volume_buckets.last_mut() {
+            Some(bucket) if bucket.start_ts == start_ts => {
+                bucket.add(volume_token0, volume_token1);
+            }
+            _ => {
+                if self.volume_buckets.len() == MAX_VOLUME_BUCKETS {
+                    self.volume_buckets.remove(0);
+                }
+                self.volume_buckets.push(VolumeBucket {
+                    start_ts,
+                    volume_token0,
+                    volume_token1,
+                    saturated: false,
+                });
+            }
+        }
+    }
+
+    // Liquidity of positions split into `buckets` equal price ranges between the lowest and
+    // the highest position bound, a position adds its liquidity to the buckets it overlaps
+    // in proportion to the part of its price range in each of them
+    pub fn liquidity_histogram(&self, buckets: u32) -> Vec<LiquidityBucket> {
+        assert!(
+            0 < buckets && buckets <= MAX_LIQUIDITY_BUCKETS,
+            "{}",
+            BAD_BUCKETS_NUMBER
+        );
+        let positions: Vec<&Position> = self
+            .ordered_positions()
+            .map(|(_, position)| position)
+            .filter(|position| position.liquidity > 0.0)
+            .collect();
+        if positions.is_empty() {
+            return Vec::new();
+        }
+        let bounds = |position: &Position| {
+            (
+                position.sqrt_lower_bound_price.powi(2),
+                position.sqrt_upper_bound_price.powi(2),
+            )
+        };
+        let lowest = positions
+            .iter()
+            .map(|position| bounds(position).0)
+            .fold(f64::INFINITY, f64::min);
+        let highest = positions
+            .iter()
+            .map(|position| bounds(position).1)
+            .fold(0.0, f64::max);
+        let width = (highest - lowest) / buckets as f64;
+        let mut histogram: Vec<LiquidityBucket> = (0..buckets)
+            .map(|i| LiquidityBucket {
+                lower_price: lowest + width * i as f64,
+                upper_price: lowest + width * (i + 1) as f64,
+                liquidity: 0.0,
+            })
+            .collect();
+        for position in positions {
+            let (lower, upper) = bounds(position);
+            let first = (((lower - lowest) / width) as usize).min(buckets as usize - 1);
+            let last = (((upper - lowest) / width) as usize).min(buckets as usize - 1);
+            for bucket in &mut histogram[first..=last] {
+                let overlap = upper.min(bucket.upper_price) - lower.max(bucket.lower_price);
+                if overlap > 0.0 {
+                    bucket.liquidity += position.liquidity * overlap / (upper - lower);
+                }
+            }
+        }
+        for bucket in &mut histogram {
+            bucket.lower_price = self.to_human_price(bucket.lower_price);
+            bucket.upper_price = self.to_human_price(bucket.upper_price);
+        }
+        histogram
+    }
+
+    // Liquidity of positions active at every `step`-th tick from `tick_lower` to `tick_upper`
+    pub fn liquidity_distribution(
+        &self,
+        tick_lower: i32,
+        tick_upper: i32,
+        step: i32,
+    ) -> Vec<(i32, f64)> {
+        assert!(
+            step > 0
+                && tick_lower <= tick_upper
+                && ((tick_upper as i64 - tick_lower as i64) / step as i64)
+                    < MAX_LIQUIDITY_BUCKETS as i64,
+            "{}",
+            BAD_TICK_SAMPLING
+        );
+        (tick_lower..=tick_upper)
+            .step_by(step as usize)
+            .map(|tick| {
+                (
+                    tick,
+                    self.calculate_liquidity_within_tick(self.tick_to_sqrt_price(tick)),
+                )
+            })
+            .collect()
+    }
+
+    // Last `limit` buckets of `bucket_size` ns with swaps, oldest first
+    pub fn volume_buckets(&self, bucket_size: u64, limit: usize) -> Vec<VolumeBucket> {
+        assert!(
+            bucket_size > 0 && bucket_size.is_multiple_of(VOLUME_BUCKET_NS),
+            "{}",
+            BAD_BUCKET_SIZE
+        );
+        let mut buckets: Vec<VolumeBucket> = Vec::new();
+        for bucket in &self.volume_buckets {
+            let start_ts = bucket.start_ts - bucket.start_ts % bucket_size;
+            match buckets.last_mut() {
+                Some(last) if last.start_ts == start_ts => {
+                    last.add(bucket.volume_token0, bucket.volume_token1);
+                    last.saturated |= bucket.saturated;
+                }
+                _ => buckets.push(VolumeBucket {
+                    start_ts,
+                    ..bucket.clone()
+                }),
+            }
+        }
+        let skipped = buckets.len().saturating_sub(limit);
+        buckets.split_off(skipped)
+    }
+
+    // Splits `amount` into (net, fee) by the total fee rate, LP rewards and protocol fee together
+    pub fn apply_fee(&self, amount: f64) -> (f64, f64) {
+        let total_rate = (self.protocol_fee + self.lp_fee_bps()) as f64;
+        let fee = amount * total_rate / BASIS_POINT_TO_PERCENT;
+        (amount - fee, fee)
+    }
+
+    // LP fee rate swaps pay now, `rewards` unless the fee is dynamic
+    pub fn lp_fee_bps(&self) -> u16 {
+        let dynamic_fee = match self.dynamic_fee {
+            Some(dynamic_fee) => dynamic_fee,
+            None => return self.rewards,
+        };
+        if self.recent_tick_moves.is_empty() {
+            return dynamic_fee.floor_bps;
+        }
+        let average_move =
+            self.recent_tick_moves.iter().sum::<u32>() as f64 / self.recent_tick_moves.len() as f64;
+        let volatility = (average_move / dynamic_fee.cap_tick_move as f64).min(1.0);
+        let range = (dynamic_fee.cap_bps - dynamic_fee.floor_bps) as f64;
+        dynamic_fee.floor_bps + (range * volatility).round() as u16
+    }
+
+    fn record_tick_move(&mut self, tick_before: i32) {
+        if self.dynamic_fee.is_none() {
+            return;
+        }
+        if self.recent_tick_moves.len() == DYNAMIC_FEE_WINDOW {
+            self.recent_tick_moves.remove(0);
+        }
+        self.recent_tick_moves
+            .push((self.tick - tick_before).unsigned_abs());
+    }
+
+    // Part of the fee taken from `amount` that goes to liquidity providers
+    fn lp_fee(&self, amount: f64) -> f64 {
+        let lp_fee_bps = self.lp_fee_bps();
+        if lp_fee_bps == 0 {
+            return 0.0;
+        }
+        let (_, fee) = self.apply_fee(amount);
+        fee * (lp_fee_bps as f64 / (self.protocol_fee + lp_fee_bps) as f64)
+    }
+
+    // Part of the fee taken from `amount` that goes to the protocol
+    pub fn protocol_fee_part(&self, amount: f64) -> f64 {
+        let (_, fee) = self.apply_fee(amount);
+        fee - self.lp_fee(amount)
+    }
+
+    // Returns the part of the protocol fee to transfer right away, the rest is accrued
+    pub fn take_protocol_fee(&mut self, token: &AccountId, amount: u128) -> u128 {
+        if token == &self.token0 {
+            self.lifetime_protocol_fees_token0 += amount;
+        } else {
+            self.lifetime_protocol_fees_token1 += amount;
+        }
+        match self.protocol_fee_mode {
+            ProtocolFeeMode::Immediate => amount,
+            ProtocolFeeMode::Accrue => {
+                self.accrue_protocol_fee(token, amount);
+                0
+            }
+        }
+    }
+
+    pub fn accrue_protocol_fee(&mut self, token: &AccountId, amount: u128) {
+        if token == &self.token0 {
+            self.protocol_fees_token0 += amount;
+        } else {
+            self.protocol_fees_token1 += amount;
+        }
+    }
+
+    // Liquidity of all positions, active or not
+    pub fn total_liquidity(&self) -> f64 {
+        self.positions
+            .values()
+            .map(|position| position.liquidity)
+            .sum()
+    }
+
+    fn assert_liquidity_cap(&self, liquidity_delta: f64) {
+        if let Some(max_total_liquidity) = self.max_total_liquidity {
+            assert!(
+                self.total_liquidity() + liquidity_delta <= max_total_liquidity,
+                "{}",
+                LIQUIDITY_CAP_REACHED
+            );
+        }
+    }
+
+    pub fn open_position(&mut self, id: u128, mut position: Position) {
+        assert!(
+            position.tick_base == self.tick_base,
+            "{}",
+            TICK_BASE_MISMATCH
+        );
+        // bounds only on multiples of the spacing keep the number of initialized ticks down
+        assert!(
+            position.tick_lower_bound_price % self.tick_spacing == 0
+                && position.tick_upper_bound_price % self.tick_spacing == 0,
+            "{}",
+            TICK_NOT_ALIGNED
+        );
+        self.assert_liquidity_cap(position.liquidity);
+        self.init_tick(position.tick_lower_bound_price);
+        self.init_tick(position.tick_upper_bound_price);
+        let inside = self.fee_growth_inside(
+            position.tick_lower_bound_price,
+            position.tick_upper_bound_price,
+        );
+        position.fee_growth_inside_last_0 = inside.0;
+        position.fee_growth_inside_last_1 = inside.1;
+        self.update_liquidity_net(&position, position.liquidity);
+        self.index_position(id, &position);
+        let (token0, token1) = rounded_locked(&position);
+        self.token0_locked += token0;
+        self.token1_locked += token1;
+        self.positions.insert(id, position);
+    }
+
+    // Replaces a position whose liquidity may have changed
+    pub fn update_position(&mut self, id: u128, position: Position) {
+        let old_position = self.positions.get(&id).unwrap();
+        assert!(!old_position.is_filled, "{}", LIMIT_ORDER_FILLED);
+        let old_liquidity = old_position.liquidity;
+        let before = rounded_locked(old_position);
+        if position.liquidity > old_liquidity {
+            self.assert_liquidity_cap(position.liquidity - old_liquidity);
+        }
+        self.update_liquidity_net(&position, position.liquidity - old_liquidity);
+        let after = rounded_locked(&position);
+        self.token0_locked = self.token0_locked + after.0 - before.0;
+        self.token1_locked = self.token1_locked + after.1 - before.1;
+        self.positions.insert(id, position);
+    }
+
+    // Adds the position to the indexes by tick, filled limit orders are left out of the ones
+    // refresh looks positions up in
+    fn index_position(&mut self, id: u128, position: &Position) {
+        self.position_order
+            .insert((position.tick_lower_bound_price, id));
+        if !position.is_filled {
+            for tick in [
+                position.tick_lower_bound_price,
+                position.tick_upper_bound_price,
+            ] {
+                self.tick_positions.entry(tick).or_default().insert(id);
+            }
+            self.active_positions.insert(id);
+        }
+    }
+
+    // Removes the position from the indexes refresh looks positions up in
+    fn unindex_position(&mut self, id: u128, position: &Position) {
+        for tick in [
+            position.tick_lower_bound_price,
+            position.tick_upper_bound_price,
+        ] {
+            if let Some(ids) = self.tick_positions.get_mut(&tick) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.tick_positions.remove(&tick);
+                }
+            }
+        }
+        self.active_positions.remove(&id);
+    }
+
+    fn update_liquidity_net(&mut self, position: &Position, liquidity_delta: f64) {
+        *self
+            .liquidity_net
+            .entry(position.tick_lower_bound_price)
+            .or_insert(0.0) += liquidity_delta;
+        *self
+            .liquidity_net
+            .entry(position.tick_upper_bound_price)
+            .or_insert(0.0) -= liquidity_delta;
+        *self
+            .liquidity_upper
+            .entry(position.tick_upper_bound_price)
+            .or_insert(0.0) += liquidity_delta;
+    }
+
+    // Forgets a tick no position is bounded by anymore
+    fn clear_tick(&mut self, tick: i32) {
+        if !self.tick_positions.contains_key(&tick) {
+            self.liquidity_net.remove(&tick);
+            self.liquidity_upper.remove(&tick);
+        }
+    }
+
+    pub fn close_position(&mut self, id: u128) {
+        let position = self.positions.get(&id).unwrap().clone();
+        if position.is_active(self.sqrt_price) {
+            self.liquidity -= position.liquidity;
+        }
+        let (token0, token1) = rounded_locked(&position);
+        self.token0_locked -= token0;
+        self.token1_locked -= token1;
+        self.update_liquidity_net(&position, -position.liquidity);
+        self.positions.remove(&id);
+        self.position_order
+            .remove(&(position.tick_lower_bound_price, id));
+        self.unindex_position(id, &position);
+        self.clear_tick(position.tick_lower_bound_price);
+        self.clear_tick(position.tick_upper_bound_price);
+    }
+
+    // Moves position `b` into position `a`, both must belong to one owner and have the same range
+    pub fn merge_positions(&mut self, a: u128, b: u128) {
+        assert!(a != b, "{}", POSITIONS_NOT_MERGEABLE);
+        let mut first = self.positions.get(&a).unwrap().clone();
+        let mut second = self.positions.get(&b).unwrap().clone();
+        assert!(
+            first.owner_id == second.owner_id
+                && !first.is_filled
+                && !second.is_filled
+                && first.tick_lower_bound_price == second.tick_lower_bound_price
+                && first.tick_upper_bound_price == second.tick_upper_bound_price,
+            "{}",
+            POSITIONS_NOT_MERGEABLE
+        );
+        self.checkpoint_fee_growth(&mut first);
+        self.checkpoint_fee_growth(&mut second);
+        let (before_first, before_second) = (rounded_locked(&first), rounded_locked(&second));
+        first.liquidity += second.liquidity;
+        first.token0_locked += second.token0_locked;
+        first.token1_locked += second.token1_locked;
+        first.fees_earned_token0 += second.fees_earned_token0;
+        first.fees_earned_token1 += second.fees_earned_token1;
+        first.rewards_for_time += second.rewards_for_time;
+        first.cashflows.append(&mut second.cashflows);
+        first.cashflows.sort_by_key(|cashflow| cashflow.timestamp);
+        first.trim_cashflows();
+        // net liquidity of the shared ticks already includes both positions
+        let after = rounded_locked(&first);
+        self.token0_locked = self.token0_locked + after.0 - before_first.0 - before_second.0;
+        self.token1_locked = self.token1_locked + after.1 - before_first.1 - before_second.1;
+        self.positions.remove(&b);
+        self.position_order
+            .remove(&(second.tick_lower_bound_price, b));
+        self.unindex_position(b, &second);
+        self.positions.insert(a, first);
+    }
+
+    // Adds `fee` in `token` to the fee growth of the liquidity active at the current price,
+    // false if there is none to earn it
+    pub fn distribute_fee(&mut self, token: &AccountId, fee: u128) -> bool {
+        let liquidity = self.calculate_liquidity_within_tick(self.sqrt_price);
+        if liquidity == 0.0 {
+            return false;
+        }
+        let fee_growth = amount_to_f64(fee) / liquidity;
+        if token == &self.token0 {
+            self.fee_growth_global_0 += fee_growth;
+        } else {
+            self.fee_growth_global_1 += fee_growth;
+        }
+        self.accrue_lp_fee(token, fee);
+        true
+    }
+
+    // LP fees are held by the pool until positions collect them
+    pub fn accrue_lp_fee(&mut self, token: &AccountId, amount: u128) {
+        if token == &self.token0 {
+            self.lp_fees_token0 += amount;
+            self.lifetime_lp_fees_token0 += amount;
+        } else {
+            self.lp_fees_token1 += amount;
+            self.lifetime_lp_fees_token1 += amount;
+        }
+    }
+
+    pub fn apply_swap_result(&mut self, swap_result: &SwapResult) {
+        let tick_before = self.tick;
+        self.liquidity = swap_result.new_liquidity;
+        self.sqrt_price = swap_result.new_sqrt_price;
+        self.tick = self.sqrt_price_to_tick(self.sqrt_price);
+        self.record_tick_move(tick_before);
+        self.fee_growth_global_0 = swap_result.fee_growth_global_0;
+        self.fee_growth_global_1 = swap_result.fee_growth_global_1;
+        for (tick, outside) in &swap_result.fee_growth_outside {
+            self.fee_growth_outside.insert(*tick, *outside);
+        }
+        let ids = self.positions_in_reach(self.sqrt_price);
+        self.update_locked_amounts(&ids);
+    }
+
+    // Moves the locked amounts of the positions `ids` and the pool totals to the current
+    // price, activity and rewards time are left to refresh
+    fn update_locked_amounts(&mut self, ids: &BTreeSet<u128>) {
+        for id in ids {
+            let position = self.positions.get_mut(id).unwrap();
+            let before = rounded_locked(position);
+            let (token0_locked, token1_locked) = amounts_for_liquidity(
+                position.liquidity,
+                self.sqrt_price,
+                position.sqrt_lower_bound_price,
+                position.sqrt_upper_bound_price,
+            );
+            position.token0_locked = token0_locked;
+            position.token1_locked = token1_locked;
+            let after = rounded_locked(position);
+            self.token0_locked = self.token0_locked + after.0 - before.0;
+            self.token1_locked = self.token1_locked + after.1 - before.1;
+        }
+    }
+}
+
+// (token0, token1) locked in the position as the tokens it took or pays out
+fn rounded_locked(position: &Position) -> (u128, u128) {
+    (
+        position.token0_locked.round() as u128,
+        position.token1_locked.round() as u128,
+    )
+}
+
+pub fn decimals_factor(token0_decimals: u8, token1_decimals: u8) -> f64 {
+    10_f64.powi(token1_decimals as i32 - token0_decimals as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        pool::{DynamicFee, FeeTier, PoolSnapshot, ProtocolFeeMode, SwapDirection, SwapResult},
+        position::{
+            amounts_for_liquidity, price_to_tick, sqrt_price_to_tick, tick_to_price,
+            tick_to_sqrt_price, PositionSpec,
+        },
+        *,
+    };
+    use std::collections::BTreeMap;
+
+    // (token0, token1) fees a swap adds to every position of the pool by its fee growth inside
+    fn earned_fees(pool: &Pool, result: &SwapResult) -> BTreeMap<u128, (f64, f64)> {
+        pool.positions
+            .iter()
+            .map(|(id, position)| {
+                let (lower, upper) = (
+                    position.tick_lower_bound_price,
+                    position.tick_upper_bound_price,
+                );
+                let before = pool.fee_growth_inside(lower, upper);
+                let after = pool.fee_growth_inside_at(
+                    lower,
+                    upper,
+                    result.new_sqrt_price,
+                    (result.fee_growth_global_0, result.fee_growth_global_1),
+                    &result.fee_growth_outside,
+                );
+                let fees = (
+                    (after.0 - before.0) * position.liquidity,
+                    (after.1 - before.1) * position.liquidity,
+                );
+                (*id, fees)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pool_fee_tiers() {
+        for (bps, fee_tier) in [
+            (5, FeeTier::Low),
+            (30, FeeTier::Medium),
+            (100, FeeTier::High),
+        ] {
+            assert!(FeeTier::from_bps(bps) == fee_tier);
+            let pool = Pool::new(
+                "first".to_string(),
+                "second".to_string(),
+                1.0,
+                0,
+                fee_tier,
+                24,
+                24,
+            );
+            assert!(pool.rewards == bps);
+            assert!(pool.fee_tier == fee_tier);
+            assert!(pool.tick_spacing == fee_tier.tick_spacing());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad fee tier")]
+    fn pool_bad_fee_tier() {
+        FeeTier::from_bps(50000);
+    }
+
+    #[test]
+    fn pool_decimals() {
+        let token0 = "usdc".to_string();
+        let token1 = "wnear".to_string();
+        let pool = Pool::new(token0, token1, 0.5, 0, FeeTier::Low, 6, 24);
+        let raw_price: f64 = 0.5e18;
+        assert!(pool.sqrt_price == raw_price.sqrt());
+        assert!(pool.tick == sqrt_price_to_tick(raw_price.sqrt(), BASIS_POINT));
+        assert!(pool.tick == 407554);
+        assert!((pool.to_human_price(pool.sqrt_price * pool.sqrt_price) - 0.5).abs() < 1e-12);
+        let token0 = "near".to_string();
+        let token1 = "usdt".to_string();
+        let pool = Pool::new(token0, token1, 2.0, 0, FeeTier::Low, 24, 6);
+        assert!(pool.sqrt_price == 2e-18_f64.sqrt());
+        assert!(pool.tick == -407555);
+        assert!(pool.tick == sqrt_price_to_tick(2e-18_f64.sqrt(), BASIS_POINT));
+    }
+
+    #[test]
+    fn pool_spot_price() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        assert!((pool.get_spot_price() - 100.0).abs() < 1e-9);
+        assert!(pool.tick == price_to_tick(100.0, BASIS_POINT));
+        let pool = Pool::new(token0, token1, 0.01, 0, FeeTier::Low, 24, 24);
+        assert!((pool.get_spot_price() - 0.01).abs() < 1e-15);
+        assert!(pool.tick == price_to_tick(0.01, BASIS_POINT));
+        assert!(
+            tick_to_price(pool.tick, BASIS_POINT) <= 0.01
+                && 0.01 < tick_to_price(pool.tick + 1, BASIS_POINT)
+        );
+        let pool = Pool::new(
+            "usdc".to_string(),
+            "wnear".to_string(),
+            0.5,
+            0,
+            FeeTier::Low,
+            6,
+            24,
+        );
+        assert!((pool.get_spot_price() - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pool_token_ordering() {
+        let pool = Pool::new(
+            "usdc".to_string(),
+            "wnear".to_string(),
+            0.5,
+            0,
+            FeeTier::Low,
+            6,
+            24,
+        );
+        let reversed_pool = Pool::new(
+            "wnear".to_string(),
+            "usdc".to_string(),
+            2.0,
+            0,
+            FeeTier::Low,
+            24,
+            6,
+        );
+        assert!(pool.token0 == "usdc");
+        assert!(reversed_pool.token0 == pool.token0);
+        assert!(reversed_pool.token1 == pool.token1);
+        assert!(reversed_pool.token0_decimals == pool.token0_decimals);
+        assert!(reversed_pool.token1_decimals == pool.token1_decimals);
+        assert!(reversed_pool.sqrt_price == pool.sqrt_price);
+        assert!(reversed_pool.tick == pool.tick);
+    }
+
+    #[test]
+    fn pool_amounts_for_liquidity() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let pool = Pool::new(token0, token1, 100.0, 0, FeeTier::Medium, 24, 24);
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(500)),
+            None,
+            83.0,
+            118.0,
+            pool.sqrt_price,
+            pool.tick_spacing,
+        );
+        let (x, y) = pool.amounts_for_liquidity(position.liquidity, 83.0, 118.0);
+        assert!((x - position.token0_locked).abs() < 1e-9);
+        assert!((y - position.token1_locked).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pool_project_fee_income() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0, token1, 100.0, 0, FeeTier::High, 24, 24);
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(500)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let spec = PositionSpec {
+            token0_liquidity: Some(U128(500)),
+            token1_liquidity: None,
+            lower_bound_price: 81.0,
+            upper_bound_price: 121.0,
+        };
+        let new_position = Position::from_spec(
+            String::new(),
+            &spec,
+            pool.sqrt_price,
+            pool.tick_spacing,
+            pool.tick_base,
+        );
+        let share = new_position.liquidity / (pool.liquidity + new_position.liquidity);
+        assert!((share - 0.5).abs() < 0.0001);
+        let (fee0, fee1) = pool.project_fee_income(&new_position, 1000000.0);
+        assert!((fee1 - 1000000.0 / 2.0 * share * 0.01).abs() < 0.0001);
+        assert!((fee0 - fee1 / 100.0).abs() < 0.0001);
+        let (fee0_doubled, fee1_doubled) = pool.project_fee_income(&new_position, 2000000.0);
+        assert!((fee0_doubled - 2.0 * fee0).abs() < 0.0001);
+        assert!((fee1_doubled - 2.0 * fee1).abs() < 0.0001);
+    }
+
+    #[test]
+    fn pool_swap_exact_in_out() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(5000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let exact_in = pool.swap_exact_in(&token0, 100);
+        let exact_out = pool.swap_exact_out(&token1, exact_in.amount.round() as u128);
+        assert!((exact_out.amount - 100.0).abs() < 0.01);
+        assert!((exact_out.new_sqrt_price - exact_in.new_sqrt_price).abs() < 1e-4);
+        let exact_in = pool.swap_exact_in(&token1, 10000);
+        let exact_out = pool.swap_exact_out(&token0, exact_in.amount.round() as u128);
+        assert!((exact_out.amount - 10000.0).abs() / 10000.0 < 0.01);
+        assert!((exact_out.new_sqrt_price - exact_in.new_sqrt_price).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pool_swap_result_serialization() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::High, 24, 24);
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(5000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let swap_result = pool.get_swap_result(&token0, 1000, SwapDirection::Return);
+        assert!(swap_result.fee_growth_global_1 > 0.0);
+        let json = near_sdk::serde_json::to_string(&swap_result).unwrap();
+        let deserialized: SwapResult = near_sdk::serde_json::from_str(&json).unwrap();
+        // JSON does not round-trip the last bit of f64
+        let close = |a: f64, b: f64| (a - b).abs() <= a.abs() * 1e-15;
+        assert!(close(deserialized.amount, swap_result.amount));
+        assert!(close(deserialized.new_liquidity, swap_result.new_liquidity));
+        assert!(close(
+            deserialized.new_sqrt_price,
+            swap_result.new_sqrt_price
+        ));
+        assert!(deserialized.remaining == swap_result.remaining);
+        assert!(close(
+            deserialized.fee_growth_global_1,
+            swap_result.fee_growth_global_1
+        ));
+        assert!(deserialized.fee_growth_outside.len() == swap_result.fee_growth_outside.len());
+    }
+
+    #[test]
+    fn pool_tick_follows_sqrt_price() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(5000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let tick_before = pool.tick;
+        let swap_result = pool.get_swap_result(&token0, 1000, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        assert!(pool.tick < tick_before);
+        assert!(pool.tick == sqrt_price_to_tick(pool.sqrt_price, BASIS_POINT));
+    }
+
+    #[test]
+    fn pool_volume_buckets() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.record_volume(&token0, 10, 1000, 0);
+        pool.record_volume(&token1, 2000, 20, VOLUME_BUCKET_NS - 1);
+        pool.record_volume(&token0, 1, 100, VOLUME_BUCKET_NS);
+        pool.record_volume(&token0, 5, 500, 3 * VOLUME_BUCKET_NS + 7);
+        let buckets = pool.volume_buckets(VOLUME_BUCKET_NS, 10);
+        assert!(buckets.len() == 3);
+        assert!(buckets[0].start_ts == 0);
+        assert!(buckets[0].volume_token0 == 30 && buckets[0].volume_token1 == 3000);
+        assert!(buckets[1].start_ts == VOLUME_BUCKET_NS);
+        assert!(buckets[1].volume_token0 == 1 && buckets[1].volume_token1 == 100);
+        assert!(buckets[2].start_ts == 3 * VOLUME_BUCKET_NS);
+        let buckets = pool.volume_buckets(2 * VOLUME_BUCKET_NS, 10);
+        assert!(buckets.len() == 2);
+        assert!(buckets[0].volume_token0 == 31 && buckets[0].volume_token1 == 3100);
+        assert!(buckets[1].start_ts == 2 * VOLUME_BUCKET_NS);
+        let buckets = pool.volume_buckets(VOLUME_BUCKET_NS, 1);
+        assert!(buckets.len() == 1);
+        assert!(buckets[0].start_ts == 3 * VOLUME_BUCKET_NS);
+        for i in 0..MAX_VOLUME_BUCKETS as u64 {
+            pool.record_volume(&token0, 1, 100, (4 + i) * VOLUME_BUCKET_NS);
+        }
+        assert!(pool.volume_buckets.len() == MAX_VOLUME_BUCKETS);
+        assert!(pool.volume_buckets[0].start_ts == 4 * VOLUME_BUCKET_NS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bucket size must be a positive multiple of an hour")]
+    fn pool_volume_buckets_bad_size() {
+        let pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.volume_buckets(VOLUME_BUCKET_NS / 2, 10);
+    }
+
+    #[test]
+    fn pool_volume_buckets_saturated() {
+        let token0 = "first".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.record_volume(&token0, u128::MAX - 10, 5, 0);
+        pool.record_volume(&token0, 10, 5, 1);
+        assert!(!pool.volume_buckets[0].saturated);
+        assert!(pool.volume_buckets[0].volume_token0 == u128::MAX);
+        pool.record_volume(&token0, 1, 5, 2);
+        assert!(pool.volume_buckets[0].saturated);
+        assert!(pool.volume_buckets[0].volume_token0 == u128::MAX);
+        assert!(pool.volume_buckets[0].volume_token1 == 15);
+        // merging into larger buckets saturates as well
+        pool.record_volume(&token0, 1, u128::MAX, VOLUME_BUCKET_NS);
+        let buckets = pool.volume_buckets(VOLUME_BUCKET_NS, 10);
+        assert!(buckets[0].saturated && !buckets[1].saturated);
+        let buckets = pool.volume_buckets(2 * VOLUME_BUCKET_NS, 10);
+        assert!(buckets.len() == 1);
+        assert!(buckets[0].saturated);
+        assert!(buckets[0].volume_token0 == u128::MAX);
+        assert!(buckets[0].volume_token1 == u128::MAX);
+    }
+
+    #[test]
+    fn pool_total_locked() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0, token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(500)), None, 121.0, 144.0, 10.0);
+        pool.open_position(1, position);
+        let position = Position::new(String::new(), None, Some(U128(30000)), 49.0, 64.0, 10.0);
+        pool.open_position(2, position);
+        pool.refresh(0);
+        let (token0_locked, token1_locked) = pool.total_locked();
+        let positions_token0: f64 = pool
+            .positions
+            .values()
+            .map(|p| p.token0_locked.round())
+            .sum();
+        let positions_token1: f64 = pool
+            .positions
+            .values()
+            .map(|p| p.token1_locked.round())
+            .sum();
+        assert!(token0_locked == positions_token0);
+        assert!(token1_locked == positions_token1);
+        assert!(pool.positions.get(&1).unwrap().token1_locked == 0.0);
+        assert!(pool.positions.get(&2).unwrap().token0_locked == 0.0);
+        let tvl = pool.tvl_in_token1();
+        assert!((tvl - (token0_locked * 100.0 + token1_locked)).abs() < 1e-6);
+        // amounts follow the price without refreshing positions
+        let swap_result = pool.get_swap_result(&pool.token0.clone(), 100, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        let (token0_moved, token1_moved) = pool.total_locked();
+        assert!(token0_moved > token0_locked);
+        assert!(token1_moved < token1_locked);
+    }
+
+    #[test]
+    fn pool_locked_totals_follow_crossings() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0, token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(500)), None, 121.0, 144.0, 10.0);
+        pool.open_position(1, position);
+        let position = Position::new(String::new(), None, Some(U128(30000)), 64.0, 81.0, 10.0);
+        pool.open_position(2, position);
+        let position = Position::new(String::new(), None, Some(U128(3000)), 1.0, 4.0, 10.0);
+        pool.open_position(3, position);
+        pool.refresh(0);
+        let far = pool.positions[&3].clone();
+        for (token, amount) in [
+            (pool.token1.clone(), 120000),
+            (pool.token0.clone(), 2500),
+            (pool.token1.clone(), 60000),
+        ] {
+            let swap_result = pool.get_swap_result(&token, amount, SwapDirection::Expense);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(1);
+            let (token0_locked, token1_locked) = pool
+                .positions
+                .values()
+                .map(|position| {
+                    amounts_for_liquidity(
+                        position.liquidity,
+                        pool.sqrt_price,
+                        position.sqrt_lower_bound_price,
+                        position.sqrt_upper_bound_price,
+                    )
+                })
+                .fold((0, 0), |(token0, token1), (x, y)| {
+                    (token0 + x.round() as u128, token1 + y.round() as u128)
+                });
+            assert_eq!(pool.token0_locked, token0_locked);
+            assert_eq!(pool.token1_locked, token1_locked);
+            assert!(
+                (pool.liquidity - pool.calculate_liquidity_within_tick(pool.sqrt_price)).abs()
+                    < 1e-9
+            );
+        }
+        // the price never reached the bounds of the last position
+        assert!(pool.positions[&3] == far);
+        pool.close_position(3);
+        pool.close_position(2);
+        pool.close_position(1);
+        pool.close_position(0);
+        assert_eq!((pool.token0_locked, pool.token1_locked), (0, 0));
+        assert!(pool.tick_positions.is_empty() && pool.active_positions.is_empty());
+    }
+
+    #[test]
+    fn pool_merge_positions() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let owner = "owner".to_string();
+        let position = Position::new(owner.clone(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(owner.clone(), Some(U128(500)), None, 81.0, 121.0, 10.0);
+        pool.open_position(1, position);
+        pool.refresh(0);
+        let swap_result = pool.get_swap_result(&token0, 100, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.refresh(0);
+        let liquidity = pool.total_liquidity();
+        let locked = pool.total_locked();
+        let token0_locked = pool
+            .positions
+            .values()
+            .map(|p| p.token0_locked)
+            .sum::<f64>();
+        let token1_locked = pool
+            .positions
+            .values()
+            .map(|p| p.token1_locked)
+            .sum::<f64>();
+        let fees_owed_0 = pool.position_fees_owed(0);
+        let fees_owed_1 = pool.position_fees_owed(1);
+        pool.merge_positions(0, 1);
+        assert!(pool.positions.len() == 1);
+        assert!(pool.total_liquidity() == liquidity);
+        assert!((pool.total_locked().0 - locked.0).abs() / locked.0 < 1e-12);
+        assert!((pool.total_locked().1 - locked.1).abs() / locked.1 < 1e-12);
+        let fees_owed = pool.position_fees_owed(0);
+        assert!(fees_owed.0 == fees_owed_0.0 + fees_owed_1.0);
+        assert!(fees_owed.1 == fees_owed_0.1 + fees_owed_1.1);
+        let position = pool.positions.get(&0).unwrap();
+        assert!(position.owner_id == owner);
+        assert!(position.token0_locked == token0_locked);
+        assert!(position.token1_locked == token1_locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only different positions of one owner")]
+    fn pool_merge_positions_different_ranges() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 144.0, 10.0);
+        pool.open_position(1, position);
+        pool.merge_positions(0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only different positions of one owner")]
+    fn pool_merge_positions_different_owners() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new("a".to_string(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new("b".to_string(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(1, position);
+        pool.merge_positions(0, 1);
+    }
+
+    #[test]
+    fn pool_position_order() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let specs = [
+            (0, 1000, 81.0, 121.0),
+            (1, 333, 90.0, 110.0),
+            (2, 777, 95.0, 144.0),
+            (3, 123, 64.0, 101.0),
+        ];
+        let mut results = Vec::new();
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1]] {
+            let mut pool = Pool::new(
+                token0.clone(),
+                token1.clone(),
+                100.0,
+                0,
+                FeeTier::Low,
+                24,
+                24,
+            );
+            for i in order {
+                let (id, amount, lower, upper) = specs[i];
+                let position =
+                    Position::new(id.to_string(), Some(U128(amount)), None, lower, upper, 10.0);
+                pool.open_position(id, position);
+            }
+            pool.refresh(0);
+            let swap_result = pool.get_swap_result(&token0, 1000, SwapDirection::Return);
+            let fees = earned_fees(&pool, &swap_result);
+            results.push((
+                swap_result.amount,
+                swap_result.new_sqrt_price,
+                swap_result.new_liquidity,
+                fees,
+            ));
+        }
+        assert!(results[0] == results[1]);
+        assert!(results[0] == results[2]);
+    }
+
+    #[test]
+    fn pool_limit_order_filled() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Medium,
+            24,
+            24,
+        );
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(1000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        let order = Position::limit_order(
+            String::new(),
+            None,
+            Some(U128(10)),
+            90.0,
+            10.0,
+            10,
+            BASIS_POINT,
+        );
+        assert!(order.tick_upper_bound_price == order.tick_lower_bound_price + 10);
+        pool.open_position(1, order);
+        pool.refresh(0);
+        assert!(pool.check_filled_orders().is_empty());
+        let swap_result = pool.get_swap_result(&token0, 800, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.refresh(0);
+        assert!(pool.check_filled_orders() == vec![1]);
+        let filled = pool.positions.get(&1).unwrap().clone();
+        assert!(filled.token0_locked > 0.0);
+        assert!(filled.token1_locked == 0.0);
+        // the price comes back above the order, it stays filled
+        let swap_result = pool.get_swap_result(&token1, 30000, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.refresh(0);
+        assert!(pool.sqrt_price > filled.sqrt_upper_bound_price);
+        assert!(pool.check_filled_orders() == vec![1]);
+        let position = pool.positions.get(&1).unwrap();
+        assert!(position.token0_locked == filled.token0_locked);
+        assert!(position.token1_locked == 0.0);
+        assert!(pool.liquidity_net.len() == 2);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Limit order range must be entirely on one side of the current price"
+    )]
+    fn pool_limit_order_contains_price() {
+        let _order = Position::limit_order(
+            String::new(),
+            Some(U128(10)),
+            None,
+            100.0,
+            10.0,
+            10,
+            BASIS_POINT,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token does not belong to the pool")]
+    fn pool_swap_foreign_token() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.get_swap_result(&"third".to_string(), 10, SwapDirection::Return);
+    }
+
+    #[test]
+    fn pool_distribute_fee_zero_liquidity() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), None, Some(U128(1000)), 81.0, 95.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 105.0, 121.0, 10.0);
+        pool.open_position(1, position);
+        pool.refresh(0);
+        assert!(pool.calculate_liquidity_within_tick(pool.sqrt_price) == 0.0);
+        // nobody is active to earn the fee, so the fee growth is not touched
+        assert!(!pool.distribute_fee(&token0, 10));
+        assert!(pool.fee_growth_global_0 == 0.0 && pool.lp_fees_token0 == 0);
+    }
+
+    #[test]
+    fn pool_dynamic_fee() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(100000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.dynamic_fee = Some(DynamicFee {
+            floor_bps: 5,
+            cap_bps: 100,
+            cap_tick_move: 50,
+        });
+        assert!(pool.lp_fee_bps() == 5);
+        let swap = |pool: &mut Pool, token: &AccountId, amount: u128| {
+            let swap_result = pool.get_swap_result(token, amount, SwapDirection::Return);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(0);
+        };
+        let mut fee_bps = pool.lp_fee_bps();
+        for _ in 0..4 {
+            swap(&mut pool, &token1, 1000000);
+            swap(&mut pool, &token0, 10000);
+            assert!(pool.lp_fee_bps() >= fee_bps);
+            fee_bps = pool.lp_fee_bps();
+        }
+        assert!(fee_bps > 50);
+        let (_, fee) = pool.apply_fee(10000.0);
+        assert!(fee == fee_bps as f64);
+        // quiet swaps push the large moves out of the window
+        for _ in 0..DYNAMIC_FEE_WINDOW {
+            swap(&mut pool, &token1, 100);
+            assert!(pool.lp_fee_bps() <= fee_bps);
+            fee_bps = pool.lp_fee_bps();
+        }
+        assert!(fee_bps < 10);
+        pool.dynamic_fee = None;
+        assert!(pool.lp_fee_bps() == pool.rewards);
+    }
+
+    #[test]
+    fn pool_take_protocol_fee() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            5,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        assert!(pool.protocol_fee_part(10000.0) == 5.0);
+        assert!(pool.take_protocol_fee(&token0, 5) == 0);
+        assert!(pool.take_protocol_fee(&token1, 7) == 0);
+        assert!(pool.protocol_fees_token0 == 5 && pool.protocol_fees_token1 == 7);
+        pool.protocol_fee_mode = ProtocolFeeMode::Immediate;
+        assert!(pool.take_protocol_fee(&token0, 5) == 5);
+        assert!(pool.protocol_fees_token0 == 5);
+    }
+
+    // 300 separate narrow positions above the price, each holding 10 of token0
+    fn pool_with_many_bounds() -> Pool {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        for i in 0..300 {
+            let tick = 46060 + 4 * i;
+            let position = Position::new(
+                String::new(),
+                Some(U128(10)),
+                None,
+                tick_to_price(tick, BASIS_POINT),
+                tick_to_price(tick + 2, BASIS_POINT),
+                pool.sqrt_price,
+            );
+            pool.open_position(i as u128, position);
+        }
+        pool.refresh(0);
+        pool
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap crosses more ticks than one call can process")]
+    fn pool_swap_too_many_ticks() {
+        let pool = pool_with_many_bounds();
+        pool.get_swap_result(&"first".to_string(), 2500, SwapDirection::Expense);
+    }
+
+    #[test]
+    fn pool_partial_swap_too_many_ticks() {
+        let pool = pool_with_many_bounds();
+        // amounts within the first bounds are swapped as usual
+        let result = pool.get_swap_result(&"first".to_string(), 500, SwapDirection::Expense);
+        assert!(result.remaining == 0.0);
+        // the partial swap stops after MAX_SWAP_ITERATIONS steps and leaves the rest
+        let result =
+            pool.get_partial_swap_result(&"first".to_string(), 2500, SwapDirection::Expense);
+        assert!(result.remaining > 0.0 && result.remaining < 2500.0);
+        assert!(result.amount > 0.0);
+        assert!(result.new_sqrt_price > pool.sqrt_price);
+        let last_tick = 46060 + 4 * 299 + 2;
+        assert!(result.new_sqrt_price < tick_to_sqrt_price(last_tick, BASIS_POINT));
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap crosses more ticks than one call can process")]
+    fn pool_amount_to_reach_price_too_many_ticks() {
+        let pool = pool_with_many_bounds();
+        pool.amount_to_reach_price(tick_to_sqrt_price(47300, BASIS_POINT));
+    }
+
+    #[test]
+    fn pool_partial_swap() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        let liquidity = position.liquidity;
+        let sqrt_upper = position.sqrt_upper_bound_price;
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let amount = 1_000_000;
+        let result = pool.get_partial_swap_result(&token1, amount, SwapDirection::Return);
+        // all token0 is bought and the rest of token1 is left
+        assert!((result.amount - 1000.0).abs() < 1.0);
+        // the last step can end one tick above the position upper bound
+        assert!(sqrt_upper <= result.new_sqrt_price);
+        assert!(result.new_sqrt_price <= sqrt_upper * BASIS_POINT.sqrt() * (1.0 + 1e-12));
+        let swapped = liquidity * (result.new_sqrt_price - 10.0);
+        assert!((result.remaining - (amount as f64 - swapped)).abs() < 1.0);
+        // amounts the pool can fill are swapped the same way as without the flag
+        let partial = pool.get_partial_swap_result(&token1, 5000, SwapDirection::Return);
+        let full = pool.get_swap_result(&token1, 5000, SwapDirection::Return);
+        assert!(partial.amount == full.amount && partial.remaining == full.remaining);
+        let result = pool.get_partial_swap_result(&token0, 2000, SwapDirection::Expense);
+        assert!((result.remaining - 1000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn pool_swap_price_limit() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        let liquidity = position.liquidity;
+        pool.open_position(0, position);
+        pool.refresh(0);
+        // the price stops at the limit and the rest of the amount is left
+        let amount = 100_000;
+        let result = pool.get_swap_result_with_limit(&token1, amount, SwapDirection::Return, 10.2);
+        assert!(result.new_sqrt_price == 10.2);
+        let swapped = liquidity * (10.2 - 10.0);
+        assert!((result.remaining - (amount as f64 - swapped)).abs() < 1e-6);
+        assert!((result.amount - liquidity * (1.0 / 10.0 - 1.0 / 10.2)).abs() < 1e-6);
+        let collected = (result.fee_growth_global_0 - pool.fee_growth_global_0) * liquidity;
+        assert!((collected - pool.lp_fee(result.amount)).abs() < 1e-9);
+        // down to a limit that is exactly a tick price
+        let limit = tick_to_sqrt_price(45000, BASIS_POINT);
+        let result =
+            pool.get_swap_result_with_limit(&token1, 100_000, SwapDirection::Expense, limit);
+        assert!(result.new_sqrt_price == limit);
+        assert!((result.remaining - (100_000.0 - liquidity * (10.0 - limit))).abs() < 1e-6);
+        // amounts that don't reach the limit are swapped as without it
+        let limited = pool.get_swap_result_with_limit(&token1, 5000, SwapDirection::Return, 11.0);
+        let full = pool.get_swap_result(&token1, 5000, SwapDirection::Return);
+        assert!(limited.amount == full.amount && limited.new_sqrt_price == full.new_sqrt_price);
+        assert!(limited.remaining == full.remaining);
+        // the liquidity ends before the limit
+        let partial = pool.get_partial_swap_result(&token1, 1_000_000, SwapDirection::Return);
+        let limited =
+            pool.get_swap_result_with_limit(&token1, 1_000_000, SwapDirection::Return, 12.0);
+        assert!(limited.amount == partial.amount && limited.remaining == partial.remaining);
+        assert!(limited.new_sqrt_price < 12.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price limit must be beyond the current price")]
+    fn pool_swap_price_limit_wrong_side() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0, token1.clone(), 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.get_swap_result_with_limit(&token1, 1000, SwapDirection::Return, 9.9);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+    fn pool_swap_not_enough_liquidity() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0, token1.clone(), 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.get_swap_result(&token1, 1_000_000, SwapDirection::Return);
+    }
+
+    #[test]
+    fn pool_liquidity_histogram() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        assert!(pool.liquidity_histogram(10).is_empty());
+        for (id, lower, upper) in [(0, 64.0, 121.0), (1, 90.0, 110.0), (2, 81.0, 400.0)] {
+            let position = Position::new(String::new(), Some(U128(1000)), None, lower, upper, 10.0);
+            pool.open_position(id, position);
+        }
+        pool.refresh(0);
+        let histogram = pool.liquidity_histogram(7);
+        assert!(histogram.len() == 7);
+        assert!((histogram[0].lower_price - 64.0).abs() < 0.01);
+        assert!((histogram[6].upper_price - 400.0).abs() < 0.1);
+        let sum: f64 = histogram.iter().map(|bucket| bucket.liquidity).sum();
+        assert!((sum / pool.total_liquidity() - 1.0).abs() < 1e-9);
+        // the narrow position lies within the first bucket
+        assert!(histogram[0].liquidity > pool.positions[&1].liquidity);
+        assert!(histogram[6].liquidity < histogram[0].liquidity);
+    }
+
+    #[test]
+    fn pool_tick_base() {
+        let token0 = "first".to_string();
+        let new_pool = || {
+            Pool::new(
+                token0.clone(),
+                "second".to_string(),
+                100.0,
+                0,
+                FeeTier::Low,
+                24,
+                24,
+            )
+        };
+        let fine = new_pool();
+        let mut coarse = new_pool();
+        coarse.set_tick_base(1.0002);
+        // 1.0002 is about 1.0001 squared, so the same price lies at about half the tick
+        assert!(fine.tick == 46054);
+        assert!(coarse.tick == 23028);
+        assert!(coarse.sqrt_price == fine.sqrt_price);
+        assert!(
+            (coarse.tick_to_sqrt_price(500) / fine.tick_to_sqrt_price(1000) - 1.0).abs() < 1e-4
+        );
+        assert!(coarse.tick_to_sqrt_price(500) != fine.tick_to_sqrt_price(500));
+        let (lower, upper) = coarse.bounds_to_ticks(81.0, 121.0);
+        assert!(fine.bounds_to_ticks(81.0, 121.0) != (lower, upper));
+        assert!(coarse.tick_to_sqrt_price(lower) <= 9.0);
+        assert!(coarse.tick_to_sqrt_price(upper + coarse.tick_spacing) > 11.0);
+        let position = Position::new_with_ticks(
+            String::new(),
+            Some(U128(100000)),
+            None,
+            lower,
+            upper,
+            coarse.sqrt_price,
+            coarse.tick_base,
+        );
+        assert!(position.check_invariants().is_ok());
+        coarse.open_position(0, position);
+        coarse.refresh(0);
+        let swap_result = coarse.get_swap_result(&token0, 1000, SwapDirection::Return);
+        coarse.apply_swap_result(&swap_result);
+        coarse.refresh(0);
+        assert!(coarse.tick == coarse.sqrt_price_to_tick(coarse.sqrt_price));
+        assert!(coarse.tick < 23028);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position ticks use another tick base than the pool")]
+    fn pool_tick_base_mismatch() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.set_tick_base(1.0002);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+    }
+
+    #[test]
+    #[should_panic(expected = "Ticks must be multiples of the pool tick spacing")]
+    fn pool_open_position_not_aligned() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Medium,
+            24,
+            24,
+        );
+        // aligned to the spacing of the fee tier it opens fine
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(1000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        assert!(position.tick_lower_bound_price % 10 == 0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        assert!(position.tick_lower_bound_price % 10 != 0);
+        pool.open_position(1, position);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tick base can only change while the pool has no positions")]
+    fn pool_tick_base_with_positions() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.set_tick_base(1.0002);
+    }
+
+    #[test]
+    fn pool_liquidity_distribution() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        for (id, lower, upper) in [(0, 64.0, 121.0), (1, 90.0, 110.0), (2, 81.0, 400.0)] {
+            let position = Position::new(String::new(), Some(U128(1000)), None, lower, upper, 10.0);
+            pool.open_position(id, position);
+        }
+        pool.refresh(0);
+        let distribution = pool.liquidity_distribution(40000, 62000, 500);
+        assert!(distribution.len() == 45);
+        assert!(distribution[0] == (40000, 0.0));
+        assert!(distribution[44] == (62000, 0.0));
+        let (peak_tick, peak) = distribution.iter().cloned().fold((0, 0.0), |max, sample| {
+            if sample.1 > max.1 {
+                sample
+            } else {
+                max
             }
-        } else {
-            let new_tick = *tick - 1;
-            new_sqrt_price = tick_to_sqrt_price(new_tick);
-            amount_in = (1.0 / new_sqrt_price - 1.0 / *sqrt_price) * liquidity;
-            amount_out = (new_sqrt_price - *sqrt_price) * liquidity;
-            assert!(new_sqrt_price < *sqrt_price);
-            assert!(amount_out < 0.0);
-            if amount_in > *remaining {
-                let delta_reversed_sqrt_price = *remaining / liquidity;
-                new_sqrt_price = *sqrt_price / (delta_reversed_sqrt_price * *sqrt_price + 1.0);
-                amount_out = (new_sqrt_price - *sqrt_price) * liquidity;
-                assert!(new_sqrt_price < *sqrt_price);
-                assert!(amount_out < 0.0);
-                *remaining = 0.0;
+        });
+        // all three ranges overlap between 90 and 110
+        assert!(sqrt_price_to_tick(90.0f64.sqrt(), BASIS_POINT) <= peak_tick);
+        assert!(peak_tick <= sqrt_price_to_tick(110.0f64.sqrt(), BASIS_POINT));
+        assert!((peak - pool.total_liquidity()).abs() < 1e-6);
+        let (_, at_current) = pool.liquidity_distribution(pool.tick, pool.tick, 1)[0];
+        assert!((at_current - pool.liquidity).abs() < 1e-6);
+        // only the widest position is active above 121
+        let (_, above) = distribution[30];
+        assert!((above / pool.positions[&2].liquidity - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic(expected = "Tick range must be ordered and step positive")]
+    fn pool_liquidity_distribution_too_many_samples() {
+        let pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.liquidity_distribution(0, 1000, 1);
+    }
+
+    #[test]
+    fn pool_snapshot_restore() {
+        let token0 = "first".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        for (id, lower, upper) in [(0, 64.0, 121.0), (1, 90.0, 110.0), (2, 81.0, 400.0)] {
+            let position = Position::new(String::new(), Some(U128(1000)), None, lower, upper, 10.0);
+            pool.open_position(id, position);
+        }
+        pool.refresh(0);
+        let swap_result = pool.get_swap_result(&token0, 500, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.record_volume(&token0, 500, swap_result.amount as u128, 0);
+        pool.refresh(10);
+        let snapshot = pool.snapshot();
+        assert!(snapshot.positions.iter().map(|(id, _)| *id).eq(0..3));
+        let borsh = snapshot.try_to_vec().unwrap();
+        let restored = Pool::restore(PoolSnapshot::try_from_slice(&borsh).unwrap());
+        assert!(restored.snapshot() == snapshot);
+        // JSON parsing of floats may be off in the last bit
+        let json = near_sdk::serde_json::to_string(&snapshot).unwrap();
+        let restored = Pool::restore(near_sdk::serde_json::from_str(&json).unwrap());
+        assert!(restored.positions.len() == 3);
+        assert!((restored.liquidity / pool.liquidity - 1.0).abs() < 1e-12);
+        assert!(restored.volume_buckets == pool.volume_buckets);
+        // tick liquidity, the position order and the liquidity stored in the snapshot are
+        // not trusted
+        let mut snapshot = snapshot;
+        snapshot.pool.liquidity = 0.0;
+        snapshot.pool.liquidity_net.insert(0, 1000.0);
+        snapshot.pool.liquidity_upper.insert(0, 1000.0);
+        snapshot.pool.position_order.insert((0, 7));
+        let restored = Pool::restore(snapshot);
+        assert!(restored.liquidity == pool.liquidity);
+        assert!(restored.liquidity_net == pool.liquidity_net);
+        assert!(restored.liquidity_upper == pool.liquidity_upper);
+        assert!(restored.position_order == pool.position_order);
+    }
+
+    #[test]
+    fn pool_amount_to_reach_price() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        for (id, lower, upper) in [(0, 81.0, 121.0), (1, 95.0, 105.0), (2, 64.0, 144.0)] {
+            let position =
+                Position::new(String::new(), Some(U128(100000)), None, lower, upper, 10.0);
+            pool.open_position(id, position);
+        }
+        // out of reach, only lets swaps of token0 in be larger than the other positions hold
+        let position = Position::new(String::new(), Some(U128(1000000)), None, 400.0, 900.0, 10.0);
+        pool.open_position(3, position);
+        pool.refresh(0);
+        assert!(pool.amount_to_reach_price(pool.sqrt_price).1 == 0);
+        // across the upper bound of the narrow position and then back below its lower bound
+        for target in [110.0_f64.sqrt(), 90.0_f64.sqrt()] {
+            let (token_in, amount) = pool.amount_to_reach_price(target);
+            let expected_token = if target > pool.sqrt_price {
+                &token1
             } else {
-                *remaining -= amount_in.abs();
-                *tick -= 1;
-            }
+                &token0
+            };
+            assert!(&token_in == expected_token);
+            let swap_result = pool.get_swap_result(&token_in, amount, SwapDirection::Return);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(0);
+            assert!((pool.sqrt_price / target - 1.0).abs() < 1e-6);
         }
-        *sqrt_price = new_sqrt_price;
-        amount_out.abs()
     }
 
-    pub fn get_sqrt_price(&self) -> f64 {
-        self.sqrt_price
+    #[test]
+    fn pool_available_liquidity_one_side() {
+        let token0 = "first".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        assert!(!pool.check_available_liquidity(pool.sqrt_price, true));
+        assert!(!pool.check_available_liquidity(pool.sqrt_price, false));
+        // only token0 strictly above the current price
+        let position = Position::new(String::new(), Some(U128(1000)), None, 121.0, 144.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        assert!(pool.liquidity == 0.0);
+        assert!(pool.check_available_liquidity(pool.sqrt_price, true));
+        assert!(!pool.check_available_liquidity(pool.sqrt_price, false));
+        // token0 out moves the price up into the position
+        let swap_result = pool.get_swap_result(&token0, 10, SwapDirection::Expense);
+        assert!(swap_result.amount > 0.0);
+        // a position entirely below the price is reachable only going down
+        let position = Position::new(String::new(), None, Some(U128(1000)), 64.0, 81.0, 10.0);
+        pool.open_position(1, position);
+        pool.refresh(0);
+        assert!(pool.check_available_liquidity(pool.sqrt_price, false));
+        pool.close_position(1);
+        assert!(!pool.check_available_liquidity(pool.sqrt_price, false));
     }
 
-    pub fn refresh(&mut self, current_timestamp: u64) {
-        let mut liquidity = 0.0;
-        let mut token0_locked = 0.0;
-        let mut token1_locked = 0.0;
-        for (_, position) in &mut self.positions {
-            position.refresh(self.sqrt_price, current_timestamp);
-            if position.is_active(self.sqrt_price) {
-                liquidity += position.liquidity;
-            }
-            token0_locked += position.token0_locked;
-            token1_locked += position.token1_locked;
-        }
-        self.liquidity = liquidity;
-        self.token0_locked = token0_locked.round() as u128;
-        self.token1_locked = token1_locked.round() as u128;
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+    fn pool_swap_down_with_liquidity_only_above() {
+        let token0 = "first".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 121.0, 144.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        // token0 in moves the price down, away from the only position
+        pool.get_swap_result(&token0, 10, SwapDirection::Return);
     }
 
-    pub fn open_position(&mut self, id: u128, position: Position) {
-        self.positions.insert(id, position);
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+    fn pool_amount_to_reach_price_past_liquidity() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(100000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.amount_to_reach_price(12.0);
     }
 
-    pub fn close_position(&mut self, id: u128) {
-        let position = self.positions.get(&id).unwrap();
-        if position.is_active(self.sqrt_price) {
-            self.liquidity -= position.liquidity;
-            self.token0_locked -= position.token0_locked.round() as u128;
-            self.token1_locked -= position.token1_locked.round() as u128;
+    #[test]
+    fn pool_price_after_swap() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(300)), None, 95.0, 105.0, 10.0);
+        pool.open_position(1, position);
+        pool.refresh(0);
+        for (token, amount, direction) in [
+            (&token0, 10, SwapDirection::Return),
+            (&token0, 700, SwapDirection::Return),
+            (&token1, 5000, SwapDirection::Expense),
+            (&token0, 200, SwapDirection::Expense),
+        ] {
+            let swap_result = pool.get_swap_result(token, amount, direction);
+            assert!(pool.price_after_swap(token, amount, direction) == swap_result.new_sqrt_price);
         }
-        self.positions.remove(&id);
     }
 
-    pub fn apply_swap_result(&mut self, swap_result: &SwapResult) {
-        self.liquidity = swap_result.new_liquidity;
-        self.sqrt_price = swap_result.new_sqrt_price;
-        self.tick = sqrt_price_to_tick(self.sqrt_price);
-        for (id, collected_fee) in &swap_result.collected_fees {
-            let mut position = self.positions.get(&id).unwrap().clone();
-            if collected_fee.token == self.token0 {
-                position.fees_earned_token0 += collected_fee.amount.round() as u128;
+    #[test]
+    fn pool_apply_fee() {
+        let pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            20,
+            FeeTier::Medium,
+            24,
+            24,
+        );
+        let amount = 123456.0;
+        let (net, fee) = pool.apply_fee(amount);
+        assert!(net + fee == amount);
+        assert!(fee == amount * 50.0 / 10000.0);
+        assert!((pool.lp_fee(amount) - amount * 30.0 / 10000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pool_current_segment_k() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(10)), None, 99.0, 101.0, 10.0);
+        pool.open_position(1, position);
+        pool.refresh(0);
+        let k = pool.current_segment_k();
+        assert!(k > 0.0);
+        // stays within the narrow position range
+        let swap_result = pool.get_swap_result(&token0, 1, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.refresh(0);
+        assert!(pool.positions.get(&1).unwrap().is_active(pool.sqrt_price));
+        assert!((pool.current_segment_k() - k).abs() / k < 1e-12);
+        // leaves the narrow position range
+        let swap_result = pool.get_swap_result(&token0, 100, SwapDirection::Return);
+        pool.apply_swap_result(&swap_result);
+        pool.refresh(0);
+        assert!(!pool.positions.get(&1).unwrap().is_active(pool.sqrt_price));
+        assert!(pool.current_segment_k() < k);
+    }
+
+    #[test]
+    fn pool_fee_growth() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(
+            String::new(),
+            Some(U128(1_000_000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+        );
+        pool.open_position(0, position);
+        let position = Position::new(
+            String::new(),
+            Some(U128(3_000_000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+        );
+        pool.open_position(1, position);
+        let position = Position::new(
+            String::new(),
+            Some(U128(1_000_000)),
+            None,
+            144.0,
+            169.0,
+            10.0,
+        );
+        pool.open_position(2, position);
+        pool.refresh(0);
+        let mut lp_fees = (0.0, 0.0);
+        for (token, amount) in [
+            (&token0, 100_000),
+            (&token1, 15_000_000),
+            (&token0, 50_000),
+            (&token1, 3_000_000),
+        ] {
+            let swap_result = pool.get_swap_result(token, amount, SwapDirection::Return);
+            let lp_fee = pool.lp_fee(swap_result.amount);
+            if token == &token0 {
+                lp_fees.1 += lp_fee;
             } else {
-                position.fees_earned_token1 += collected_fee.amount.round() as u128;
+                lp_fees.0 += lp_fee;
             }
-            self.positions.insert(*id, position);
+            pool.apply_swap_result(&swap_result);
         }
+        let owed0 = pool.position_fees_owed(0);
+        let owed1 = pool.position_fees_owed(1);
+        assert!(owed0.0 > 0 && owed0.1 > 0);
+        // every position loses less than a unit to rounding down
+        assert!((owed1.0 as f64 / 3.0 - owed0.0 as f64).abs() <= 1.0);
+        assert!((owed1.1 as f64 / 3.0 - owed0.1 as f64).abs() <= 1.0);
+        assert!((lp_fees.0 - (owed0.0 + owed1.0) as f64).abs() <= 2.0);
+        assert!((lp_fees.1 - (owed0.1 + owed1.1) as f64).abs() <= 2.0);
+        assert!(pool.position_fees_owed(2) == (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+    fn pool_swap_exact_out_not_enough_liquidity() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(token0.clone(), token1, 100.0, 0, FeeTier::Low, 24, 24);
+        let position = Position::new(String::new(), Some(U128(5000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.swap_exact_out(&token0, 5001);
+    }
+
+    // Pool 5 ticks below MAX_TICK with a position of token1 up to it
+    fn max_tick_pool() -> Pool {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            tick_to_price(MAX_TICK - 5, BASIS_POINT),
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let position = Position::new(
+            String::new(),
+            None,
+            Some(U128(10u128.pow(30))),
+            tick_to_price(MAX_TICK - 100, BASIS_POINT),
+            tick_to_price(MAX_TICK, BASIS_POINT) * (1.0 + 1e-9),
+            pool.sqrt_price,
+        );
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool
+    }
+
+    #[test]
+    fn pool_swap_stops_at_max_tick() {
+        let pool = max_tick_pool();
+        let token1 = pool.token1.clone();
+        let result = pool.get_partial_swap_result(&token1, 10u128.pow(29), SwapDirection::Return);
+        assert!(result.new_sqrt_price == tick_to_sqrt_price(MAX_TICK, BASIS_POINT));
+        assert!(result.amount > 0.0);
+        assert!(result.remaining > 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+    fn pool_swap_past_max_tick() {
+        let pool = max_tick_pool();
+        let token1 = pool.token1.clone();
+        pool.get_swap_result(&token1, 10u128.pow(29), SwapDirection::Return);
+    }
+
+    #[test]
+    fn pool_swap_leaves_dust() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0,
+            token1.clone(),
+            tick_to_price(46000, BASIS_POINT),
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        let mut position = Position::new(
+            String::new(),
+            Some(U128(1000)),
+            None,
+            64.0,
+            121.0,
+            pool.sqrt_price,
+        );
+        // reaching the upper bound takes 1e-9 less than the amount sent
+        let upper_sqrt_price = tick_to_sqrt_price(position.tick_upper_bound_price, BASIS_POINT);
+        position.liquidity = (1000.0 - 1e-9) / (upper_sqrt_price - pool.sqrt_price);
+        position.refresh(pool.sqrt_price, 0);
+        pool.open_position(0, position);
+        pool.refresh(0);
+        let result = pool.get_swap_result(&token1, 1000, SwapDirection::Return);
+        assert!(result.remaining > 0.0);
+        assert!(result.remaining < SWAP_EPSILON);
+        assert!(result.new_sqrt_price == upper_sqrt_price);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::{pool::SwapDirection, position::sqrt_price_to_tick, *};
     #[test]
     fn pool_get_expense_x() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 7.0);
         assert!(position.liquidity == 376.34409850346157);
         pool.open_position(0, position);
         let result = pool.get_swap_result(&token0, 10, SwapDirection::Expense);
-        assert!(result.amount == 601.965597403578);
+        assert!(result.amount == 601.9655974035757);
         assert!(result.new_sqrt_price == 8.599508534336799);
         assert!(result.new_liquidity == 376.34409850346157);
     }
@@ -364,12 +3238,20 @@ mod test {
     fn pool_get_expense_y() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 7.0);
         assert!(position.liquidity == 376.34409850346157);
         pool.open_position(0, position);
         let result = pool.get_swap_result(&token1, 10, SwapDirection::Expense);
-        assert!(result.amount == 0.20485926166133644);
+        assert!(result.amount == 0.2048592616613365);
         assert!(result.new_sqrt_price == 6.973428572309849);
         assert!(result.new_liquidity == 376.34409850346157);
     }
@@ -378,7 +3260,15 @@ mod test {
     fn pool_get_return_x() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 10.0);
         assert!(position.liquidity.floor() == 555.0);
         pool.open_position(0, position);
@@ -393,7 +3283,15 @@ mod test {
     fn pool_get_return_y1() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 10.0);
         assert!(position.liquidity.floor() == 555.0);
         println!("before opening position");
@@ -411,13 +3309,21 @@ mod test {
     fn pool_get_expense_x_out_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 25.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            25.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(10)), None, 20.0, 26.0, 5.0);
         assert_eq!(position.liquidity, 2578.6245298379777);
         pool.open_position(0, position);
         pool.refresh(0);
         let result = pool.get_swap_result(&token0, 1, SwapDirection::Expense);
-        let new_tick = sqrt_price_to_tick(result.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(result.new_sqrt_price, BASIS_POINT);
         assert_ne!(new_tick, pool.tick);
     }
 
@@ -425,13 +3331,21 @@ mod test {
     fn pool_get_expense_y_out_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 25.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            25.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(10)), None, 20.0, 26.0, 5.0);
         assert_eq!(position.liquidity, 2578.6245298379777);
         pool.open_position(0, position);
         pool.refresh(0);
         let result = pool.get_swap_result(&token1, 1, SwapDirection::Expense);
-        let new_tick = sqrt_price_to_tick(result.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(result.new_sqrt_price, BASIS_POINT);
         assert_ne!(new_tick, pool.tick);
     }
 
@@ -439,13 +3353,21 @@ mod test {
     fn pool_get_expense_x_in_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(500)), None, 99.0, 101.0, 10.0);
         assert_eq!(position.liquidity, 1012698.5416276127);
         pool.open_position(0, position);
         pool.refresh(0);
         let result = pool.get_swap_result(&token0, 5, SwapDirection::Expense);
-        let new_tick = sqrt_price_to_tick(result.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(result.new_sqrt_price, BASIS_POINT);
         assert_eq!(new_tick, pool.tick);
     }
 
@@ -453,27 +3375,43 @@ mod test {
     fn pool_get_expense_y_in_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(500)), None, 99.0, 101.0, 10.0);
         assert_eq!(position.liquidity, 1012698.5416276127);
         pool.open_position(0, position);
         pool.refresh(0);
         let exp = pool.get_swap_result(&token1, 1, SwapDirection::Expense);
-        let new_tick = sqrt_price_to_tick(exp.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(exp.new_sqrt_price, BASIS_POINT);
         assert_eq!(new_tick, pool.tick);
     }
     #[test]
     fn pool_get_return_x_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 105.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            105.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(5000)), None, 90.0, 110.0, 10.0);
         pool.open_position(0, position);
         pool.refresh(0);
         println!("pool.token0_locked = {}", pool.token0_locked);
         println!("pool.token1_locked = {}", pool.token1_locked);
         let result = pool.get_swap_result(&token0, 1, SwapDirection::Return);
-        let new_tick = sqrt_price_to_tick(result.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(result.new_sqrt_price, BASIS_POINT);
         println!("new_tick = {new_tick}");
         println!("pool.tick = {}", pool.tick);
         assert!(new_tick == pool.tick);
@@ -483,12 +3421,20 @@ mod test {
     fn pool_get_return_y_within_one_tick() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(500)), None, 99.0, 101.0, 10.0);
         pool.open_position(0, position);
         pool.refresh(0);
         let exp = pool.get_swap_result(&token1, 1, SwapDirection::Return);
-        let new_tick = sqrt_price_to_tick(exp.new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(exp.new_sqrt_price, BASIS_POINT);
         assert!(new_tick == pool.tick);
     }
 
@@ -497,7 +3443,15 @@ mod test {
     fn pool_get_return_not_enough_liquidity() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         pool.get_swap_result(&token1, 1000, SwapDirection::Return);
     }
 
@@ -506,7 +3460,15 @@ mod test {
     fn pool_get_expense_not_enough_liquidity() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         pool.get_swap_result(&token1, 1000, SwapDirection::Expense);
     }
 
@@ -515,7 +3477,15 @@ mod test {
     fn pool_get_amount_many_positions_panic() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         for i in 1..100 {
             let position = Position::new(
                 String::new(),
@@ -538,7 +3508,15 @@ mod test {
     fn pool_get_amount_many_positions() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         for i in 1..100 {
             let position = Position::new(
                 String::new(),
@@ -557,11 +3535,95 @@ mod test {
         pool.get_swap_result(&token1, 1000000, SwapDirection::Expense);
     }
 
+    #[test]
+    fn pool_liquidity_net() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        for i in 1..100 {
+            let position = Position::new(
+                String::new(),
+                Some(U128(i * 100)),
+                None,
+                100.0 - i as f64,
+                100.0 + i as f64,
+                10.0,
+            );
+            pool.open_position(i, position);
+            pool.refresh(0);
+        }
+        let close = |a: f64, b: f64| ((a - b) / b).abs() < 1e-12;
+        // positions bounded exactly at the price are active, as in the scan over all positions
+        for i in 1..100 {
+            let position = pool.positions.get(&i).unwrap();
+            for tick in [
+                position.tick_lower_bound_price,
+                position.tick_upper_bound_price,
+            ] {
+                for sqrt_price in [
+                    tick_to_sqrt_price(tick, BASIS_POINT),
+                    (tick_to_sqrt_price(tick, BASIS_POINT)
+                        + tick_to_sqrt_price(tick + 1, BASIS_POINT))
+                        / 2.0,
+                ] {
+                    let liquidity = pool.calculate_liquidity_within_tick(sqrt_price);
+                    let expected: f64 = pool
+                        .positions
+                        .values()
+                        .filter(|p| p.is_active(sqrt_price))
+                        .map(|p| p.liquidity)
+                        .sum();
+                    assert!(liquidity == expected || close(liquidity, expected));
+                }
+            }
+        }
+        // results of the scan over all positions on every tick, up to the rounding of the
+        // steps between position bounds
+        let result = pool.get_swap_result(&token0, 495000, SwapDirection::Return);
+        assert!(close(result.amount, 41207532.01814036));
+        assert!(close(result.new_sqrt_price, 8.242711295226181));
+        let fees = earned_fees(&pool, &result);
+        assert!(close(fees[&1].1, 5.15157985309621));
+        // the whole LP fee is shared, including steps starting at a crossed position bound
+        let collected: f64 = fees.values().map(|fees| fees.1).sum();
+        assert!(close(collected, pool.lp_fee(result.amount)));
+        let result = pool.get_swap_result(&token1, 1000000, SwapDirection::Expense);
+        assert!(close(result.amount, 10037.27933619803));
+        assert!(close(result.new_sqrt_price, 9.962859122527853));
+        let result = pool.get_swap_result(&token1, 100000, SwapDirection::Return);
+        assert!(close(result.amount, 999.6287291187059));
+        assert!(close(result.new_sqrt_price, 10.003714087747214));
+        let result = pool.get_swap_result(&token0, 1000, SwapDirection::Expense);
+        assert!(close(result.amount, 100037.15467698584));
+        assert!(close(result.new_sqrt_price, 10.00371546770452));
+        for i in 1..100 {
+            pool.close_position(i);
+        }
+        assert!(pool.liquidity_net.is_empty());
+        assert!(pool.liquidity_upper.is_empty());
+    }
+
     #[test]
     fn pool_apply_swap_result_return() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 100.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 10.0);
         assert!(position.liquidity.floor() == 555.0);
         pool.open_position(0, position);
@@ -576,7 +3638,15 @@ mod test {
     fn pool_apply_swap_result_expense() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let position = Position::new(String::new(), Some(U128(50)), None, 1.0, 10000.0, 7.0);
         assert!(position.liquidity == 376.34409850346157);
         pool.open_position(0, position);
@@ -591,23 +3661,32 @@ mod test {
     fn pool_fees_expense() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 100, 100);
-        let position = Position::new(
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            100,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let position = Position::with_tick_spacing(
             "user.near".to_string(),
             Some(U128(50)),
             None,
             1.0,
             10000.0,
             7.0,
+            pool.tick_spacing,
         );
         pool.open_position(0, position);
         pool.refresh(0);
         let result = pool.get_swap_result(&token1, 10, SwapDirection::Expense);
         let amount = result.amount / 100.0;
-        let mut fee = 0.0;
-        for (_, collected_fee) in result.collected_fees {
-            fee += collected_fee.amount;
-        }
+        let fee: f64 = earned_fees(&pool, &result)
+            .values()
+            .map(|fees| fees.0)
+            .sum();
         assert!((amount - fee).abs() < 0.00001);
     }
 
@@ -615,49 +3694,273 @@ mod test {
     fn pool_fees_return() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 100, 100);
-        let position = Position::new(
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            100,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let position = Position::with_tick_spacing(
             "user.near".to_string(),
             Some(U128(50)),
             None,
             1.0,
             10000.0,
             7.0,
+            pool.tick_spacing,
         );
         pool.open_position(0, position);
         pool.refresh(0);
         let result = pool.get_swap_result(&token1, 10, SwapDirection::Return);
         let amount = result.amount / 100.0;
-        let mut fee = 0.0;
-        for (_, collected_fee) in result.collected_fees {
-            fee += collected_fee.amount;
-        }
+        let fee: f64 = earned_fees(&pool, &result)
+            .values()
+            .map(|fees| fees.0)
+            .sum();
         assert!((amount - fee).abs() < 0.00001);
     }
 
+    #[test]
+    fn pool_claim_rewards_reward_token() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.reward_token = Some("reward".to_string());
+        pool.reward_per_liquidity_second = 0.01;
+        pool.reward_budget = 1_000_000;
+        for (id, amount) in [(0, 1000), (1, 3000)] {
+            let position =
+                Position::new(String::new(), Some(U128(amount)), None, 81.0, 121.0, 10.0);
+            pool.open_position(id, position);
+        }
+        pool.refresh(0);
+        pool.refresh(10_000_000_000);
+        let (small, large) = (pool.positions[&0].liquidity, pool.positions[&1].liquidity);
+        let small_reward = pool.claim_rewards(0);
+        assert!(small_reward == (10.0 * small * 0.01).round() as u128);
+        // the small position is claimed later, accruing for twice as long
+        pool.refresh(20_000_000_000);
+        let large_reward = pool.claim_rewards(1);
+        assert!(large_reward == (20.0 * large * 0.01).round() as u128);
+        assert!((large_reward as f64 / small_reward as f64 - 6.0).abs() < 0.01);
+        assert!(pool.reward_budget == 1_000_000 - small_reward - large_reward);
+        // claims stop at the budget
+        pool.reward_budget = 5;
+        pool.refresh(30_000_000_000);
+        assert!(pool.claim_rewards(0) == 5);
+        assert!(pool.claim_rewards(1) == 0);
+        assert!(pool.positions[&1].rewards_for_time == 0);
+    }
+
+    #[test]
+    fn pool_collect_position_fees() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let owner = "user.near".to_string();
+        let position = Position::with_tick_spacing(
+            owner.clone(),
+            Some(U128(100000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        pool.refresh(0);
+        for (token, amount) in [(&token0, 1000), (&token1, 100000)] {
+            let swap_result = pool.get_swap_result(token, amount, SwapDirection::Return);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(0);
+        }
+        let position = pool.position_with_fees(0);
+        let fees = (position.fees_earned_token0, position.fees_earned_token1);
+        // 1% of the outputs of swapping 1000 token0 and 100000 token1, rounded down
+        assert!((9..=10).contains(&fees.0) && (999..=1000).contains(&fees.1));
+        assert!(pool.collect_position_fees(&owner, 0) == fees);
+        assert!(pool.positions[&0].liquidity == position.liquidity);
+        assert!(pool.collect_position_fees(&owner, 0) == (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the position owner can collect its fees")]
+    fn pool_collect_position_fees_not_owner() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let position = Position::with_tick_spacing(
+            "user.near".to_string(),
+            Some(U128(100000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        pool.collect_position_fees(&"other.near".to_string(), 0);
+    }
+
+    #[test]
+    fn pool_compound_position() {
+        let token0 = "first".to_string();
+        let token1 = "second".to_string();
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            100.0,
+            0,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(100000)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        pool.open_position(0, position);
+        pool.refresh(0);
+        for (token, amount) in [(&token0, 1000), (&token1, 100000)] {
+            let swap_result = pool.get_swap_result(token, amount, SwapDirection::Return);
+            pool.apply_swap_result(&swap_result);
+            pool.refresh(0);
+        }
+        let before = pool.position_with_fees(0);
+        let pool_liquidity = pool.liquidity;
+        assert!(before.fees_earned_token0 > 0 && before.fees_earned_token1 > 0);
+        pool.lp_fees_token0 = before.fees_earned_token0;
+        pool.lp_fees_token1 = before.fees_earned_token1;
+        let held0 = pool.token0_locked + pool.lp_fees_token0;
+        let held1 = pool.token1_locked + pool.lp_fees_token1;
+        pool.compound_position(0);
+        // the reinvested fees are not counted twice
+        assert!(pool.token0_locked + pool.lp_fees_token0 == held0);
+        assert!(pool.token1_locked + pool.lp_fees_token1 == held1);
+        assert!(pool.lp_fees_token0 >= pool.positions[&0].fees_earned_token0);
+        assert!(pool.lp_fees_token1 >= pool.positions[&0].fees_earned_token1);
+        let after = &pool.positions[&0];
+        assert!(after.liquidity > before.liquidity);
+        let added = after.liquidity - before.liquidity;
+        assert!((pool.liquidity - pool_liquidity - added).abs() < 1e-6);
+        // one of the fees is used up, the rest of the other one doesn't fit the ratio
+        assert!(after.fees_earned_token0 < before.fees_earned_token0);
+        assert!(after.fees_earned_token1 < before.fees_earned_token1);
+        assert!(after.fees_earned_token0 == 0 || after.fees_earned_token1 == 0);
+    }
+
+    #[test]
+    fn pool_compound_position_out_of_range() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::High,
+            24,
+            24,
+        );
+        let mut position = Position::with_tick_spacing(
+            String::new(),
+            Some(U128(100000)),
+            None,
+            121.0,
+            144.0,
+            10.0,
+            pool.tick_spacing,
+        );
+        position.fees_earned_token0 = 100;
+        position.fees_earned_token1 = 50;
+        let liquidity = position.liquidity;
+        pool.lp_fees_token0 = 100;
+        pool.lp_fees_token1 = 50;
+        pool.open_position(0, position);
+        pool.refresh(0);
+        pool.compound_position(0);
+        // below the range only token0 can be added
+        let position = &pool.positions[&0];
+        assert!(position.liquidity > liquidity);
+        assert!(position.fees_earned_token0 == 0);
+        assert!(position.fees_earned_token1 == 50);
+        assert!(pool.token0_locked == 100100);
+        assert!(pool.lp_fees_token0 == 0 && pool.lp_fees_token1 == 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position not found in this pool")]
+    fn pool_compound_missing_position() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::High,
+            24,
+            24,
+        );
+        pool.compound_position(0);
+    }
+
     #[test]
     fn pool_fees2() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 100, 100);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            100,
+            FeeTier::High,
+            24,
+            24,
+        );
         for i in 0..9 {
-            let position = Position::new(
+            let position = Position::with_tick_spacing(
                 "user.near".to_string(),
                 Some(U128(50)),
                 None,
                 1.0,
                 10000.0,
                 7.0,
+                pool.tick_spacing,
             );
             pool.open_position(i, position);
             pool.refresh(0);
         }
         let result = pool.get_swap_result(&token1, 10, SwapDirection::Expense);
         let amount = result.amount / 100.0;
-        let mut fee = 0.0;
-        for (_, collected_fee) in result.collected_fees {
-            fee += collected_fee.amount;
-        }
+        let fee: f64 = earned_fees(&pool, &result)
+            .values()
+            .map(|fees| fees.0)
+            .sum();
         assert!((amount - fee).abs() < 0.00001);
     }
 
@@ -665,7 +3968,15 @@ mod test {
     fn pool_add_liquidity1() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let mut position = Position::new(
             "user.near".to_string(),
             Some(U128(50)),
@@ -692,24 +4003,32 @@ mod test {
     fn pool_add_liquidity2() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let mut position = Position::new(
             "user.near".to_string(),
             None,
-            Some(U128(50)),
+            Some(U128(500)),
             1.0,
             10000.0,
             7.0,
         );
         position.refresh(7.0, 0);
         pool.refresh(0);
-        assert!(position.token1_locked.round() == 50.0);
+        assert!(position.token1_locked.round() == 500.0);
         let liquidity1 = position.liquidity;
         let token0_locked1 = position.token0_locked;
-        position.add_liquidity(None, Some(U128(100)), 7.0);
+        position.add_liquidity(None, Some(U128(1000)), 7.0);
         pool.refresh(0);
         position.refresh(7.0, 0);
-        assert!(position.token1_locked.round() == 150.0);
+        assert!(position.token1_locked.round() == 1500.0);
         let liquidity2 = position.liquidity;
         let token0_locked2 = position.token0_locked;
         assert!((liquidity2 / liquidity1) == (token0_locked2 / token0_locked1));
@@ -719,7 +4038,15 @@ mod test {
     fn pool_remove_liquidity1() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let mut position = Position::new(
             "user.near".to_string(),
             Some(U128(150)),
@@ -748,11 +4075,48 @@ mod test {
         assert!((liquidity1 / liquidity2) == (token1_locked1 / token1_locked2));
     }
 
+    #[test]
+    fn pool_claim_rewards() {
+        let mut pool = Pool::new(
+            "first".to_string(),
+            "second".to_string(),
+            100.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
+        pool.reward_tokens_per_second = 100;
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(0, position);
+        let position = Position::new(String::new(), Some(U128(3000)), None, 81.0, 121.0, 10.0);
+        pool.open_position(1, position);
+        let position = Position::new(String::new(), Some(U128(3000)), None, 121.0, 144.0, 10.0);
+        pool.open_position(2, position);
+        pool.refresh(0);
+        pool.refresh(10_000_000_000);
+        assert!(pool.positions.get(&0).unwrap().rewards_for_time == 10_000_000_000);
+        assert!(pool.claim_rewards(0) == 250);
+        assert!(pool.claim_rewards(1) == 750);
+        // out of range
+        assert!(pool.claim_rewards(2) == 0);
+        assert!(pool.positions.get(&0).unwrap().rewards_for_time == 0);
+        assert!(pool.claim_rewards(0) == 0);
+    }
+
     #[test]
     fn pool_remove_liquidity2() {
         let token0 = "first".to_string();
         let token1 = "second".to_string();
-        let mut pool = Pool::new(token0.clone(), token1.clone(), 49.0, 0, 0);
+        let mut pool = Pool::new(
+            token0.clone(),
+            token1.clone(),
+            49.0,
+            0,
+            FeeTier::Low,
+            24,
+            24,
+        );
         let mut position = Position::new(
             "user.near".to_string(),
             None,
@@ -1,13 +1,16 @@
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
     json_types::U128,
-    serde::Serialize,
+    serde::{Deserialize, Serialize},
     AccountId,
 };
 
-use crate::{errors::*, BASIS_POINT};
+use crate::{
+    errors::*, BASIS_POINT, MAX_CASHFLOWS, MAX_EXACT_F64_INTEGER, MAX_F64_AMOUNT, MAX_TICK,
+    MIN_POSITION_LIQUIDITY, MIN_TICK, SECONDS_IN_YEAR, TICK_EPSILON,
+};
 
-#[derive(Clone, Serialize, BorshDeserialize, BorshSerialize, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Position {
     pub owner_id: AccountId,
@@ -18,11 +21,93 @@ pub struct Position {
     pub tick_upper_bound_price: i32,
     pub sqrt_lower_bound_price: f64, // p_a
     pub sqrt_upper_bound_price: f64, // p_b
+    // Price ratio between neighbouring ticks of the pool the position was opened in
+    pub tick_base: f64,
     pub is_active: bool,
     pub last_update: u64,
     pub rewards_for_time: u64,
     pub fees_earned_token0: u128,
     pub fees_earned_token1: u128,
+    pub cashflows: Vec<CashFlow>,
+    pub fee_growth_inside_last_0: f64,
+    pub fee_growth_inside_last_1: f64,
+    pub entry_sqrt_price: f64,
+    pub is_limit_order: bool,
+    // Limit order crossed by the price, its liquidity is withdrawn from the pool
+    pub is_filled: bool,
+}
+
+// Tokens received by the LP (positive) or sent to the position (negative)
+#[derive(Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CashFlow {
+    pub timestamp: u64,
+    pub token0: i128,
+    pub token1: i128,
+}
+
+// Parameters of a position that is not opened yet, used by quote views
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionSpec {
+    pub token0_liquidity: Option<U128>,
+    pub token1_liquidity: Option<U128>,
+    pub lower_bound_price: f64,
+    pub upper_bound_price: f64,
+}
+
+// Outcome of a liquidity removal, `removed` of the requested token can differ from `requested`
+// since locked amounts are f64 and the tokens paid out are rounded down
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RemovedLiquidity {
+    pub requested: U128,
+    pub removed: U128,
+    pub token0_out: U128,
+    pub token1_out: U128,
+}
+
+// Invariant of a well-formed position that check_invariants found broken
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PositionInvariant {
+    NonFiniteBounds,
+    UnorderedBounds,
+    TicksMismatchBounds,
+    NegativeLiquidity,
+    NegativeLockedAmount,
+    LockedAmountExceedsLiquidity,
+}
+
+impl PositionInvariant {
+    pub fn message(&self) -> &'static str {
+        match self {
+            PositionInvariant::NonFiniteBounds => NON_FINITE_BOUNDS,
+            PositionInvariant::UnorderedBounds => BAD_SQRT_PRICE_BOUNDS,
+            PositionInvariant::TicksMismatchBounds => TICKS_MISMATCH_BOUNDS,
+            PositionInvariant::NegativeLiquidity => NEGATIVE_LIQUIDITY,
+            PositionInvariant::NegativeLockedAmount => NEGATIVE_LOCKED_AMOUNT,
+            PositionInvariant::LockedAmountExceedsLiquidity => LOCKED_AMOUNT_EXCEEDS_LIQUIDITY,
+        }
+    }
+}
+
+// Where the price is relative to a position's range, with the distance to it in whole ticks
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RangeStatus {
+    Below(u32),
+    InRange,
+    Above(u32),
+}
+
+// Values of a position too large to be stored in f64 without losing precision
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PrecisionWarning {
+    pub liquidity: bool,
+    pub token0_locked: bool,
+    pub token1_locked: bool,
 }
 
 impl Default for Position {
@@ -36,11 +121,18 @@ impl Default for Position {
             tick_upper_bound_price: 0,
             sqrt_lower_bound_price: 0.0,
             sqrt_upper_bound_price: 0.0,
+            tick_base: BASIS_POINT,
             is_active: false,
             last_update: 0,
             rewards_for_time: 0,
             fees_earned_token0: 0,
             fees_earned_token1: 0,
+            cashflows: Vec::new(),
+            fee_growth_inside_last_0: 0.0,
+            fee_growth_inside_last_1: 0.0,
+            entry_sqrt_price: 0.0,
+            is_limit_order: false,
+            is_filled: false,
         }
     }
 }
@@ -53,29 +145,136 @@ impl Position {
         lower_bound_price: f64,
         upper_bound_price: f64,
         sqrt_price: f64,
+    ) -> Position {
+        Position::with_tick_spacing(
+            owner_id,
+            token0_liquidity,
+            token1_liquidity,
+            lower_bound_price,
+            upper_bound_price,
+            sqrt_price,
+            1,
+        )
+    }
+
+    // Lower bound is rounded down and upper bound is rounded up to a multiple of `tick_spacing`
+    pub fn with_tick_spacing(
+        owner_id: AccountId,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        lower_bound_price: f64,
+        upper_bound_price: f64,
+        sqrt_price: f64,
+        tick_spacing: i32,
+    ) -> Position {
+        assert!(lower_bound_price < upper_bound_price);
+        let (tick_lower_bound_price, tick_upper_bound_price) = bounds_to_ticks(
+            lower_bound_price,
+            upper_bound_price,
+            tick_spacing,
+            BASIS_POINT,
+        );
+        Position::new_with_ticks(
+            owner_id,
+            token0_liquidity,
+            token1_liquidity,
+            tick_lower_bound_price,
+            tick_upper_bound_price,
+            sqrt_price,
+            BASIS_POINT,
+        )
+    }
+
+    // Position over the widest range of ticks aligned to `tick_spacing`
+    pub fn full_range(
+        owner_id: AccountId,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        sqrt_price: f64,
+        tick_spacing: i32,
+        tick_base: f64,
+    ) -> Position {
+        Position::new_with_ticks(
+            owner_id,
+            token0_liquidity,
+            token1_liquidity,
+            snap_tick_up(MIN_TICK, tick_spacing),
+            snap_tick_down(MAX_TICK, tick_spacing),
+            sqrt_price,
+            tick_base,
+        )
+    }
+
+    // Limit order selling token0 (token1) once the price rises above (falls below)
+    // the `tick_spacing` wide range starting at the tick of `price`
+    pub fn limit_order(
+        owner_id: AccountId,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        price: f64,
+        sqrt_price: f64,
+        tick_spacing: i32,
+        tick_base: f64,
+    ) -> Position {
+        let tick = sqrt_price_to_tick(price.sqrt(), tick_base);
+        let tick_lower_bound_price = snap_tick_down(tick, tick_spacing);
+        let mut position = Position::new_with_ticks(
+            owner_id,
+            token0_liquidity,
+            token1_liquidity,
+            tick_lower_bound_price,
+            tick_lower_bound_price + tick_spacing,
+            sqrt_price,
+            tick_base,
+        );
+        let contains_price = if token0_liquidity.is_some() {
+            position.sqrt_lower_bound_price < sqrt_price
+        } else {
+            sqrt_price < position.sqrt_upper_bound_price
+        };
+        assert!(!contains_price, "{}", LIMIT_ORDER_CONTAINS_PRICE);
+        position.is_limit_order = true;
+        position
+    }
+
+    pub fn new_with_ticks(
+        owner_id: AccountId,
+        token0_liquidity: Option<U128>,
+        token1_liquidity: Option<U128>,
+        tick_lower_bound_price: i32,
+        tick_upper_bound_price: i32,
+        sqrt_price: f64,
+        tick_base: f64,
     ) -> Position {
         assert!(
             token0_liquidity.is_some() ^ token1_liquidity.is_some(),
             "{}",
             INCORRECT_TOKEN
         );
-        assert!(lower_bound_price < upper_bound_price);
         let liquidity;
         let x;
         let y;
-        let tick_lower_bound_price = sqrt_price_to_tick(lower_bound_price.sqrt());
-        let tick_upper_bound_price = sqrt_price_to_tick(upper_bound_price.sqrt());
-        let sqrt_lower_bound_price = tick_to_sqrt_price(tick_lower_bound_price);
-        let sqrt_upper_bound_price = tick_to_sqrt_price(tick_upper_bound_price);
+        assert!(
+            MIN_TICK <= tick_lower_bound_price && tick_upper_bound_price <= MAX_TICK,
+            "{}",
+            TICK_OUT_OF_BOUNDS
+        );
+        assert!(
+            tick_lower_bound_price < tick_upper_bound_price,
+            "{}",
+            PRICE_RANGE_TOO_NARROW
+        );
+        let sqrt_lower_bound_price = tick_to_sqrt_price(tick_lower_bound_price, tick_base);
+        let sqrt_upper_bound_price = tick_to_sqrt_price(tick_upper_bound_price, tick_base);
         if token0_liquidity.is_some() {
             let token0_liquidity: u128 = token0_liquidity.unwrap().into();
-            x = token0_liquidity as f64;
+            x = amount_to_f64(token0_liquidity);
             assert!(x > 0.0, "token0 liqudity cannot be 0");
             assert!(
-                sqrt_price <= sqrt_upper_bound_price,
+                sqrt_price < sqrt_upper_bound_price,
                 "send token1 liquidity instead of token0"
             );
-            if sqrt_lower_bound_price < sqrt_price && sqrt_price < sqrt_upper_bound_price {
+            if sqrt_lower_bound_price < sqrt_price {
                 liquidity = get_liquidity_0(x, sqrt_price, sqrt_upper_bound_price);
             } else {
                 liquidity = get_liquidity_0(x, sqrt_lower_bound_price, sqrt_upper_bound_price);
@@ -88,13 +287,13 @@ impl Position {
             );
         } else {
             let token1_liquidity: u128 = token1_liquidity.unwrap().into();
-            y = token1_liquidity as f64;
+            y = amount_to_f64(token1_liquidity);
             assert!(y > 0.0, "token1 liqudity cannot be 0");
             assert!(
-                sqrt_price >= sqrt_lower_bound_price,
+                sqrt_price > sqrt_lower_bound_price,
                 "send token0 liquidity instead of token1"
             );
-            if sqrt_lower_bound_price <= sqrt_price && sqrt_price <= sqrt_upper_bound_price {
+            if sqrt_price < sqrt_upper_bound_price {
                 liquidity = get_liquidity_1(y, sqrt_lower_bound_price, sqrt_price);
             } else {
                 liquidity = get_liquidity_1(y, sqrt_lower_bound_price, sqrt_upper_bound_price);
@@ -106,6 +305,11 @@ impl Position {
                 sqrt_upper_bound_price,
             );
         }
+        assert!(
+            liquidity >= MIN_POSITION_LIQUIDITY,
+            "{}",
+            LIQUIDITY_TOO_SMALL
+        );
         Position {
             owner_id,
             liquidity,
@@ -115,36 +319,307 @@ impl Position {
             tick_upper_bound_price,
             sqrt_lower_bound_price,
             sqrt_upper_bound_price,
+            tick_base,
             is_active: true,
             last_update: 0,
             rewards_for_time: 0,
             fees_earned_token0: 0,
             fees_earned_token1: 0,
+            cashflows: Vec::new(),
+            fee_growth_inside_last_0: 0.0,
+            fee_growth_inside_last_1: 0.0,
+            entry_sqrt_price: sqrt_price,
+            is_limit_order: false,
+            is_filled: false,
         }
     }
 
+    pub fn from_spec(
+        owner_id: AccountId,
+        spec: &PositionSpec,
+        sqrt_price: f64,
+        tick_spacing: i32,
+        tick_base: f64,
+    ) -> Position {
+        let (tick_lower_bound_price, tick_upper_bound_price) = bounds_to_ticks(
+            spec.lower_bound_price,
+            spec.upper_bound_price,
+            tick_spacing,
+            tick_base,
+        );
+        Position::new_with_ticks(
+            owner_id,
+            spec.token0_liquidity,
+            spec.token1_liquidity,
+            tick_lower_bound_price,
+            tick_upper_bound_price,
+            sqrt_price,
+            tick_base,
+        )
+    }
+
     pub fn refresh(&mut self, sqrt_price: f64, current_timestamp: u64) {
-        self.token0_locked = calculate_x(
+        // NaN or inf stored here would leak into the pool liquidity for good
+        assert!(
+            sqrt_price.is_finite() && sqrt_price > 0.0,
+            "{}",
+            BAD_SQRT_PRICE
+        );
+        if self.is_filled {
+            self.last_update = current_timestamp;
+            return;
+        }
+        assert!(
+            0.0 < self.sqrt_lower_bound_price
+                && self.sqrt_lower_bound_price < self.sqrt_upper_bound_price
+                && self.sqrt_upper_bound_price.is_finite(),
+            "{}",
+            BAD_SQRT_PRICE_BOUNDS
+        );
+        let token0_locked = calculate_x(
             self.liquidity,
             sqrt_price,
             self.sqrt_lower_bound_price,
             self.sqrt_upper_bound_price,
         );
-        self.token1_locked = calculate_y(
+        let token1_locked = calculate_y(
             self.liquidity,
             sqrt_price,
             self.sqrt_lower_bound_price,
             self.sqrt_upper_bound_price,
         );
+        assert!(
+            token0_locked.is_finite() && token1_locked.is_finite(),
+            "{}",
+            LOCKED_AMOUNT_NOT_FINITE
+        );
+        self.token0_locked = token0_locked;
+        self.token1_locked = token1_locked;
         if self.is_active {
-            self.rewards_for_time = current_timestamp - self.last_update;
+            self.rewards_for_time += current_timestamp - self.last_update;
         }
         self.is_active = self.is_active(sqrt_price);
         self.last_update = current_timestamp;
     }
 
+    pub fn record_cashflow(&mut self, timestamp: u64, token0: i128, token1: i128) {
+        self.cashflows.push(CashFlow {
+            timestamp,
+            token0,
+            token1,
+        });
+        self.trim_cashflows();
+    }
+
+    // Drops the oldest cash flows after the opening one, which tells what the position was
+    // funded with, down to MAX_CASHFLOWS
+    pub fn trim_cashflows(&mut self) {
+        if self.cashflows.len() > MAX_CASHFLOWS {
+            self.cashflows
+                .drain(1..=self.cashflows.len() - MAX_CASHFLOWS);
+        }
+    }
+
+    // Net (token0, token1) flow for `from_ts <= timestamp <= to_ts`
+    pub fn cashflow(&self, from_ts: u64, to_ts: u64) -> (i128, i128) {
+        self.cashflows
+            .iter()
+            .filter(|cashflow| from_ts <= cashflow.timestamp && cashflow.timestamp <= to_ts)
+            .fold((0, 0), |(token0, token1), cashflow| {
+                (token0 + cashflow.token0, token1 + cashflow.token1)
+            })
+    }
+
+    pub fn precision_warning(&self) -> Option<PrecisionWarning> {
+        let warning = PrecisionWarning {
+            liquidity: self.liquidity > MAX_EXACT_F64_INTEGER,
+            token0_locked: self.token0_locked > MAX_EXACT_F64_INTEGER,
+            token1_locked: self.token1_locked > MAX_EXACT_F64_INTEGER,
+        };
+        if warning.liquidity || warning.token0_locked || warning.token1_locked {
+            Some(warning)
+        } else {
+            None
+        }
+    }
+
+    // Price at which a single-sided (limit order) position is fully converted into the other token
+    pub fn full_fill_price(&self) -> f64 {
+        let deposited_token0 = match self.cashflows.first() {
+            Some(cashflow) => cashflow.token0 < 0,
+            None => self.token1_locked == 0.0,
+        };
+        if deposited_token0 {
+            self.sqrt_upper_bound_price.powi(2)
+        } else {
+            self.sqrt_lower_bound_price.powi(2)
+        }
+    }
+
+    // Percentage by which the position value with earned fees is ahead (positive) or behind
+    // the tokens it would have held at `entry_sqrt_price` left untouched, both valued in token1
+    pub fn vs_hodl(&self, sqrt_price: f64, entry_sqrt_price: f64) -> f64 {
+        let fees_value = amount_to_f64(self.fees_earned_token0) * sqrt_price * sqrt_price
+            + amount_to_f64(self.fees_earned_token1);
+        self.value_vs_hodl(entry_sqrt_price, sqrt_price, fees_value)
+    }
+
+    // Same as vs_hodl without the earned fees, negative when the position lost value and 0
+    // without liquidity
+    pub fn impermanent_loss(&self, entry_sqrt_price: f64, current_sqrt_price: f64) -> f64 {
+        self.value_vs_hodl(entry_sqrt_price, current_sqrt_price, 0.0)
+    }
+
+    // Sqrt bounds of a range as wide as this one with its geometric midpoint at `sqrt_price`
+    pub fn recentered_bounds(&self, sqrt_price: f64) -> (f64, f64) {
+        let half_width = (self.sqrt_upper_bound_price / self.sqrt_lower_bound_price).sqrt();
+        (sqrt_price / half_width, sqrt_price * half_width)
+    }
+
+    // Tokens (token0, token1) to add to move the position's liquidity into the recentered
+    // range at `current_sqrt_price`, negative amounts are released
+    pub fn tokens_to_recenter(&self, current_sqrt_price: f64) -> (f64, f64) {
+        let (sa, sb) = self.recentered_bounds(current_sqrt_price);
+        let (x, y) = amounts_for_liquidity(self.liquidity, current_sqrt_price, sa, sb);
+        let (locked_x, locked_y) = amounts_for_liquidity(
+            self.liquidity,
+            current_sqrt_price,
+            self.sqrt_lower_bound_price,
+            self.sqrt_upper_bound_price,
+        );
+        (x - locked_x, y - locked_y)
+    }
+
+    // Worth of the tokens the position holds at `sqrt_price` and its earned fees, in token1
+    pub fn value_in_token1(&self, sqrt_price: f64) -> f64 {
+        let price = sqrt_price * sqrt_price;
+        // a filled limit order has no liquidity left, only the tokens it bought
+        let (x, y) = if self.is_filled {
+            (self.token0_locked, self.token1_locked)
+        } else {
+            amounts_for_liquidity(
+                self.liquidity,
+                sqrt_price,
+                self.sqrt_lower_bound_price,
+                self.sqrt_upper_bound_price,
+            )
+        };
+        let fees_value =
+            amount_to_f64(self.fees_earned_token0) * price + amount_to_f64(self.fees_earned_token1);
+        x * price + y + fees_value
+    }
+
+    // Earned fees annualized over `elapsed_seconds` as a percentage of the position value,
+    // both in token1 at `sqrt_price`
+    pub fn estimated_apr(&self, sqrt_price: f64, elapsed_seconds: u64) -> f64 {
+        let value = self.value_in_token1(sqrt_price);
+        if elapsed_seconds == 0 || value <= 0.0 {
+            return 0.0;
+        }
+        let fees_value = amount_to_f64(self.fees_earned_token0) * sqrt_price * sqrt_price
+            + amount_to_f64(self.fees_earned_token1);
+        fees_value / value * (SECONDS_IN_YEAR / elapsed_seconds as f64) * 100.0
+    }
+
+    fn value_vs_hodl(&self, entry_sqrt_price: f64, sqrt_price: f64, fees_value: f64) -> f64 {
+        let price = sqrt_price * sqrt_price;
+        let (x, y) = amounts_for_liquidity(
+            self.liquidity,
+            sqrt_price,
+            self.sqrt_lower_bound_price,
+            self.sqrt_upper_bound_price,
+        );
+        let (entry_x, entry_y) = amounts_for_liquidity(
+            self.liquidity,
+            entry_sqrt_price,
+            self.sqrt_lower_bound_price,
+            self.sqrt_upper_bound_price,
+        );
+        let value = x * price + y + fees_value;
+        let hodl_value = entry_x * price + entry_y;
+        // nothing held to compare with, e.g. all liquidity removed
+        if hodl_value <= 0.0 {
+            return 0.0;
+        }
+        (value / hodl_value - 1.0) * 100.0
+    }
+
+    // Below means the price is under the range, so the position holds only token0
+    pub fn range_status(&self, sqrt_price: f64) -> RangeStatus {
+        if sqrt_price < self.sqrt_lower_bound_price {
+            let tick = sqrt_price_to_tick(sqrt_price, self.tick_base);
+            RangeStatus::Below((self.tick_lower_bound_price - tick) as u32)
+        } else if sqrt_price > self.sqrt_upper_bound_price {
+            let tick = sqrt_price_to_tick(sqrt_price, self.tick_base);
+            RangeStatus::Above((tick - self.tick_upper_bound_price) as u32)
+        } else {
+            RangeStatus::InRange
+        }
+    }
+
     pub fn is_active(&self, sqrt_price: f64) -> bool {
-        self.sqrt_lower_bound_price <= sqrt_price && self.sqrt_upper_bound_price >= sqrt_price
+        !self.is_filled
+            && self.sqrt_lower_bound_price <= sqrt_price
+            && self.sqrt_upper_bound_price >= sqrt_price
+    }
+
+    pub fn assert_invariants(&self) {
+        if let Err(invariant) = self.check_invariants() {
+            panic!("{}", invariant.message());
+        }
+    }
+
+    // Locked amounts are checked against the most of each token the liquidity can hold,
+    // which it does at the opposite bound, since the current price isn't known here
+    pub fn check_invariants(&self) -> Result<(), PositionInvariant> {
+        let sa = self.sqrt_lower_bound_price;
+        let sb = self.sqrt_upper_bound_price;
+        if !sa.is_finite() || !sb.is_finite() {
+            return Err(PositionInvariant::NonFiniteBounds);
+        }
+        if !(0.0 < sa && sa < sb) {
+            return Err(PositionInvariant::UnorderedBounds);
+        }
+        let matches_tick = |tick: i32, sqrt_price: f64| {
+            (tick_to_sqrt_price(tick, self.tick_base) / sqrt_price - 1.0).abs() < 1e-12
+        };
+        if !matches_tick(self.tick_lower_bound_price, sa)
+            || !matches_tick(self.tick_upper_bound_price, sb)
+        {
+            return Err(PositionInvariant::TicksMismatchBounds);
+        }
+        if self.liquidity.is_nan() || self.liquidity < 0.0 {
+            return Err(PositionInvariant::NegativeLiquidity);
+        }
+        if self.token0_locked.is_nan()
+            || self.token0_locked < 0.0
+            || self.token1_locked.is_nan()
+            || self.token1_locked < 0.0
+        {
+            return Err(PositionInvariant::NegativeLockedAmount);
+        }
+        // a filled limit order holds the bought tokens without liquidity
+        if !self.is_filled {
+            let (max_x, _) = amounts_for_liquidity(self.liquidity, sa, sa, sb);
+            let (_, max_y) = amounts_for_liquidity(self.liquidity, sb, sa, sb);
+            let tolerance = 1.0 + 1e-9;
+            if self.token0_locked > max_x * tolerance || self.token1_locked > max_y * tolerance {
+                return Err(PositionInvariant::LockedAmountExceedsLiquidity);
+            }
+        }
+        Ok(())
+    }
+
+    // Limit order the price has moved fully through, token0 orders lie above the opening price
+    pub fn is_crossed(&self, sqrt_price: f64) -> bool {
+        if !self.is_limit_order {
+            return false;
+        }
+        if self.entry_sqrt_price <= self.sqrt_lower_bound_price {
+            sqrt_price >= self.sqrt_upper_bound_price
+        } else {
+            sqrt_price <= self.sqrt_lower_bound_price
+        }
     }
 
     pub fn add_liquidity(
@@ -160,13 +635,12 @@ impl Position {
         );
         if token0_liquidity.is_some() {
             let token0_liquidity: u128 = token0_liquidity.unwrap().into();
-            self.token0_locked += token0_liquidity as f64;
+            self.token0_locked += amount_to_f64(token0_liquidity);
             assert!(
-                sqrt_price <= self.sqrt_upper_bound_price,
+                sqrt_price < self.sqrt_upper_bound_price,
                 "send token1 liquidity instead of token0"
             );
-            if self.sqrt_lower_bound_price < sqrt_price && sqrt_price < self.sqrt_upper_bound_price
-            {
+            if self.sqrt_lower_bound_price < sqrt_price {
                 self.liquidity =
                     get_liquidity_0(self.token0_locked, sqrt_price, self.sqrt_upper_bound_price);
             } else {
@@ -184,14 +658,12 @@ impl Position {
             );
         } else {
             let token1_liquidity: u128 = token1_liquidity.unwrap().into();
-            self.token1_locked += token1_liquidity as f64;
+            self.token1_locked += amount_to_f64(token1_liquidity);
             assert!(
-                sqrt_price >= self.sqrt_lower_bound_price,
-                "send token0 liquidity instead of token0"
+                sqrt_price > self.sqrt_lower_bound_price,
+                "send token0 liquidity instead of token1"
             );
-            if self.sqrt_lower_bound_price <= sqrt_price
-                && sqrt_price <= self.sqrt_upper_bound_price
-            {
+            if sqrt_price < self.sqrt_upper_bound_price {
                 self.liquidity =
                     get_liquidity_1(self.token1_locked, self.sqrt_lower_bound_price, sqrt_price);
             } else {
@@ -208,6 +680,55 @@ impl Position {
                 self.sqrt_upper_bound_price,
             );
         }
+        assert!(
+            self.liquidity >= MIN_POSITION_LIQUIDITY,
+            "{}",
+            LIQUIDITY_TOO_SMALL
+        );
+    }
+
+    // Adds as much liquidity as both amounts allow and returns unused (token0, token1)
+    pub fn add_liquidity_both(
+        &mut self,
+        amount0: U128,
+        amount1: U128,
+        sqrt_price: f64,
+    ) -> (f64, f64) {
+        let amount0 = amount_to_f64(amount0.0);
+        let amount1 = amount_to_f64(amount1.0);
+        let liquidity = if sqrt_price <= self.sqrt_lower_bound_price {
+            get_liquidity_0(
+                amount0,
+                self.sqrt_lower_bound_price,
+                self.sqrt_upper_bound_price,
+            )
+        } else if sqrt_price < self.sqrt_upper_bound_price {
+            let liquidity0 = get_liquidity_0(amount0, sqrt_price, self.sqrt_upper_bound_price);
+            let liquidity1 = get_liquidity_1(amount1, self.sqrt_lower_bound_price, sqrt_price);
+            min(liquidity0, liquidity1)
+        } else {
+            get_liquidity_1(
+                amount1,
+                self.sqrt_lower_bound_price,
+                self.sqrt_upper_bound_price,
+            )
+        };
+        let used0 = calculate_x(
+            liquidity,
+            sqrt_price,
+            self.sqrt_lower_bound_price,
+            self.sqrt_upper_bound_price,
+        );
+        let used1 = calculate_y(
+            liquidity,
+            sqrt_price,
+            self.sqrt_lower_bound_price,
+            self.sqrt_upper_bound_price,
+        );
+        self.liquidity += liquidity;
+        self.token0_locked += used0;
+        self.token1_locked += used1;
+        (max(amount0 - used0, 0.0), max(amount1 - used1, 0.0))
     }
 
     pub fn remove_liquidity(
@@ -223,14 +744,13 @@ impl Position {
         );
         if token0_liquidity.is_some() {
             let token0_liquidity: u128 = token0_liquidity.unwrap().into();
-            self.token0_locked -= token0_liquidity as f64;
+            self.token0_locked -= amount_to_f64(token0_liquidity);
             assert!(self.token0_locked > 0.0);
             assert!(
-                sqrt_price <= self.sqrt_upper_bound_price,
+                sqrt_price < self.sqrt_upper_bound_price,
                 "send token1 liquidity instead of token0"
             );
-            if self.sqrt_lower_bound_price < sqrt_price && sqrt_price < self.sqrt_upper_bound_price
-            {
+            if self.sqrt_lower_bound_price < sqrt_price {
                 self.liquidity =
                     get_liquidity_0(self.token0_locked, sqrt_price, self.sqrt_upper_bound_price);
             } else {
@@ -248,15 +768,13 @@ impl Position {
             );
         } else {
             let token1_liquidity: u128 = token1_liquidity.unwrap().into();
-            self.token1_locked -= token1_liquidity as f64;
+            self.token1_locked -= amount_to_f64(token1_liquidity);
             assert!(self.token1_locked > 0.0);
             assert!(
-                sqrt_price >= self.sqrt_lower_bound_price,
-                "send token0 liquidity instead of token0"
+                sqrt_price > self.sqrt_lower_bound_price,
+                "send token0 liquidity instead of token1"
             );
-            if self.sqrt_lower_bound_price <= sqrt_price
-                && sqrt_price <= self.sqrt_upper_bound_price
-            {
+            if sqrt_price < self.sqrt_upper_bound_price {
                 self.liquidity =
                     get_liquidity_1(self.token1_locked, self.sqrt_lower_bound_price, sqrt_price);
             } else {
@@ -292,15 +810,24 @@ fn max(first: f64, second: f64) -> f64 {
     }
 }
 
+// Every u128 token amount goes through this before f64 math
+pub fn amount_to_f64(amount: u128) -> f64 {
+    assert!(amount <= MAX_F64_AMOUNT, "{}", AMOUNT_TOO_LARGE);
+    amount as f64
+}
+
 pub fn get_liquidity_0(x: f64, sa: f64, sb: f64) -> f64 {
+    assert!(0.0 < sa && sa < sb, "{}", BAD_SQRT_PRICE_BOUNDS);
     x * sa * sb / (sb - sa)
 }
 
 pub fn get_liquidity_1(y: f64, sa: f64, sb: f64) -> f64 {
+    assert!(0.0 < sa && sa < sb, "{}", BAD_SQRT_PRICE_BOUNDS);
     y / (sb - sa)
 }
 
 pub fn _get_liquidity(x: f64, y: f64, sp: f64, sa: f64, sb: f64) -> f64 {
+    assert!(0.0 < sa && sa < sb, "{}", BAD_SQRT_PRICE_BOUNDS);
     let liquidity;
     if sp <= sa {
         liquidity = get_liquidity_0(x, sa, sb);
@@ -329,6 +856,7 @@ pub fn _calculate_a1(l: f64, sp: f64, _sb: f64, _x: f64, y: f64) -> f64 {
 }
 
 pub fn _calculate_a2(sp: f64, sb: f64, x: f64, y: f64) -> f64 {
+    assert!(0.0 < sp && sp < sb, "{}", BAD_SQRT_PRICE_BOUNDS);
     let sa = y / (sb * x) + sp - y / (sp * x);
     sa.powf(2.0)
 }
@@ -338,16 +866,80 @@ pub fn _calculate_b1(l: f64, sp: f64, _sa: f64, x: f64, _y: f64) -> f64 {
 }
 
 pub fn _calculate_b2(sp: f64, sa: f64, x: f64, y: f64) -> f64 {
+    assert!(0.0 < sa && sa < sp, "{}", BAD_SQRT_PRICE_BOUNDS);
     let p = sp.powf(2.0);
     (sp * y / ((sa * sp - p) * x + y)).powf(2.0)
 }
 
-pub fn tick_to_sqrt_price(tick: i32) -> f64 {
-    BASIS_POINT.powf(tick as f64 / 2.0)
+// Neighbouring ticks differ in price by `tick_base` times, BASIS_POINT by default
+pub fn tick_to_sqrt_price(tick: i32, tick_base: f64) -> f64 {
+    tick_base.powf(tick as f64 / 2.0)
+}
+
+// Tick whose range contains `sqrt_price`, a sqrt price within TICK_EPSILON of a tick
+// boundary belongs to that tick so the log rounding can't move it to the tick below
+pub fn sqrt_price_to_tick(sqrt_price: f64, tick_base: f64) -> i32 {
+    let tick = 2.0 * sqrt_price.log(tick_base);
+    let nearest = tick.round();
+    let tick = if (tick - nearest).abs() < TICK_EPSILON {
+        nearest
+    } else {
+        tick.floor()
+    };
+    // also rejects NaN, which would be cast to tick 0
+    assert!(
+        (MIN_TICK as f64..=MAX_TICK as f64).contains(&tick),
+        "{}",
+        TICK_OUT_OF_BOUNDS
+    );
+    tick as i32
+}
+
+// Amounts of (x, y) backing liquidity `l` in range [sa, sb] at sqrt price `sp`
+pub fn amounts_for_liquidity(l: f64, sp: f64, sa: f64, sb: f64) -> (f64, f64) {
+    (calculate_x(l, sp, sa, sb), calculate_y(l, sp, sa, sb))
+}
+
+pub fn bounds_to_ticks(
+    lower_bound_price: f64,
+    upper_bound_price: f64,
+    tick_spacing: i32,
+    tick_base: f64,
+) -> (i32, i32) {
+    let tick_lower = sqrt_price_to_tick(lower_bound_price.sqrt(), tick_base);
+    let tick_upper = sqrt_price_to_tick(upper_bound_price.sqrt(), tick_base);
+    (
+        snap_tick_down(tick_lower, tick_spacing),
+        snap_tick_up(tick_upper, tick_spacing),
+    )
+}
+
+pub fn snap_tick_down(tick: i32, tick_spacing: i32) -> i32 {
+    tick.div_euclid(tick_spacing) * tick_spacing
+}
+
+pub fn snap_tick_up(tick: i32, tick_spacing: i32) -> i32 {
+    -(-tick).div_euclid(tick_spacing) * tick_spacing
+}
+
+pub fn tick_to_price(tick: i32, tick_base: f64) -> f64 {
+    let sqrt_price = tick_to_sqrt_price(tick, tick_base);
+    sqrt_price * sqrt_price
 }
 
-pub fn sqrt_price_to_tick(sqrt_price: f64) -> i32 {
-    (2.0 * sqrt_price.log(BASIS_POINT)).floor() as i32
+// Tick whose range contains `price`
+pub fn price_to_tick(price: f64, tick_base: f64) -> i32 {
+    sqrt_price_to_tick(price.sqrt(), tick_base)
+}
+
+pub fn price_to_nearest_tick(price: f64, tick_base: f64) -> i32 {
+    let tick = price.log(tick_base).round();
+    assert!(
+        (MIN_TICK as f64..=MAX_TICK as f64).contains(&tick),
+        "{}",
+        TICK_OUT_OF_BOUNDS
+    );
+    tick as i32
 }
 
 pub fn _calculate_sp(l: f64, x: f64, sb: f64) -> f64 {
@@ -407,36 +999,166 @@ mod test {
 
     #[test]
     fn get_liquidity_test() {
-        // At sp <= sa ((x * sa * sb)/(sb - sa))
-        let mut sp = 1500.02_f64.powf(0.5);
-        let mut sa = 3500.3_f64.powf(0.5);
-        let mut sb = 1500.3_f64.powf(0.5);
-        let mut x = 2_f64;
-        let mut y = 5096.06_f64;
-        let mut l = _get_liquidity(x, y, sp, sa, sb).floor();
-        assert_eq!(l, -225.0);
-        println!("sp <= sa, l = {}", l);
         // At sp < sb
         // min(get_liquidity_0, get_liquidity_1)
         // get_liquidity_0 = ((x * sa * sb)/(sb - sa))
         // get_liquidity_1 = y /(sb - sa)
-        sp = 3227.02_f64.powf(0.5);
-        sa = 3000.3_f64.powf(0.5);
-        sb = 3800.3_f64.powf(0.5);
-        x = 1_f64;
-        y = 5096.06_f64;
-        l = _get_liquidity(x, y, sp, sa, sb).floor();
+        let sp = 3227.02_f64.powf(0.5);
+        let sa = 3000.3_f64.powf(0.5);
+        let sb = 3800.3_f64.powf(0.5);
+        let x = 1_f64;
+        let y = 5096.06_f64;
+        let l = _get_liquidity(x, y, sp, sa, sb).floor();
         assert_eq!(l, 723.0);
         println!("sp < sb, l = {}", l);
-        // At sa < sp > sb
-        sp = 3600.02_f64.powf(0.5);
-        sa = 3500.3_f64.powf(0.5);
-        sb = 3000.3_f64.powf(0.5);
-        x = 1_f64;
-        y = 5096.06_f64;
-        l = _get_liquidity(x, y, sp, sa, sb).floor();
-        assert_eq!(l, -1162.0);
-        println!(" sa < sp > sb, l = {}", l);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price bounds must be positive")]
+    fn get_liquidity_misordered_bounds_below() {
+        // sp <= sa with sa > sb used to give l = -225
+        let sp = 1500.02_f64.powf(0.5);
+        let sa = 3500.3_f64.powf(0.5);
+        let sb = 1500.3_f64.powf(0.5);
+        _get_liquidity(2.0, 5096.06, sp, sa, sb);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price bounds must be positive")]
+    fn get_liquidity_misordered_bounds_above() {
+        // sa < sp > sb used to give l = -1162
+        let sp = 3600.02_f64.powf(0.5);
+        let sa = 3500.3_f64.powf(0.5);
+        let sb = 3000.3_f64.powf(0.5);
+        _get_liquidity(1.0, 5096.06, sp, sa, sb);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price bounds must be positive")]
+    fn get_liquidity_negative_bound() {
+        get_liquidity_1(100.0, -1.0, 10.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price bounds must be positive")]
+    fn get_liquidity_0_equal_bounds() {
+        get_liquidity_0(100.0, 10.0, 10.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price bounds must be positive")]
+    fn get_liquidity_1_equal_bounds() {
+        get_liquidity_1(100.0, 10.0, 10.0);
+    }
+
+    #[test]
+    fn position_range_status() {
+        let position = Position::new(String::new(), None, Some(U128(100)), 81.0, 121.0, 10.0);
+        assert!(position.range_status(10.0) == RangeStatus::InRange);
+        assert!(position.range_status(9.1) == RangeStatus::InRange);
+        assert!(position.range_status(10.9) == RangeStatus::InRange);
+        // log(81 / 64) / log(1.0001) = 2355.8
+        match position.range_status(8.0) {
+            RangeStatus::Below(ticks) => assert!(ticks == 2355 || ticks == 2356),
+            _ => panic!("price 64 is below the range"),
+        }
+        // log(144 / 121) / log(1.0001) = 1740.3
+        match position.range_status(12.0) {
+            RangeStatus::Above(ticks) => assert!(ticks == 1740 || ticks == 1741),
+            _ => panic!("price 144 is above the range"),
+        }
+        assert!(position.is_active(10.0));
+        assert!(!position.is_active(8.0) && !position.is_active(12.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Position liquidity is too small")]
+    fn open_position_dust() {
+        // 1 token1 spread over 81..121 is liquidity 0.5
+        Position::new(String::new(), None, Some(U128(1)), 81.0, 121.0, 10.0);
+    }
+
+    #[test]
+    fn open_position_min_liquidity() {
+        let position = Position::new(String::new(), None, Some(U128(100)), 81.0, 121.0, 10.0);
+        assert!(position.liquidity >= crate::MIN_POSITION_LIQUIDITY);
+        let mut position = Position::new(String::new(), None, Some(U128(100)), 81.0, 121.0, 121.0);
+        position.add_liquidity(None, Some(U128(1)), 121.0);
+        assert!((position.token1_locked - 101.0).abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price range collapses to a single tick")]
+    fn open_position_single_tick_range() {
+        Position::new(String::new(), Some(U128(50)), None, 100.0, 100.001, 9.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn open_position_upper_bound_beyond_max_tick() {
+        Position::new(String::new(), Some(U128(50)), None, 25.0, 1e300, 10.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn open_position_lower_bound_below_min_tick() {
+        Position::new(String::new(), None, Some(U128(50)), 1e-300, 121.0, 10.0);
+    }
+
+    #[test]
+    fn tick_bounds() {
+        // sqrt prices of the extreme ticks are about 2^-64 and 2^64
+        let max_sqrt_price = tick_to_sqrt_price(MAX_TICK, BASIS_POINT);
+        let min_sqrt_price = tick_to_sqrt_price(MIN_TICK, BASIS_POINT);
+        assert!((max_sqrt_price.log2() - 64.0).abs() < 1e-4);
+        assert!((min_sqrt_price.log2() + 64.0).abs() < 1e-4);
+        assert!(tick_to_sqrt_price(MAX_TICK + 1, BASIS_POINT) > 2_f64.powi(64));
+        assert!(sqrt_price_to_tick(max_sqrt_price, BASIS_POINT) == MAX_TICK);
+        assert!(sqrt_price_to_tick(min_sqrt_price, BASIS_POINT) == MIN_TICK);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn sqrt_price_to_tick_beyond_max_tick() {
+        sqrt_price_to_tick(2_f64.powi(65), BASIS_POINT);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn sqrt_price_to_tick_nan() {
+        sqrt_price_to_tick(f64::NAN, BASIS_POINT);
+    }
+
+    #[test]
+    fn price_to_nearest_tick_bounds() {
+        let max_price = tick_to_sqrt_price(MAX_TICK, BASIS_POINT).powi(2);
+        assert!(price_to_nearest_tick(max_price, BASIS_POINT) == MAX_TICK);
+        assert!(price_to_nearest_tick(1.0 / max_price, BASIS_POINT) == MIN_TICK);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn price_to_nearest_tick_beyond_max_tick() {
+        price_to_nearest_tick(2_f64.powi(130), BASIS_POINT);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price bound is outside of the [MIN_TICK, MAX_TICK] range")]
+    fn price_to_nearest_tick_zero() {
+        // ln(0) is -inf, it must not turn into a tick
+        price_to_nearest_tick(0.0, BASIS_POINT);
+    }
+
+    #[test]
+    fn open_position_full_range() {
+        let position =
+            Position::full_range(String::new(), Some(U128(1000)), None, 10.0, 60, BASIS_POINT);
+        assert!(position.tick_lower_bound_price == -887220);
+        assert!(position.tick_upper_bound_price == 887220);
+        // almost all of the liquidity is backed at the current price
+        let expected_liquidity = 1000.0 / (1.0 / 10.0 - 1.0 / position.sqrt_upper_bound_price);
+        assert!((position.liquidity / expected_liquidity - 1.0).abs() < 1e-9);
+        assert!((position.token1_locked / (position.liquidity * 10.0) - 1.0).abs() < 1e-9);
     }
 
     #[test]
@@ -559,6 +1281,34 @@ mod test {
         );
     }
 
+    #[test]
+    fn amounts_for_liquidity_match_positions() {
+        // in range, below range and above range
+        for (position, sqrt_price) in [
+            (
+                Position::new(String::new(), Some(U128(50)), None, 25.0, 121.0, 10.0),
+                10.0,
+            ),
+            (
+                Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0),
+                10.0,
+            ),
+            (
+                Position::new(String::new(), None, Some(U128(50)), 121.0, 144.0, 13.0),
+                13.0,
+            ),
+        ] {
+            let (x, y) = amounts_for_liquidity(
+                position.liquidity,
+                sqrt_price,
+                position.sqrt_lower_bound_price,
+                position.sqrt_upper_bound_price,
+            );
+            assert!((x - position.token0_locked).abs() < 1e-9);
+            assert!((y - position.token1_locked).abs() < 1e-9);
+        }
+    }
+
     #[test]
     fn open_position_less_than_lower_bound() {
         let position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
@@ -665,6 +1415,449 @@ mod test {
         let _position = Position::new(String::new(), None, Some(U128(1)), 121.0, 144.0, 10.0);
     }
 
+    #[test]
+    fn open_position_token0_lower_bound_at_spot() {
+        let reference = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        let sqrt_price = reference.sqrt_lower_bound_price;
+        let position = Position::new(
+            String::new(),
+            Some(U128(50)),
+            None,
+            121.0,
+            144.0,
+            sqrt_price,
+        );
+        assert!(position.liquidity == reference.liquidity);
+        assert!(position.token0_locked == 50.0);
+        assert!(position.token1_locked == 0.0);
+    }
+
+    #[should_panic(expected = "send token1 liquidity instead of token0")]
+    #[test]
+    fn open_position_token0_upper_bound_at_spot() {
+        let reference = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        let sqrt_price = reference.sqrt_upper_bound_price;
+        let _position = Position::new(
+            String::new(),
+            Some(U128(50)),
+            None,
+            121.0,
+            144.0,
+            sqrt_price,
+        );
+    }
+
+    #[should_panic(expected = "send token0 liquidity instead of token1")]
+    #[test]
+    fn open_position_token1_lower_bound_at_spot() {
+        let reference = Position::new(String::new(), None, Some(U128(50)), 121.0, 144.0, 13.0);
+        let sqrt_price = reference.sqrt_lower_bound_price;
+        let _position = Position::new(
+            String::new(),
+            None,
+            Some(U128(50)),
+            121.0,
+            144.0,
+            sqrt_price,
+        );
+    }
+
+    #[test]
+    fn open_position_token1_upper_bound_at_spot() {
+        let reference = Position::new(String::new(), None, Some(U128(50)), 121.0, 144.0, 13.0);
+        let sqrt_price = reference.sqrt_upper_bound_price;
+        let position = Position::new(
+            String::new(),
+            None,
+            Some(U128(50)),
+            121.0,
+            144.0,
+            sqrt_price,
+        );
+        assert!(position.liquidity == reference.liquidity);
+        assert!(position.token0_locked == 0.0);
+        assert!(position.token1_locked == 50.0);
+    }
+
+    #[test]
+    fn open_position_no_bound_at_spot() {
+        let position0 = Position::new(String::new(), Some(U128(50)), None, 100.0, 144.0, 11.0);
+        assert!(position0.token0_locked == 50.0);
+        assert!(position0.token1_locked > 0.0);
+        let position1 = Position::new(
+            String::new(),
+            None,
+            Some(U128(position0.token1_locked.round() as u128)),
+            100.0,
+            144.0,
+            11.0,
+        );
+        assert!(position1.token0_locked > 0.0);
+        assert!((position1.liquidity - position0.liquidity).abs() / position0.liquidity < 0.0001);
+    }
+
+    #[test]
+    fn open_position_snapped_to_tick_spacing() {
+        let position =
+            Position::with_tick_spacing(String::new(), Some(U128(50)), None, 30.0, 110.0, 10.0, 60);
+        assert!(sqrt_price_to_tick(30.0_f64.sqrt(), BASIS_POINT) == 34013);
+        assert!(sqrt_price_to_tick(110.0_f64.sqrt(), BASIS_POINT) == 47007);
+        assert!(position.tick_lower_bound_price == 33960);
+        assert!(position.tick_upper_bound_price == 47040);
+        assert!(position.sqrt_lower_bound_price == tick_to_sqrt_price(33960, BASIS_POINT));
+        assert!(position.sqrt_upper_bound_price == tick_to_sqrt_price(47040, BASIS_POINT));
+    }
+
+    #[test]
+    fn price_to_tick_round_trip() {
+        for price in [0.0001, 0.03, 0.5, 0.9999, 1.0, 1.5, 100.0, 12345.6, 1e9] {
+            let tick = price_to_tick(price, BASIS_POINT);
+            let tick_price = tick_to_price(tick, BASIS_POINT);
+            assert!(tick_price <= price * (1.0 + 1e-12));
+            assert!(price < tick_to_price(tick + 1, BASIS_POINT));
+        }
+    }
+
+    #[test]
+    fn snap_ticks() {
+        assert!(snap_tick_down(125, 60) == 120);
+        assert!(snap_tick_up(125, 60) == 180);
+        assert!(snap_tick_down(-125, 60) == -180);
+        assert!(snap_tick_up(-125, 60) == -120);
+        assert!(snap_tick_down(120, 60) == 120);
+        assert!(snap_tick_up(120, 60) == 120);
+    }
+
+    #[test]
+    fn add_liquidity_both_in_range() {
+        let mut position = Position::new(String::new(), Some(U128(50)), None, 25.0, 121.0, 10.0);
+        let ratio = position.token1_locked / position.token0_locked;
+        let liquidity = position.liquidity;
+        let (left0, left1) = position.add_liquidity_both(U128(100), U128(1000000), 10.0);
+        assert!(left0 < 1e-9);
+        assert!((left1 - (1000000.0 - 100.0 * ratio)).abs() < 1e-6);
+        assert!((position.token1_locked / position.token0_locked - ratio).abs() < 1e-9);
+        assert!((position.liquidity / liquidity - 3.0).abs() < 1e-9);
+        let (left0, left1) = position.add_liquidity_both(U128(1000000), U128(5501), 10.0);
+        assert!(left1 < 1e-9);
+        assert!((left0 - (1000000.0 - 5501.0 / ratio)).abs() < 1e-6);
+        assert!((position.token1_locked / position.token0_locked - ratio).abs() < 1e-9);
+    }
+
+    #[test]
+    fn add_liquidity_both_out_of_range() {
+        let mut position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        let liquidity = position.liquidity;
+        let (left0, left1) = position.add_liquidity_both(U128(50), U128(1000), 10.0);
+        assert!(left0 < 1e-9);
+        assert!(left1 == 1000.0);
+        assert!((position.liquidity / liquidity - 2.0).abs() < 1e-9);
+        assert!(position.token1_locked == 0.0);
+    }
+
+    #[test]
+    fn impermanent_loss_price_doubling() {
+        // wide enough to behave as a full range position
+        let position = Position::new(String::new(), Some(U128(1000)), None, 1e-8, 1e12, 10.0);
+        let sqrt_price = 200.0_f64.sqrt();
+        let textbook = (2.0 * 2.0_f64.sqrt() / 3.0 - 1.0) * 100.0;
+        assert!((position.impermanent_loss(10.0, sqrt_price) - textbook).abs() < 1e-3);
+        // halving the price is the same loss
+        let sqrt_price = 50.0_f64.sqrt();
+        assert!((position.impermanent_loss(10.0, sqrt_price) - textbook).abs() < 1e-3);
+        assert!(position.impermanent_loss(10.0, 10.0) == 0.0);
+    }
+
+    #[test]
+    fn impermanent_loss_no_liquidity() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.liquidity = 0.0;
+        let loss = position.impermanent_loss(10.0, 12.0);
+        assert!(loss == 0.0);
+    }
+
+    #[test]
+    fn impermanent_loss_out_of_range() {
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        let sa = position.sqrt_lower_bound_price;
+        let sb = position.sqrt_upper_bound_price;
+        let l = position.liquidity;
+        let entry_x = l * (sb - 10.0) / (10.0 * sb);
+        let entry_y = l * (10.0 - sa);
+        // above the range only token1 is left
+        let expected = (l * (sb - sa) / (entry_x * 144.0 + entry_y) - 1.0) * 100.0;
+        assert!((position.impermanent_loss(10.0, 12.0) - expected).abs() < 1e-9);
+        // below the range only token0 is left
+        let x = l * (sb - sa) / (sa * sb);
+        let expected = (x * 64.0 / (entry_x * 64.0 + entry_y) - 1.0) * 100.0;
+        assert!((position.impermanent_loss(10.0, 8.0) - expected).abs() < 1e-9);
+        assert!(position.impermanent_loss(10.0, 8.0) < 0.0);
+    }
+
+    #[test]
+    fn amount_to_f64_at_limit() {
+        assert!(amount_to_f64(1 << 53) == 9007199254740992.0);
+        assert!(amount_to_f64(MAX_F64_AMOUNT) == 2f64.powi(100));
+        let position = Position::new(
+            String::new(),
+            Some(U128(MAX_F64_AMOUNT)),
+            None,
+            81.0,
+            121.0,
+            10.0,
+        );
+        assert!(position.token0_locked == 2f64.powi(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token amount is too large to be handled precisely")]
+    fn amount_to_f64_above_limit() {
+        amount_to_f64(MAX_F64_AMOUNT + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token amount is too large to be handled precisely")]
+    fn open_position_amount_too_large() {
+        Position::new(
+            String::new(),
+            None,
+            Some(U128(u128::MAX)),
+            81.0,
+            121.0,
+            10.0,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price must be a finite positive number")]
+    fn refresh_zero_sqrt_price() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.refresh(0.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sqrt price must be a finite positive number")]
+    fn refresh_nan_sqrt_price() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.refresh(f64::NAN, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Position locked amounts are not finite")]
+    fn refresh_nan_liquidity() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.liquidity = f64::NAN;
+        position.refresh(10.0, 0);
+    }
+
+    #[test]
+    fn new_with_ticks_matches_prices() {
+        for (tick_lower, tick_upper) in [(43944, 47958), (-500, 500), (-46054, -40000)] {
+            let sqrt_price = tick_to_sqrt_price((tick_lower + tick_upper) / 2, BASIS_POINT);
+            let by_ticks = Position::new_with_ticks(
+                String::new(),
+                Some(U128(1000)),
+                None,
+                tick_lower,
+                tick_upper,
+                sqrt_price,
+                BASIS_POINT,
+            );
+            // the exact tick price can round into the tick below, so a price inside the tick is used
+            let by_prices = Position::new(
+                String::new(),
+                Some(U128(1000)),
+                None,
+                tick_to_price(tick_lower, BASIS_POINT) * 1.00005,
+                tick_to_price(tick_upper, BASIS_POINT) * 1.00005,
+                sqrt_price,
+            );
+            assert!(by_ticks.tick_lower_bound_price == tick_lower);
+            assert!(by_ticks.tick_upper_bound_price == tick_upper);
+            assert!(by_ticks.tick_lower_bound_price == by_prices.tick_lower_bound_price);
+            assert!(by_ticks.tick_upper_bound_price == by_prices.tick_upper_bound_price);
+            assert!(by_ticks.liquidity == by_prices.liquidity);
+            assert!(by_ticks.token1_locked == by_prices.token1_locked);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Price range collapses to a single tick")]
+    fn new_with_ticks_bad_order() {
+        Position::new_with_ticks(
+            String::new(),
+            Some(U128(1000)),
+            None,
+            500,
+            500,
+            1.0,
+            BASIS_POINT,
+        );
+    }
+
+    #[test]
+    fn position_invariants() {
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        assert!(position.check_invariants() == Ok(()));
+        position.assert_invariants();
+        for invariant in [
+            PositionInvariant::NonFiniteBounds,
+            PositionInvariant::UnorderedBounds,
+            PositionInvariant::TicksMismatchBounds,
+            PositionInvariant::NegativeLiquidity,
+            PositionInvariant::NegativeLockedAmount,
+            PositionInvariant::LockedAmountExceedsLiquidity,
+        ] {
+            let mut corrupted = position.clone();
+            match invariant {
+                PositionInvariant::NonFiniteBounds => {
+                    corrupted.sqrt_upper_bound_price = f64::INFINITY
+                }
+                PositionInvariant::UnorderedBounds => corrupted.sqrt_lower_bound_price = 12.0,
+                PositionInvariant::TicksMismatchBounds => corrupted.tick_lower_bound_price += 1,
+                PositionInvariant::NegativeLiquidity => corrupted.liquidity = f64::NAN,
+                PositionInvariant::NegativeLockedAmount => corrupted.token1_locked = -1.0,
+                PositionInvariant::LockedAmountExceedsLiquidity => corrupted.token0_locked *= 3.0,
+            }
+            assert!(corrupted.check_invariants() == Err(invariant));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Position liquidity is negative")]
+    fn position_assert_invariants() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.liquidity = -1.0;
+        position.assert_invariants();
+    }
+
+    #[test]
+    fn value_in_token1() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.fees_earned_token0 = 3;
+        position.fees_earned_token1 = 200;
+        let l = position.liquidity;
+        let sa = position.sqrt_lower_bound_price;
+        let sb = position.sqrt_upper_bound_price;
+        // in range
+        let sp = 10.5;
+        let x = l * (sb - sp) / (sp * sb);
+        let y = l * (sp - sa);
+        let expected = x * sp * sp + y + 3.0 * sp * sp + 200.0;
+        assert!((position.value_in_token1(sp) - expected).abs() < 1e-6);
+        // below the range only token0 is held
+        let sp = 8.0;
+        let x = l * (sb - sa) / (sa * sb);
+        let expected = x * sp * sp + 3.0 * sp * sp + 200.0;
+        assert!((position.value_in_token1(sp) - expected).abs() < 1e-6);
+        // above the range only token1 is held
+        let sp = 12.0;
+        let y = l * (sb - sa);
+        let expected = y + 3.0 * sp * sp + 200.0;
+        assert!((position.value_in_token1(sp) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn estimated_apr() {
+        let mut position = Position::new(String::new(), None, Some(U128(10000)), 81.0, 121.0, 10.0);
+        // a week of fees worth 1% of the position value
+        let value = position.value_in_token1(10.0);
+        position.fees_earned_token1 = (value / 99.0).round() as u128;
+        let apr = position.estimated_apr(10.0, 7 * 24 * 60 * 60);
+        assert!(apr > 50.0 && apr < 53.0);
+        // a new position without elapsed time has no estimate
+        assert!(position.estimated_apr(10.0, 0) == 0.0);
+    }
+
+    #[test]
+    fn tokens_to_recenter_out_of_range() {
+        let position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        let width = position.sqrt_upper_bound_price / position.sqrt_lower_bound_price;
+        // price went above the range, the position holds only token1
+        let sqrt_price = 12.0;
+        let (sa, sb) = position.recentered_bounds(sqrt_price);
+        assert!(((sa * sb).sqrt() - sqrt_price).abs() < 1e-12);
+        assert!((sb / sa - width).abs() < 1e-12);
+        let (token0, token1) = position.tokens_to_recenter(sqrt_price);
+        assert!(token0 > 0.0);
+        assert!(token1 < 0.0);
+        let locked_y = calculate_y(
+            position.liquidity,
+            sqrt_price,
+            position.sqrt_lower_bound_price,
+            position.sqrt_upper_bound_price,
+        );
+        assert!(locked_y > 0.0);
+        // both recentered amounts correspond to the same liquidity around the current price
+        let l0 = get_liquidity_0(token0, sqrt_price, sb);
+        let l1 = get_liquidity_1(locked_y + token1, sa, sqrt_price);
+        assert!((l0 - position.liquidity).abs() / position.liquidity < 1e-9);
+        assert!((l1 - position.liquidity).abs() / position.liquidity < 1e-9);
+        let midpoint = (position.sqrt_lower_bound_price * position.sqrt_upper_bound_price).sqrt();
+        let (token0, token1) = position.tokens_to_recenter(midpoint);
+        assert!(token0.abs() < 1e-6 && token1.abs() < 1e-6);
+    }
+
+    #[test]
+    fn vs_hodl_out_of_range() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        assert!(position.entry_sqrt_price == 10.0);
+        assert!(position.vs_hodl(10.0, 10.0) == 0.0);
+        let sa = position.sqrt_lower_bound_price;
+        let sb = position.sqrt_upper_bound_price;
+        let l = position.liquidity;
+        // all token0 is sold when the price goes above the range
+        let value = l * (sb - sa);
+        let hodl_value = l * (sb - 10.0) / (10.0 * sb) * 144.0 + l * (10.0 - sa);
+        let impermanent_loss = (value / hodl_value - 1.0) * 100.0;
+        assert!(impermanent_loss < 0.0);
+        assert!((position.vs_hodl(12.0, 10.0) - impermanent_loss).abs() < 1e-9);
+        position.fees_earned_token1 = 50;
+        let with_fees = ((value + 50.0) / hodl_value - 1.0) * 100.0;
+        assert!((position.vs_hodl(12.0, 10.0) - with_fees).abs() < 1e-9);
+    }
+
+    #[test]
+    fn vs_hodl_no_liquidity() {
+        let mut position = Position::new(String::new(), Some(U128(1000)), None, 81.0, 121.0, 10.0);
+        position.liquidity = 0.0;
+        position.fees_earned_token1 = 50;
+        assert!(position.vs_hodl(12.0, 10.0) == 0.0);
+    }
+
+    #[test]
+    fn add_liquidity_at_bounds() {
+        let mut position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        let sqrt_lower = position.sqrt_lower_bound_price;
+        let sqrt_upper = position.sqrt_upper_bound_price;
+        position.add_liquidity(Some(U128(50)), None, sqrt_lower);
+        let reference = Position::new(String::new(), Some(U128(100)), None, 121.0, 144.0, 10.0);
+        assert!(position.liquidity == reference.liquidity);
+        assert!(position.token1_locked == 0.0);
+
+        let mut position = Position::new(String::new(), None, Some(U128(50)), 121.0, 144.0, 13.0);
+        position.add_liquidity(None, Some(U128(50)), sqrt_upper);
+        let reference = Position::new(String::new(), None, Some(U128(100)), 121.0, 144.0, 13.0);
+        assert!(position.liquidity == reference.liquidity);
+        assert!(position.token0_locked == 0.0);
+    }
+
+    #[should_panic(expected = "send token0 liquidity instead of token1")]
+    #[test]
+    fn add_liquidity_token1_at_lower_bound() {
+        let mut position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        let sqrt_lower = position.sqrt_lower_bound_price;
+        position.add_liquidity(None, Some(U128(50)), sqrt_lower);
+    }
+
+    #[should_panic(expected = "send token1 liquidity instead of token0")]
+    #[test]
+    fn add_liquidity_token0_at_upper_bound() {
+        let mut position = Position::new(String::new(), None, Some(U128(50)), 121.0, 144.0, 13.0);
+        let sqrt_upper = position.sqrt_upper_bound_price;
+        position.add_liquidity(Some(U128(50)), None, sqrt_upper);
+    }
+
     #[test]
     fn open_position1() {
         let position = Position::new(
@@ -694,6 +1887,40 @@ mod test {
         assert!(position.sqrt_upper_bound_price == 33.16598911754618);
     }
 
+    #[test]
+    fn precision_warning() {
+        let position = Position::new(
+            String::new(),
+            Some(U128(1000000000000000000000000)),
+            None,
+            900.0,
+            1100.0,
+            1000.0_f64.sqrt(),
+        );
+        assert!(
+            position.precision_warning()
+                == Some(PrecisionWarning {
+                    liquidity: true,
+                    token0_locked: true,
+                    token1_locked: true,
+                })
+        );
+        let position = Position::new(String::new(), Some(U128(50)), None, 25.0, 121.0, 10.0);
+        assert!(position.precision_warning().is_none());
+    }
+
+    #[test]
+    fn full_fill_price() {
+        let position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        assert!((position.full_fill_price() / 144.0 - 1.0).abs() < 1e-4);
+        let mut position = Position::new(String::new(), None, Some(U128(50)), 64.0, 81.0, 10.0);
+        assert!((position.full_fill_price() / 64.0 - 1.0).abs() < 1e-4);
+        // partially filled order still reports the bound of the deposited token
+        position.refresh(8.5, 0);
+        assert!(position.token0_locked > 0.0 && position.token1_locked > 0.0);
+        assert!((position.full_fill_price() / 64.0 - 1.0).abs() < 1e-4);
+    }
+
     #[test]
     fn open_position2() {
         let position = Position::new(
@@ -751,20 +1978,43 @@ mod test {
     #[test]
     fn ticks1() {
         let tick = 500;
-        let sqrt_price = tick_to_sqrt_price(tick);
-        let new_tick = sqrt_price_to_tick(sqrt_price);
+        let sqrt_price = tick_to_sqrt_price(tick, BASIS_POINT);
+        let new_tick = sqrt_price_to_tick(sqrt_price, BASIS_POINT);
         assert!(tick == new_tick);
     }
 
+    #[test]
+    fn ticks_round_trip() {
+        for tick in MIN_TICK..=MAX_TICK {
+            assert!(sqrt_price_to_tick(tick_to_sqrt_price(tick, BASIS_POINT), BASIS_POINT) == tick);
+        }
+        assert!(price_to_tick(tick_to_price(-46055, BASIS_POINT), BASIS_POINT) == -46055);
+    }
+
     #[test]
     fn ticks2() {
         let sqrt_price = 10.0;
-        let tick = sqrt_price_to_tick(sqrt_price);
+        let tick = sqrt_price_to_tick(sqrt_price, BASIS_POINT);
         assert!(tick == 46054);
-        let new_sqrt_price = tick_to_sqrt_price(tick + 1);
+        let new_sqrt_price = tick_to_sqrt_price(tick + 1, BASIS_POINT);
         println!("new_sqrt_price = {new_sqrt_price}");
         assert!(new_sqrt_price > sqrt_price);
-        let new_tick = sqrt_price_to_tick(new_sqrt_price);
+        let new_tick = sqrt_price_to_tick(new_sqrt_price, BASIS_POINT);
         assert!(new_tick > tick)
     }
+
+    #[test]
+    fn cashflows_capped() {
+        let mut position = Position::new(String::new(), Some(U128(50)), None, 121.0, 144.0, 10.0);
+        position.record_cashflow(0, -50, 0);
+        for timestamp in 1..=2 * MAX_CASHFLOWS as u64 {
+            position.record_cashflow(timestamp, 1, 1);
+        }
+        assert!(position.cashflows.len() == MAX_CASHFLOWS);
+        // the opening deposit is kept with the latest ones
+        assert!(position.cashflows[0].timestamp == 0);
+        assert!(position.cashflows[1].timestamp == MAX_CASHFLOWS as u64 + 2);
+        assert!(position.cashflows.last().unwrap().timestamp == 2 * MAX_CASHFLOWS as u64);
+        assert!((position.full_fill_price() / 144.0 - 1.0).abs() < 1e-4);
+    }
 }
@@ -1,13 +1,27 @@
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::Deserialize;
 use near_sdk::{env, json_types::U128, near_bindgen, PromiseOrValue};
 
 use crate::*;
 
+// Action passed in `msg` of ft_transfer_call, empty msg is a plain deposit
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+enum TokenReceiverMessage {
+    Deposit,
+    Swap {
+        pool_id: usize,
+        token_out: AccountId,
+        min_out: Option<U128>,
+        sqrt_price_limit: Option<f64>,
+    },
+}
+
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
-    #[allow(unreachable_code)]
-    #[allow(unused_variables)]
+    // The whole amount is always used: it is credited to the sender's balance
+    // and, for a swap, the swap output is credited there as well
     fn ft_on_transfer(
         &mut self,
         sender_id: ValidAccountId,
@@ -15,7 +29,42 @@ impl FungibleTokenReceiver for Contract {
         msg: String,
     ) -> PromiseOrValue<U128> {
         let token_in = env::predecessor_account_id();
-        self.deposit_ft(&sender_id.into(), &token_in, amount.into());
+        assert!(self.is_known_token(&token_in), "{}", UNKNOWN_TOKEN);
+        let message = if msg.is_empty() {
+            TokenReceiverMessage::Deposit
+        } else {
+            near_sdk::serde_json::from_str(&msg).expect(BAD_TRANSFER_MESSAGE)
+        };
+        let sender_id: AccountId = sender_id.into();
+        self.deposit_ft(&sender_id, &token_in, amount.into());
+        if let TokenReceiverMessage::Swap {
+            pool_id,
+            token_out,
+            min_out,
+            sqrt_price_limit,
+        } = message
+        {
+            self.swap_for_account(
+                sender_id,
+                pool_id,
+                token_in,
+                amount,
+                token_out,
+                min_out,
+                sqrt_price_limit,
+            );
+        }
         PromiseOrValue::Value(U128(0))
     }
 }
+
+impl Contract {
+    // Tokens of delisted tokens' existing pools can still be deposited
+    fn is_known_token(&self, token: &AccountId) -> bool {
+        self.whitelisted_tokens.contains(token)
+            || self
+                .pools
+                .iter()
+                .any(|pool| &pool.token0 == token || &pool.token1 == token)
+    }
+}
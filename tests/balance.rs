@@ -1,5 +1,12 @@
-use crate::common::utils::{deposit_tokens, setup_contract, withdraw_tokens};
+use crate::common::utils::{deposit_tokens, setup_contract, unwrap_value, withdraw_tokens};
+use mycelium_lab_near_amm::Contract;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::serde_json;
+use near_sdk::test_utils::{
+    get_created_receipts, testing_env_with_promise_results, VMContextBuilder,
+};
 use near_sdk::MockedBlockchain;
+use near_sdk::PromiseResult;
 use near_sdk::{json_types::U128, test_utils::accounts, testing_env};
 
 mod common;
@@ -142,3 +149,333 @@ fn test_balance_after_two_deposits_two_accounts() {
     assert_eq!(balance3, U128(30000));
     assert_eq!(balance4, U128(40000));
 }
+
+#[test]
+fn test_ft_on_transfer_deposit_action() {
+    let (mut context, mut contract) = setup_contract();
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let unused = contract.ft_on_transfer(
+        accounts(0),
+        U128(10000),
+        r#"{"action": "deposit"}"#.to_string(),
+    );
+    assert!(unwrap_value(unused) == U128(0));
+    let balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(10000));
+}
+
+#[test]
+fn test_ft_on_transfer_swap_action() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let expected_out = contract.get_return(0, &accounts(1).to_string(), U128(100000));
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let msg = format!(
+        r#"{{"action": "swap", "pool_id": 0, "token_out": "{}"}}"#,
+        accounts(2)
+    );
+    let unused = contract.ft_on_transfer(accounts(3), U128(100000), msg);
+    assert!(unwrap_value(unused) == U128(0));
+    let balance1 = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    let balance2 = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert_eq!(balance1, U128(0));
+    // 5 bps of the output go to the LP
+    let expected_out = (expected_out.0 as f64 * (1.0 - 0.0005)).round() as u128;
+    assert_eq!(balance2, U128(expected_out));
+}
+
+#[test]
+#[should_panic(expected = "Token is not accepted by this contract")]
+fn test_ft_on_transfer_unknown_token() {
+    let (mut context, mut contract) = setup_contract();
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.ft_on_transfer(accounts(0), U128(10000), "".to_string());
+}
+
+#[test]
+fn test_ft_on_transfer_delisted_token_with_pool() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.remove_whitelisted_token(accounts(1).to_string());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10000),
+    );
+    let balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(10000));
+}
+
+#[test]
+#[should_panic(expected = "Bad ft_transfer_call message")]
+fn test_ft_on_transfer_bad_message() {
+    let (mut context, mut contract) = setup_contract();
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    contract.ft_on_transfer(
+        accounts(0),
+        U128(10000),
+        r#"{"action": "stake"}"#.to_string(),
+    );
+}
+
+#[test]
+fn test_balance_after_partial_withdraw() {
+    let (mut context, mut contract) = setup_contract();
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10000),
+    );
+    withdraw_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(4000),
+    );
+    let balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(6000));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(vec![]),
+    );
+    contract.resolve_withdraw(accounts(0).to_string(), accounts(1).to_string(), U128(4000));
+    let balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(6000));
+}
+
+#[test]
+fn test_balance_restored_after_failed_withdraw() {
+    let (mut context, mut contract) = setup_contract();
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10000),
+    );
+    withdraw_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(4000),
+    );
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Failed,
+    );
+    contract.resolve_withdraw(accounts(0).to_string(), accounts(1).to_string(), U128(4000));
+    let balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(10000));
+}
+
+#[test]
+fn test_accounted_amount_follows_balances() {
+    let (mut context, mut contract) = setup_contract();
+    let token = accounts(1).to_string();
+    for (account, amount) in [(accounts(0), 10000), (accounts(3), 2500)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account,
+            accounts(1),
+            U128(amount),
+        );
+    }
+    assert_eq!(contract.accounted_amount(&token), 12500);
+    assert_eq!(contract.accounted_amount(&accounts(2).to_string()), 0);
+    withdraw_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(1000),
+    );
+    // tokens of a withdrawal in flight can still come back
+    assert_eq!(contract.accounted_amount(&token), 12500);
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Failed,
+    );
+    contract.resolve_withdraw(accounts(3).to_string(), token.clone(), U128(1000));
+    assert_eq!(contract.accounted_amount(&token), 12500);
+    withdraw_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(1000),
+    );
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(vec![]),
+    );
+    contract.resolve_withdraw(accounts(3).to_string(), token.clone(), U128(1000));
+    assert_eq!(contract.accounted_amount(&token), 11500);
+}
+
+// accounts(3) deposits 100000 of accounts(1) and locks half of it in a position along with
+// accounts(2), then 500 more of accounts(1) are sent to the contract without a deposit
+fn setup_stray_tokens() -> (VMContextBuilder, Contract) {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(100000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(2),
+        U128(6000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.open_position(0, Some(U128(50000)), None, 81.0, 121.0);
+    assert_eq!(contract.accounted_amount(&accounts(1).to_string()), 100000);
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(b"\"100500\"".to_vec()),
+    );
+    (context, contract)
+}
+
+#[test]
+fn test_rescue_stuck_tokens() {
+    let (_context, mut contract) = setup_stray_tokens();
+    contract.resolve_rescue_stuck_tokens(
+        accounts(1).to_string(),
+        U128(500),
+        accounts(0).to_string(),
+    );
+    let transfers = get_created_receipts()
+        .iter()
+        .filter(|receipt| {
+            serde_json::to_string(receipt)
+                .unwrap()
+                .contains("\"method_name\":\"ft_transfer\"")
+        })
+        .count();
+    assert_eq!(transfers, 1);
+    // accounted tokens stay where they are
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    assert_eq!(balance, U128(50000));
+}
+
+#[test]
+#[should_panic(expected = "Amount exceeds the tokens the contract holds above its balances")]
+fn test_rescue_stuck_tokens_above_surplus() {
+    let (_context, mut contract) = setup_stray_tokens();
+    contract.resolve_rescue_stuck_tokens(
+        accounts(1).to_string(),
+        U128(501),
+        accounts(0).to_string(),
+    );
+}
+
+#[test]
+#[should_panic(expected = "Only the owner can call this method")]
+fn test_rescue_stuck_tokens_not_owner() {
+    let (mut context, mut contract) = setup_stray_tokens();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.rescue_stuck_tokens(accounts(1).to_string(), U128(500), accounts(3).to_string());
+}
+
+#[test]
+fn test_rescue_stuck_tokens_keeps_reward_budget() {
+    let (mut context, mut contract) = setup_stray_tokens();
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(1000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.set_reward_token(0, accounts(1).to_string(), 0.001, U128(1000));
+    assert_eq!(contract.accounted_amount(&accounts(1).to_string()), 101000);
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(b"\"101500\"".to_vec()),
+    );
+    contract.resolve_rescue_stuck_tokens(
+        accounts(1).to_string(),
+        U128(500),
+        accounts(0).to_string(),
+    );
+    assert_eq!(contract.get_pool(0).reward_budget, 1000);
+}
+
+#[test]
+#[should_panic(expected = "Amount exceeds the tokens the contract holds above its balances")]
+fn test_rescue_stuck_tokens_reward_budget_above_surplus() {
+    let (mut context, mut contract) = setup_stray_tokens();
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(1000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.set_reward_token(0, accounts(1).to_string(), 0.001, U128(1000));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(b"\"101500\"".to_vec()),
+    );
+    contract.resolve_rescue_stuck_tokens(
+        accounts(1).to_string(),
+        U128(501),
+        accounts(0).to_string(),
+    );
+}
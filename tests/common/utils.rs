@@ -1,6 +1,7 @@
 use mycelium_lab_near_amm::Contract;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::MockedBlockchain;
+use near_sdk::PromiseOrValue;
 use near_sdk::{
     json_types::{ValidAccountId, U128},
     test_utils::{accounts, VMContextBuilder},
@@ -11,7 +12,46 @@ use near_sdk_sim::to_yocto;
 pub fn setup_contract() -> (VMContextBuilder, Contract) {
     let mut context = VMContextBuilder::new();
     testing_env!(context.predecessor_account_id(accounts(0)).build());
-    let contract = Contract::new(accounts(0).to_string());
+    let mut contract = Contract::new(accounts(0).to_string());
+    for i in 0..4 {
+        contract.add_whitelisted_token(accounts(i).to_string());
+    }
+    (context, contract)
+}
+
+// Pool 0 of accounts(1) and accounts(2) at price 100 with 200000 of accounts(1) and 11005078 of
+// accounts(2) deposited for both accounts(0) and accounts(3), and a position of accounts(0) with
+// 100000 of accounts(1) in 81..121
+#[allow(dead_code)]
+pub fn setup_funded_pool() -> (VMContextBuilder, Contract) {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    for account in [accounts(0), accounts(3)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(200000),
+        );
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account,
+            accounts(2),
+            U128(11005078),
+        );
+    }
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
     (context, contract)
 }
 
@@ -43,3 +83,11 @@ pub fn withdraw_tokens(
         .build());
     contract.withdraw(token_id.into(), amount.into());
 }
+
+#[allow(dead_code)]
+pub fn unwrap_value<T>(result: PromiseOrValue<T>) -> T {
+    match result {
+        PromiseOrValue::Value(value) => value,
+        PromiseOrValue::Promise(_) => panic!("Expected a value, got a promise"),
+    }
+}
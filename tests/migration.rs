@@ -0,0 +1,144 @@
+use crate::common::utils::{setup_funded_pool, unwrap_value};
+use mycelium_lab_near_amm::balance::BalancesMap;
+use mycelium_lab_near_amm::migration::{OldContract, OldPool, OldPosition};
+use mycelium_lab_near_amm::{Contract, StorageKey};
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap};
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::MockedBlockchain;
+use near_sdk::{env, json_types::U128, testing_env};
+use std::collections::HashMap;
+
+mod common;
+
+// State of the funded pool written in the old layout, along with the output of a swap of
+// 1000 of accounts(1) by accounts(3) in the pool before the swap
+fn setup_old_state() -> (Contract, u128) {
+    let (mut context, mut contract) = setup_funded_pool();
+    let pool = contract.get_pool(0);
+    let balances: Vec<(String, u128, u128)> = [accounts(0), accounts(3)]
+        .iter()
+        .map(|account| {
+            (
+                account.to_string(),
+                contract
+                    .get_balance(&account.to_string(), &accounts(1).to_string())
+                    .0,
+                contract
+                    .get_balance(&account.to_string(), &accounts(2).to_string())
+                    .0,
+            )
+        })
+        .collect();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let amount: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    // the old state goes to empty storage
+    env::take_blockchain_interface();
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    let mut balances_map: BalancesMap = UnorderedMap::new(b"a");
+    for (account, amount0, amount1) in balances {
+        let mut balance = UnorderedMap::new(account.clone().into_bytes());
+        balance.insert(&accounts(1).to_string(), &amount0);
+        balance.insert(&accounts(2).to_string(), &amount1);
+        balances_map.insert(&account, &balance);
+    }
+    let positions: HashMap<u128, OldPosition> = pool
+        .positions
+        .iter()
+        .map(|(id, position)| {
+            (
+                *id,
+                OldPosition {
+                    owner_id: position.owner_id.clone(),
+                    liquidity: position.liquidity,
+                    token0_locked: position.token0_locked,
+                    token1_locked: position.token1_locked,
+                    tick_lower_bound_price: position.tick_lower_bound_price,
+                    tick_upper_bound_price: position.tick_upper_bound_price,
+                    sqrt_lower_bound_price: position.sqrt_lower_bound_price,
+                    sqrt_upper_bound_price: position.sqrt_upper_bound_price,
+                    is_active: position.is_active,
+                    last_update: position.last_update,
+                    rewards_for_time: position.rewards_for_time,
+                    fees_earned_token0: 0,
+                    fees_earned_token1: 0,
+                },
+            )
+        })
+        .collect();
+    env::state_write(&OldContract {
+        owner_id: accounts(0).to_string(),
+        pools: vec![OldPool {
+            token0: pool.token0,
+            token1: pool.token1,
+            liquidity: pool.liquidity,
+            sqrt_price: pool.sqrt_price,
+            token0_locked: pool.token0_locked,
+            token1_locked: pool.token1_locked,
+            tick: pool.tick,
+            positions,
+            protocol_fee: pool.protocol_fee,
+            rewards: pool.rewards,
+        }],
+        balances_map,
+        tokens_per_owner: LookupMap::new(StorageKey::TokensPerOwner.try_to_vec().unwrap()),
+        tokens_by_id: LookupMap::new(StorageKey::TokensById.try_to_vec().unwrap()),
+        token_metadata_by_id: UnorderedMap::new(
+            StorageKey::TokenMetadataById.try_to_vec().unwrap(),
+        ),
+        metadata: LazyOption::new(StorageKey::NFTContractMetadata.try_to_vec().unwrap(), None),
+        positions_opened: 1,
+    });
+    (Contract::migrate(), amount)
+}
+
+#[test]
+fn migrate_rebuilds_balance_totals() {
+    let (contract, _) = setup_old_state();
+    let pool = contract.get_pool(0);
+    for (token, locked) in [
+        (accounts(1).to_string(), pool.token0_locked),
+        (accounts(2).to_string(), pool.token1_locked),
+    ] {
+        let total: u128 = [accounts(0), accounts(3)]
+            .iter()
+            .map(|account| contract.get_balance(&account.to_string(), &token).0)
+            .sum();
+        assert!(total > 0);
+        assert_eq!(contract.balance_totals.get(&token), Some(total));
+        assert_eq!(contract.accounted_amount(&token), total + locked);
+    }
+}
+
+#[test]
+fn migrate_keeps_pools_swappable() {
+    let (mut contract, amount) = setup_old_state();
+    let pool = contract.get_pool(0);
+    assert_eq!(pool.positions.len(), 1);
+    assert_eq!(pool.rewards, 5);
+    assert_eq!(pool.liquidity_net.len(), 2);
+    assert_eq!(
+        contract.get_pool_by_tokens_and_fee(accounts(1).to_string(), accounts(2).to_string(), 5),
+        Some(0)
+    );
+    let mut context = VMContextBuilder::new();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let migrated_amount: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    assert_eq!(migrated_amount, amount);
+}
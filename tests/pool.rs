@@ -1,13 +1,22 @@
 use std::collections::HashMap;
 
+use mycelium_lab_near_amm::pool::{FeeTier, Pool, ProtocolFeeMode, SwapDirection};
+use mycelium_lab_near_amm::{
+    Contract, MAX_TICK, MIN_POSITION_LIQUIDITY, MIN_TICK, VOLUME_BUCKET_NS,
+};
 use near_sdk::json_types::U128;
 use near_sdk::serde_json;
-use near_sdk::test_utils::accounts;
+use near_sdk::test_utils::{
+    accounts, get_created_receipts, testing_env_with_promise_results, VMContextBuilder,
+};
 use near_sdk::testing_env;
 use near_sdk::MockedBlockchain;
+use near_sdk::{PromiseOrValue, PromiseResult};
 
 use crate::common::utils::deposit_tokens;
 use crate::common::utils::setup_contract;
+use crate::common::utils::setup_funded_pool;
+use crate::common::utils::unwrap_value;
 
 mod common;
 
@@ -19,7 +28,9 @@ fn create_pool() {
         accounts(1).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     let pool = contract.get_pool(0);
     assert!(pool.token0 == accounts(0).to_string());
@@ -29,18 +40,246 @@ fn create_pool() {
     assert!(pool.positions == HashMap::new());
     assert!(pool.sqrt_price == 10.0);
     assert!(pool.protocol_fee == 0);
-    assert!(pool.rewards == 0);
+    assert!(pool.rewards == 5);
 }
 
 #[test]
-fn open_position_is_correct() {
+#[should_panic(expected = "Pool for this token pair and fee tier already exists")]
+fn create_pool_duplicate() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        30,
+        24,
+        24,
+    );
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(0).to_string(),
+        0.01,
+        0,
+        30,
+        24,
+        24,
+    );
+}
+
+#[test]
+fn create_pool_fee_tiers() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        30,
+        24,
+        24,
+    );
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(0).to_string(),
+        0.01,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        100,
+        24,
+        24,
+    );
+    assert!(contract.get_pool(0).tick_spacing == 10);
+    assert!(contract.get_pool(1).tick_spacing == 1);
+    assert!(contract.get_pool(2).tick_spacing == 50);
+    let pool_id =
+        contract.get_pool_by_tokens_and_fee(accounts(1).to_string(), accounts(0).to_string(), 30);
+    assert!(pool_id == Some(0));
+    let pool_id =
+        contract.get_pool_by_tokens_and_fee(accounts(0).to_string(), accounts(1).to_string(), 100);
+    assert!(pool_id == Some(2));
+    // the lowest fee tier is found by the pair alone
+    let pool_id = contract.get_pool_by_tokens(accounts(0).to_string(), accounts(1).to_string());
+    assert!(pool_id == Some(1));
+    contract.remove_pool(1);
+    let pool_id = contract.get_pool_by_tokens(accounts(0).to_string(), accounts(1).to_string());
+    assert!(pool_id == Some(0));
+    let pool_id =
+        contract.get_pool_by_tokens_and_fee(accounts(0).to_string(), accounts(1).to_string(), 5);
+    assert!(pool_id.is_none());
+    let pool_id =
+        contract.get_pool_by_tokens_and_fee(accounts(0).to_string(), accounts(1).to_string(), 100);
+    assert!(pool_id == Some(1));
+}
+
+#[test]
+#[should_panic(expected = "Protocol fee and rewards must be at most 1000 basis points each")]
+fn create_pool_protocol_fee_too_high() {
+    let (_context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        50000,
+        30,
+        24,
+        24,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Pool tokens must be different")]
+fn create_pool_same_tokens() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(0).to_string(),
+        1.0,
+        0,
+        5,
+        24,
+        24,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Token is not whitelisted")]
+fn create_pool_not_whitelisted() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(4).to_string(),
+        1.0,
+        0,
+        5,
+        24,
+        24,
+    );
+}
+
+#[test]
+fn remove_whitelisted_token_keeps_pools() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
+        5,
+        24,
+        24,
+    );
+    contract.remove_whitelisted_token(accounts(2).to_string());
+    let mut whitelisted_tokens = contract.get_whitelisted_tokens();
+    whitelisted_tokens.sort();
+    assert!(
+        whitelisted_tokens
+            == vec![
+                accounts(0).to_string(),
+                accounts(1).to_string(),
+                accounts(3).to_string()
+            ]
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(1000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(2),
+        U128(1000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.open_position(0, Some(U128(1000)), None, 90.0, 110.0);
+    assert!(contract.get_pool(0).positions.len() == 1);
+}
+
+#[test]
+#[should_panic(expected = "Token is not whitelisted")]
+fn remove_whitelisted_token_blocks_new_pools() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.remove_whitelisted_token(accounts(2).to_string());
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+}
+
+#[test]
+fn get_pool_by_tokens() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.create_pool(
+        accounts(2).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    let pool_id = contract.get_pool_by_tokens(accounts(0).to_string(), accounts(1).to_string());
+    assert!(pool_id == Some(0));
+    let pool_id = contract.get_pool_by_tokens(accounts(1).to_string(), accounts(0).to_string());
+    assert!(pool_id == Some(0));
+    let pool_id = contract.get_pool_by_tokens(accounts(1).to_string(), accounts(2).to_string());
+    assert!(pool_id == Some(1));
+    let pool_id = contract.get_pool_by_tokens(accounts(0).to_string(), accounts(2).to_string());
+    assert!(pool_id.is_none());
+    contract.remove_pool(0);
+    let pool_id = contract.get_pool_by_tokens(accounts(0).to_string(), accounts(1).to_string());
+    assert!(pool_id.is_none());
+    let pool_id = contract.get_pool_by_tokens(accounts(2).to_string(), accounts(1).to_string());
+    assert!(pool_id == Some(0));
+}
+
+#[test]
+fn open_position_is_correct() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
         0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -84,7 +323,9 @@ fn open_position_less_than_lower_bound() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -127,7 +368,9 @@ fn open_position_more_than_upper_bound() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -163,14 +406,206 @@ fn open_position_more_than_upper_bound() {
 }
 
 #[test]
-fn open_two_positions() {
+#[should_panic(expected = "Position liquidity is too small")]
+fn open_position_liquidity_too_small() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(2000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(3000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(1)), None, 1.0, 10000.0);
+}
+
+#[test]
+fn open_position_min_liquidity() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(2000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(3000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(10)), None, 1.0, 10000.0);
+    let pool = contract.get_pool(0);
+    assert!(pool.positions.len() == 1);
+    assert!(pool.liquidity >= MIN_POSITION_LIQUIDITY);
+}
+
+#[test]
+fn tokens_for_target_liquidity() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        30,
+        24,
+        24,
+    );
+    let (amount0, amount1) = contract.tokens_for_target_liquidity(0, 1000000.0, 81.0, 121.0);
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        amount0,
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(amount1.0 * 2),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(amount0), None, 81.0, 121.0);
+    let position = contract.get_pool(0).positions.get(&0).unwrap().clone();
+    assert!((position.liquidity - 1000000.0).abs() / 1000000.0 < 0.0001);
+    assert!((position.token1_locked - amount1.0 as f64).abs() / (amount1.0 as f64) < 0.0001);
+}
+
+fn setup_value_pool() -> (VMContextBuilder, Contract) {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(1000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(100000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    (context, contract)
+}
+
+#[test]
+fn add_liquidity_by_value() {
+    let (_context, mut contract) = setup_value_pool();
+    let position_id = contract.add_liquidity_by_value(0, 1000000.0, 2.0, 81.0, 121.0);
+    let pool = contract.get_pool(0);
+    let position = pool.positions.get(&position_id).unwrap();
+    assert!(position.token0_locked > 0.0);
+    assert!(position.token1_locked > 0.0);
+    let value = position.token0_locked * 2.0 + position.token1_locked * 2.0 / 100.0;
+    assert!((value - 1000000.0).abs() / 1000000.0 < 0.0001);
+}
+
+#[test]
+#[should_panic(
+    expected = "Total value and reference price of token0 must be finite positive numbers"
+)]
+fn add_liquidity_by_value_nan_total() {
+    let (_context, mut contract) = setup_value_pool();
+    contract.add_liquidity_by_value(0, f64::NAN, 2.0, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(
+    expected = "Total value and reference price of token0 must be finite positive numbers"
+)]
+fn add_liquidity_by_value_negative_total() {
+    let (_context, mut contract) = setup_value_pool();
+    contract.add_liquidity_by_value(0, -1000000.0, 2.0, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(
+    expected = "Total value and reference price of token0 must be finite positive numbers"
+)]
+fn add_liquidity_by_value_zero_reference_price() {
+    let (_context, mut contract) = setup_value_pool();
+    contract.add_liquidity_by_value(0, 1000000.0, 0.0, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(
+    expected = "Total value and reference price of token0 must be finite positive numbers"
+)]
+fn add_liquidity_by_value_infinite_reference_price() {
+    let (_context, mut contract) = setup_value_pool();
+    contract.add_liquidity_by_value(0, 1000000.0, f64::INFINITY, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(expected = "Position bounds hold no value at the reference prices")]
+fn add_liquidity_by_value_empty_range() {
+    let (_context, mut contract) = setup_value_pool();
+    contract.add_liquidity_by_value(0, 1000000.0, 2.0, 100.0, 100.0);
+}
+
+#[test]
+fn open_two_positions() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
         0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -211,7 +646,9 @@ fn open_three_positions() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -252,7 +689,9 @@ fn open_ten_positions() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -300,7 +739,9 @@ fn close_position() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -337,18 +778,462 @@ fn close_position() {
 }
 
 #[test]
-fn close_two_position() {
+fn fee_shares_after_swap() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    deposit_tokens(
-        &mut context,
+    for (account, token0_liquidity, token1_liquidity, lower_bound_price, upper_bound_price) in [
+        (accounts(0), Some(U128(1000)), None, 81.0, 121.0),
+        (accounts(3), None, Some(U128(100000)), 64.0, 81.0),
+        (accounts(4), Some(U128(5000)), None, 144.0, 169.0),
+    ] {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(5000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(2),
+            U128(200000),
+        );
+        testing_env!(context.predecessor_account_id(account).build());
+        contract.open_position(
+            0,
+            token0_liquidity,
+            token1_liquidity,
+            lower_bound_price,
+            upper_bound_price,
+        );
+    }
+    let shares = contract.fee_shares_after_swap(
+        0,
+        accounts(1).to_string(),
+        U128(100),
+        SwapDirection::Return,
+    );
+    assert!(shares.len() == 1);
+    assert!(shares.get(&accounts(0).to_string()) == Some(&1.0));
+    // price goes below 81 so only the lower position stays in range
+    let shares = contract.fee_shares_after_swap(
+        0,
+        accounts(1).to_string(),
+        U128(1300),
+        SwapDirection::Return,
+    );
+    assert!(shares.len() == 1);
+    assert!(shares.get(&accounts(3).to_string()) == Some(&1.0));
+}
+
+#[test]
+fn simulate_swap_sequence() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(2000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(10000)), None, 81.0, 121.0);
+    let pool_before = contract.get_pool(0);
+    let swaps = vec![(accounts(2).to_string(), U128(10000), SwapDirection::Return); 3];
+    let states = contract.simulate_swap_sequence(0, swaps);
+    assert!(states.len() == 3);
+    assert!(pool_before.sqrt_price < states[0].sqrt_price);
+    assert!(states[0].sqrt_price < states[1].sqrt_price);
+    assert!(states[1].sqrt_price < states[2].sqrt_price);
+    assert!(states[0].token1_locked < states[2].token1_locked);
+    // the same swap moves the sqrt price by the same amount within one liquidity range
+    let first_drift = states[0].sqrt_price - pool_before.sqrt_price;
+    let last_drift = states[2].sqrt_price - states[1].sqrt_price;
+    assert!((first_drift - last_drift).abs() < 1e-9);
+    let pool_after = contract.get_pool(0);
+    assert!(pool_after.sqrt_price == pool_before.sqrt_price);
+    assert!(pool_after.token1_locked == pool_before.token1_locked);
+}
+
+#[test]
+fn get_account_total_liquidity() {
+    let (mut context, mut contract) = setup_contract();
+    for token in [accounts(2), accounts(3)] {
+        contract.create_pool(
+            accounts(1).to_string(),
+            token.to_string(),
+            100.0,
+            0,
+            5,
+            24,
+            24,
+        );
+    }
+    for token in [accounts(1), accounts(2), accounts(3)] {
+        testing_env!(context.predecessor_account_id(token.clone()).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            accounts(0),
+            token,
+            U128(1000000),
+        );
+    }
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(1000)), None, 81.0, 121.0);
+    contract.open_position(1, Some(U128(2000)), None, 90.0, 110.0);
+    let liquidity0 = contract.get_pool(0).positions.get(&0).unwrap().liquidity;
+    let liquidity1 = contract.get_pool(1).positions.get(&1).unwrap().liquidity;
+    let total = contract.get_account_total_liquidity(accounts(0).to_string());
+    assert!(total == liquidity0 + liquidity1);
+    contract.close_position(0, 0);
+    let total = contract.get_account_total_liquidity(accounts(0).to_string());
+    assert!(total == liquidity1);
+    assert!(contract.get_account_total_liquidity(accounts(4).to_string()) == 0.0);
+}
+
+fn setup_compliance_hook_pool() -> (VMContextBuilder, Contract) {
+    let (context, mut contract) = setup_funded_pool();
+    contract.set_compliance_hook(Some(accounts(5).to_string()));
+    (context, contract)
+}
+
+#[test]
+fn compliance_hook_allows_swap() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let result = contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(200000));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&true).unwrap()),
+    );
+    let amount = contract.resolve_compliance_swap(
+        accounts(3).to_string(),
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    assert!(amount.0 > 0);
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(199000));
+}
+
+#[test]
+#[should_panic(expected = "Swap is not allowed by the compliance hook")]
+fn compliance_hook_denies_swap() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&false).unwrap()),
+    );
+    contract.resolve_compliance_swap(
+        accounts(3).to_string(),
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn compliance_hook_allows_swap_exact_out() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let result = contract.swap_exact_out(0, accounts(2).to_string(), U128(1000), U128(100));
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(balance == U128(11005078));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&true).unwrap()),
+    );
+    let amount_in = contract.resolve_compliance_swap_exact_out(
+        accounts(3).to_string(),
+        0,
+        accounts(2).to_string(),
+        U128(1000),
+        U128(100),
+    );
+    assert!(amount_in.0 > 0);
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(balance == U128(11006078));
+}
+
+#[test]
+#[should_panic(expected = "Swap is not allowed by the compliance hook")]
+fn compliance_hook_denies_swap_exact_out() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&false).unwrap()),
+    );
+    contract.resolve_compliance_swap_exact_out(
+        accounts(3).to_string(),
+        0,
+        accounts(2).to_string(),
+        U128(1000),
+        U128(100),
+    );
+}
+
+#[test]
+fn compliance_hook_allows_batch_swap() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    let swaps = vec![(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        SwapDirection::Return,
+    )];
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let result = contract.batch_swap(swaps.clone());
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(200000));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&true).unwrap()),
+    );
+    let results = contract.resolve_compliance_batch_swap(accounts(3).to_string(), swaps);
+    assert!(results.len() == 1);
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(199000));
+}
+
+#[test]
+#[should_panic(expected = "Swap is not allowed by the compliance hook")]
+fn compliance_hook_denies_batch_swap() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Failed,
+    );
+    contract.resolve_compliance_batch_swap(
+        accounts(3).to_string(),
+        vec![(
+            0,
+            accounts(1).to_string(),
+            U128(1000),
+            SwapDirection::Return,
+        )],
+    );
+}
+
+#[test]
+fn compliance_hook_allows_close_to_single_token() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    // liquidity of accounts(3) the position of accounts(0) is swapped against
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    let result = contract.close_to_single_token(0, 0, accounts(2).to_string(), U128(0));
+    assert!(matches!(result, PromiseOrValue::Promise(_)));
+    assert!(contract.get_pool(0).positions.contains_key(&0));
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&true).unwrap()),
+    );
+    let amount = contract.resolve_compliance_close_to_single_token(
+        accounts(0).to_string(),
+        0,
+        0,
+        accounts(2).to_string(),
+        U128(0),
+    );
+    assert!(amount.0 > 0);
+    assert!(!contract.get_pool(0).positions.contains_key(&0));
+}
+
+#[test]
+#[should_panic(expected = "Swap is not allowed by the compliance hook")]
+fn compliance_hook_denies_close_to_single_token() {
+    let (mut context, mut contract) = setup_compliance_hook_pool();
+    testing_env_with_promise_results(
+        context.predecessor_account_id(accounts(0)).build(),
+        PromiseResult::Successful(serde_json::to_vec(&false).unwrap()),
+    );
+    contract.resolve_compliance_close_to_single_token(
+        accounts(0).to_string(),
+        0,
+        0,
+        accounts(2).to_string(),
+        U128(0),
+    );
+}
+
+#[test]
+fn close_to_single_token() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        10,
+        30,
+        24,
+        24,
+    );
+    for account in [accounts(0), accounts(3)] {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(100000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(2),
+            U128(20000000),
+        );
+        testing_env!(context.predecessor_account_id(account).build());
+        contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    }
+    let mut pool = contract.get_pool(0);
+    let position = pool.positions.get(&0).unwrap().clone();
+    let amount0 = position.token0_locked.round() as u128;
+    let amount1 = position.token1_locked.round() as u128;
+    pool.close_position(0);
+    let swap_result =
+        pool.get_swap_result(&accounts(1).to_string(), amount0, SwapDirection::Return);
+    let fees_amount = swap_result.amount * 40.0 / 10000.0;
+    let expected = amount1 + swap_result.amount.round() as u128 - fees_amount.round() as u128;
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    let balance_before: u128 = contract
+        .get_balance(&accounts(0).to_string(), &accounts(2).to_string())
+        .into();
+    let result =
+        unwrap_value(contract.close_to_single_token(0, 0, accounts(2).to_string(), U128(expected)));
+    let balance_after: u128 = contract
+        .get_balance(&accounts(0).to_string(), &accounts(2).to_string())
+        .into();
+    assert!(result == U128(expected));
+    assert!(balance_after - balance_before == expected);
+    assert!(contract.get_pool(0).positions.len() == 1);
+}
+
+// accounts(0) and accounts(3) open the same position, position 0 belongs to accounts(0)
+fn setup_close_to_single_token() -> (VMContextBuilder, Contract) {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        10,
+        30,
+        24,
+        24,
+    );
+    for account in [accounts(0), accounts(3)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(100000),
+        );
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(2),
+            U128(20000000),
+        );
+        testing_env!(context.predecessor_account_id(account).build());
+        contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    }
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    (context, contract)
+}
+
+#[test]
+#[should_panic(expected = "Output amount is less than min_out")]
+fn close_to_single_token_min_out() {
+    let (_context, mut contract) = setup_close_to_single_token();
+    contract.close_to_single_token(0, 0, accounts(2).to_string(), U128(100000000));
+}
+
+#[test]
+#[should_panic(expected = "Pool is paused")]
+fn close_to_single_token_paused() {
+    let (_context, mut contract) = setup_close_to_single_token();
+    contract.set_pool_paused(0, true);
+    contract.close_to_single_token(0, 0, accounts(2).to_string(), U128(0));
+}
+
+#[test]
+#[should_panic(expected = "Swap amount is below the pool minimum")]
+fn close_to_single_token_below_min_swap() {
+    let (_context, mut contract) = setup_close_to_single_token();
+    // the position holds 100000 of token0 to swap
+    contract.set_min_swap_amount(0, Some(U128(100001)));
+    contract.close_to_single_token(0, 0, accounts(2).to_string(), U128(0));
+}
+
+#[test]
+fn close_two_position() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
         &mut contract,
         accounts(0),
         accounts(1),
@@ -395,7 +1280,9 @@ fn get_expense() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -445,7 +1332,9 @@ fn swap_in_token0() {
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -470,27 +1359,32 @@ fn swap_in_token0() {
     assert!(balance1_before == U128(100000));
     assert!(balance2_before == U128(0));
     let amount1 = 100000;
-    let amount2 = contract.swap(
+    let amount2 = unwrap_value(contract.swap(
         0,
         accounts(1).to_string(),
         U128(amount1),
         accounts(2).to_string(),
-    );
+        None,
+        None,
+    ));
     let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
     let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
     assert!(balance1_after == U128(0));
-    assert!(balance2_after == amount2);
+    // the LP fee stays in the pool until the position collects it
+    assert!(balance2_after.0 + contract.get_pool(0).lp_fees_token1 == amount2.0);
 }
 
 #[test]
-fn swap_in_token1() {
+fn swap_min_out() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(1)).build());
     deposit_tokens(
@@ -498,7 +1392,7 @@ fn swap_in_token1() {
         &mut contract,
         accounts(0),
         accounts(1),
-        U128(100000),
+        U128(200000),
     );
     testing_env!(context.predecessor_account_id(accounts(2)).build());
     deposit_tokens(
@@ -506,45 +1400,412 @@ fn swap_in_token1() {
         &mut contract,
         accounts(0),
         accounts(2),
-        U128(11105078),
+        U128(11005078),
     );
     testing_env!(context.predecessor_account_id(accounts(0)).build());
     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-    let balance1_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-    let balance2_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-    assert!(balance1_before == U128(0));
-    println!("balance2_before = {}", balance2_before.0);
-    assert!(balance2_before == U128(100000));
-    let amount1 = 100000;
-    let amount2 = contract.swap(
+    let pool = &contract.pools[0];
+    let swap_result = pool.get_swap_result(&accounts(1).to_string(), 1000, SwapDirection::Return);
+    let (_, fee) = pool.apply_fee(swap_result.amount);
+    let amount_out = swap_result.amount.round() as u128 - fee.round() as u128;
+    let amount = unwrap_value(contract.swap(
         0,
+        accounts(1).to_string(),
+        U128(1000),
         accounts(2).to_string(),
-        U128(amount1),
+        Some(U128(amount_out)),
+        None,
+    ));
+    assert!(amount == U128(swap_result.amount.round() as u128));
+}
+
+#[test]
+#[should_panic(expected = "Not enough tokens")]
+fn swap_token_not_deposited() {
+    let (mut context, mut contract) = setup_funded_pool();
+    // accounts(4) has a balance of accounts(2) but none of accounts(1)
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(4),
+        accounts(2),
+        U128(1000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.swap(
+        0,
         accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
     );
-    let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-    let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-    assert!(balance1_after == amount2);
-    assert!(balance2_after == U128(0));
 }
 
-// #[test]
-// fn swap_out_token0() {
-//     let (mut context, mut contract) = setup_contract();
-//     contract.create_pool(
-//         accounts(1).to_string(),
-//         accounts(2).to_string(),
-//         100.0,
-//         0,
-//         0,
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(1)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(0),
-//         accounts(1),
-//         U128(101000),
+#[test]
+#[should_panic(expected = "Output amount is less than min_out")]
+fn swap_min_out_not_reached() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let pool = &contract.pools[0];
+    let swap_result = pool.get_swap_result(&accounts(1).to_string(), 1000, SwapDirection::Return);
+    let (_, fee) = pool.apply_fee(swap_result.amount);
+    let amount_out = swap_result.amount.round() as u128 - fee.round() as u128;
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        Some(U128(amount_out + 1)),
+        None,
+    );
+}
+
+#[test]
+fn swap_sqrt_price_limit() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(20000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let balance_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    let swap_result = contract.pools[0].get_swap_result_with_limit(
+        &accounts(2).to_string(),
+        5000000,
+        SwapDirection::Return,
+        10.1,
+    );
+    assert!(swap_result.remaining > 0.0);
+    contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(5000000),
+        accounts(1).to_string(),
+        None,
+        Some(10.1),
+    );
+    assert!(contract.get_pool(0).sqrt_price == 10.1);
+    // only the part swapped up to the limit is taken from the balance
+    let balance_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    let swapped = 5000000 - swap_result.remaining.round() as u128;
+    assert!(balance_before.0 - balance_after.0 == swapped);
+}
+
+#[test]
+fn swap_partial_fill() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(1000)), None, 81.0, 121.0);
+    let quote = contract.get_partial_swap_result(
+        0,
+        accounts(2).to_string(),
+        U128(1000000),
+        SwapDirection::Return,
+    );
+    assert!(quote.remaining > 0.0);
+    assert!((quote.amount - 1000.0).abs() < 1.0);
+    let balance_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    // a limit beyond the liquidity of the pool fills as much as there is
+    let amount = unwrap_value(contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(1000000),
+        accounts(1).to_string(),
+        None,
+        Some(12.0),
+    ));
+    assert!(amount == U128(quote.amount.round() as u128));
+    let balance_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    let swapped = 1000000 - quote.remaining.round() as u128;
+    assert!(balance_before.0 - balance_after.0 == swapped);
+    assert!(contract.get_pool(0).sqrt_price == quote.new_sqrt_price);
+}
+
+#[test]
+fn swap_exact_out() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let pool = &contract.pools[0];
+    let swap_result = pool.get_swap_result(&accounts(2).to_string(), 1000, SwapDirection::Expense);
+    let (_, fee) = pool.apply_fee(swap_result.amount);
+    let expected_in = swap_result.amount.round() as u128 + fee.round() as u128;
+    let balance1_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    let amount_in = unwrap_value(contract.swap_exact_out(
+        0,
+        accounts(2).to_string(),
+        U128(1000),
+        U128(expected_in),
+    ));
+    assert!(amount_in == U128(expected_in));
+    let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    // the account is also the only LP, so it gets the LP fee back
+    let lp_fee = contract.pools[0].positions[&0].fees_earned_token0;
+    assert!(balance1_before.0 - balance1_after.0 == expected_in - lp_fee);
+    assert!(balance2_after.0 - balance2_before.0 == 1000);
+}
+
+#[test]
+#[should_panic(expected = "Not enough tokens")]
+fn swap_exact_out_token_not_deposited() {
+    let (mut context, mut contract) = setup_funded_pool();
+    // accounts(4) has a balance of accounts(2) but none of accounts(1) it pays with
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(4),
+        accounts(2),
+        U128(1000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.swap_exact_out(0, accounts(2).to_string(), U128(1000), U128(100));
+}
+
+#[test]
+#[should_panic(expected = "Input amount is more than max_amount_in")]
+fn swap_exact_out_max_amount_in() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let pool = &contract.pools[0];
+    let swap_result = pool.get_swap_result(&accounts(2).to_string(), 1000, SwapDirection::Expense);
+    let (_, fee) = pool.apply_fee(swap_result.amount);
+    let expected_in = swap_result.amount.round() as u128 + fee.round() as u128;
+    contract.swap_exact_out(
+        0,
+        accounts(2).to_string(),
+        U128(1000),
+        U128(expected_in - 1),
+    );
+}
+
+#[test]
+#[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+fn swap_exact_out_more_than_locked() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    contract.swap_exact_out(0, accounts(1).to_string(), U128(100001), U128(u128::MAX));
+}
+
+#[test]
+fn swap_in_token1() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11105078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let balance1_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(balance1_before == U128(0));
+    println!("balance2_before = {}", balance2_before.0);
+    assert!(balance2_before == U128(100000));
+    let amount1 = 100000;
+    let amount2 = unwrap_value(contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(amount1),
+        accounts(1).to_string(),
+        None,
+        None,
+    ));
+    let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(balance1_after == amount2);
+    assert!(balance2_after == U128(0));
+}
+
+// #[test]
+// fn swap_out_token0() {
+//     let (mut context, mut contract) = setup_contract();
+//     contract.create_pool(
+//         accounts(1).to_string(),
+//         accounts(2).to_string(),
+//         100.0,
+//         0,
+//         0,
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(1)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(0),
+//         accounts(1),
+//         U128(101000),
 //     );
 //     testing_env!(context.predecessor_account_id(accounts(2)).build());
 //     deposit_tokens(
@@ -573,683 +1834,2336 @@ fn swap_in_token1() {
 //     assert!(balance2_after == U128(amount1));
 // }
 
-// #[test]
-// fn swap_out_token1() {
-//     let (mut context, mut contract) = setup_contract();
-//     contract.create_pool(
-//         accounts(1).to_string(),
-//         accounts(2).to_string(),
-//         100.0,
-//         0,
-//         0,
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(1)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(0),
-//         accounts(1),
-//         U128(100000),
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(2)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(0),
-//         accounts(2),
-//         U128(22000000),
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(0)).build());
-//     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-//     let balance1_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-//     let balance2_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-//     assert!(balance1_before == U128(0));
-//     assert!(balance2_before == U128(11000000));
-//     let amount1 = 100000;
-//     contract.swap_out(
-//         0,
-//         accounts(2).to_string(),
-//         U128(amount1),
-//         accounts(1).to_string(),
-//     );
-//     let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-//     let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-//     assert_eq!(balance1_after, U128(amount1));
-//     assert_eq!(balance2_after, U128(0));
-// }
+// #[test]
+// fn swap_out_token1() {
+//     let (mut context, mut contract) = setup_contract();
+//     contract.create_pool(
+//         accounts(1).to_string(),
+//         accounts(2).to_string(),
+//         100.0,
+//         0,
+//         0,
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(1)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(0),
+//         accounts(1),
+//         U128(100000),
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(2)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(0),
+//         accounts(2),
+//         U128(22000000),
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(0)).build());
+//     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+//     let balance1_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+//     let balance2_before = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+//     assert!(balance1_before == U128(0));
+//     assert!(balance2_before == U128(11000000));
+//     let amount1 = 100000;
+//     contract.swap_out(
+//         0,
+//         accounts(2).to_string(),
+//         U128(amount1),
+//         accounts(1).to_string(),
+//     );
+//     let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+//     let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+//     assert_eq!(balance1_after, U128(amount1));
+//     assert_eq!(balance2_after, U128(0));
+// }
+
+// #[test]
+// fn fee_test_out() {
+//     let (mut context, mut contract) = setup_contract();
+//     contract.create_pool(
+//         accounts(1).to_string(),
+//         accounts(2).to_string(),
+//         100.0,
+//         100,
+//         100,
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(1)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(0),
+//         accounts(1),
+//         U128(100000),
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(2)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(0),
+//         accounts(2),
+//         U128(11000000),
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(0)).build());
+//     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+//     testing_env!(context.predecessor_account_id(accounts(1)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(3),
+//         accounts(1),
+//         U128(0),
+//     );
+//     testing_env!(context.predecessor_account_id(accounts(2)).build());
+//     deposit_tokens(
+//         &mut context,
+//         &mut contract,
+//         accounts(3),
+//         accounts(2),
+//         U128(11220000),
+//     );
+//     let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+//     let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+//     assert!(balance1_before == U128(0));
+//     assert!(balance2_before == U128(11220000));
+//     let amount1 = 100000;
+//     testing_env!(context.predecessor_account_id(accounts(3)).build());
+//     contract.swap_out(
+//         0,
+//         accounts(2).to_string(),
+//         U128(amount1),
+//         accounts(1).to_string(),
+//     );
+//     let balance1_after = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+//     let balance2_after = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+//     assert_eq!(balance1_after, U128(amount1));
+//     assert_eq!(balance2_after, U128(0));
+//     let balance1_lp_after =
+//         contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+//     let balance2_lp_after =
+//         contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+//     let balance2_before: u128 = balance2_before.into();
+//     let amount2 = (balance2_before as f64 / 1.02) * 0.01;
+//     assert!(balance1_lp_after == U128(0));
+//     let balance2_lp_after: u128 = balance2_lp_after.into();
+//     assert!((balance2_lp_after as f64 - amount2).abs() < 100.0);
+// }
+
+#[test]
+fn fee_test() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        100,
+        100,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11012476),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(0),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(2),
+        U128(100000),
+    );
+    let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(balance1_before == U128(0));
+    assert!(balance2_before == U128(100000));
+    let amount1 = 100000;
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let result: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(amount1),
+        accounts(1).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    let balance1_after: u128 = contract
+        .get_balance(&accounts(3).to_string(), &accounts(1).to_string())
+        .into();
+    let balance2_after: u128 = contract
+        .get_balance(&accounts(3).to_string(), &accounts(2).to_string())
+        .into();
+    let amount2 = result as f64 * 0.98;
+    assert!((balance1_after as f64 - amount2).abs() < 10.0);
+    assert!(balance2_after == 0);
+    let balance1_lp_after: u128 = contract
+        .get_balance(&accounts(0).to_string(), &accounts(1).to_string())
+        .into();
+    let balance2_lp_after: u128 = contract
+        .get_balance(&accounts(0).to_string(), &accounts(2).to_string())
+        .into();
+    let amount3 = result as f64 * 0.01;
+    assert!((balance1_lp_after as f64 - amount3).abs() < 10.0);
+    assert!(balance2_lp_after == 0);
+}
+
+#[test]
+fn collected_fee() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        100,
+        100,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(50000)), None, 81.0, 121.0);
+    contract.open_position(0, Some(U128(50000)), None, 91.0, 111.0);
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(2),
+        U128(100000),
+    );
+    let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
+    let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(balance1_before == U128(100000));
+    assert!(balance2_before == U128(100000));
+    let amount1 = 100000;
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let _pool = &contract.pools[0];
+    let _result: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(amount1),
+        accounts(1).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    let _pool = &contract.pools[0];
+    let _result: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(99001),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    let pool = &contract.pools[0];
+    let position = pool.position_with_fees(0);
+    assert!(position.fees_earned_token0 == 3);
+    println!(
+        "pool.positions[0].fees_earned_token1 = {}",
+        position.fees_earned_token1
+    );
+    assert!(position.fees_earned_token1 == 46121);
+    println!(
+        "pool.positions[0].fees_earned_token1 = {}",
+        position.fees_earned_token1
+    );
+    let position = pool.position_with_fees(1);
+    assert!(position.fees_earned_token0 == 6);
+    println!(
+        "pool.positions[1].fees_earned_token1 = {}",
+        position.fees_earned_token1
+    );
+    assert!(position.fees_earned_token1 == 46398);
+}
+
+#[test]
+fn value_locked_open_close() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        100,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 100000;
+    let initial_balance2 = 11005077;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let pool = &contract.pools[0];
+    assert!(pool.token0_locked == 100000);
+    assert!(pool.token1_locked == 11005078);
+    contract.close_position(0, 0);
+    let pool = &contract.pools[0];
+    assert!(pool.token0_locked == 0);
+    assert!(pool.token1_locked == 0);
+
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let pool = &contract.pools[0];
+    assert!(pool.token0_locked == 100000);
+    assert!(pool.token1_locked == 11005078);
+
+    contract.close_position(0, 1);
+    let pool = &contract.pools[0];
+    assert!(pool.token0_locked == 0);
+    assert!(pool.token1_locked == 0);
+    let final_balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let final_balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(initial_balance1 == final_balance1.0);
+    assert!(((initial_balance2 as f64).abs() - (final_balance2.0 as f64).abs()) <= 1.0);
+}
+
+#[test]
+fn close_position_pays_fees() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        100,
+        24,
+        24,
+    );
+    for account in [accounts(0), accounts(3)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(1000000),
+        );
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account,
+            accounts(2),
+            U128(100000000),
+        );
+    }
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(10000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    let pool = contract.get_pool(0);
+    let position = pool.position_with_fees(0);
+    assert!(position.fees_earned_token1 > 0);
+    let lp = accounts(0).to_string();
+    let balance1 = contract.get_balance(&lp, &accounts(2).to_string());
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.close_position(0, 0);
+    let expected =
+        balance1.0 + position.token1_locked.round() as u128 + position.fees_earned_token1;
+    assert!(contract.get_balance(&lp, &accounts(2).to_string()).0 == expected);
+    // only the rounding dust of the position's fees stays in the pool
+    assert!(contract.get_pool(0).lp_fees_token1 <= 1);
+}
+
+#[test]
+fn collect_position_fees() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        100,
+        24,
+        24,
+    );
+    // nothing else is sent to the contract, so the deposits are all it holds
+    let deposited = [1000000, 100000000];
+    for account in [accounts(0), accounts(3)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(deposited[0]),
+        );
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account,
+            accounts(2),
+            U128(deposited[1]),
+        );
+    }
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    for (token_in, amount, token_out) in [
+        (accounts(1), 10000, accounts(2)),
+        (accounts(2), 1000000, accounts(1)),
+    ] {
+        contract.swap(
+            0,
+            token_in.to_string(),
+            U128(amount),
+            token_out.to_string(),
+            None,
+            None,
+        );
+    }
+    let lp = accounts(0).to_string();
+    let balance0 = contract.get_balance(&lp, &accounts(1).to_string());
+    let balance1 = contract.get_balance(&lp, &accounts(2).to_string());
+    let liquidity = contract.get_pool(0).positions[&0].liquidity;
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    let (fees0, fees1) = contract.collect_position_fees(0, U128(0));
+    assert!(fees0.0 > 0 && fees1.0 > 0);
+    assert!(contract.get_balance(&lp, &accounts(1).to_string()).0 == balance0.0 + fees0.0);
+    assert!(contract.get_balance(&lp, &accounts(2).to_string()).0 == balance1.0 + fees1.0);
+    assert!(contract.get_pool(0).positions[&0].liquidity == liquidity);
+    assert!(contract.collect_position_fees(0, U128(0)) == (U128(0), U128(0)));
+    // balances, locked tokens and fees still held add up to what the contract holds, the
+    // collected fees are not paid twice
+    for (token, deposited) in [(accounts(1), deposited[0]), (accounts(2), deposited[1])] {
+        let accounted = contract.accounted_amount(&token.to_string());
+        assert!(accounted <= 2 * deposited + 1 && accounted + 2 >= 2 * deposited);
+    }
+}
+
+#[test]
+fn value_locked_swap() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 200000;
+    let initial_balance2 = 11005078;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(initial_balance1),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(initial_balance2),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(100000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    contract.close_position(0, 0);
+    let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(balance1.0 == 200000);
+    assert!(balance2.0 == 11005078);
+}
+
+#[test]
+fn value_locked_more_open() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        100,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 100000;
+    let initial_balance2 = 11005100;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(initial_balance1),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(initial_balance2),
+    );
+    for _ in 0..100 {
+        // each open is its own transaction, the mocked gas is counted per environment
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.open_position(0, Some(U128(1000)), None, 81.0, 121.0);
+        let pool = &contract.pools[0];
+        assert!(pool.token0_locked <= initial_balance1);
+        assert!(pool.token1_locked <= initial_balance2);
+    }
+    let pool = &contract.pools[0];
+    assert!(pool.token0_locked == 100000);
+    assert!(pool.token1_locked == 11005100);
+}
+
+#[test]
+fn value_locked_more_swaps() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        10000.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 101000;
+    let initial_balance2 = 10763056;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(initial_balance1),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(initial_balance2),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 9990.0, 11000.0);
+    for _ in 0..10 {
+        contract.swap(
+            0,
+            accounts(1).to_string(),
+            U128(100),
+            accounts(2).to_string(),
+            None,
+            None,
+        );
+        let pool = &contract.pools[0];
+        let position = &pool.positions.get(&0).unwrap();
+        assert!(pool.token0_locked == (position.token0_locked.round() as u128));
+        assert!(pool.token1_locked == (position.token1_locked.round() as u128));
+        assert!(pool.token0_locked <= initial_balance1);
+        assert!(pool.token1_locked <= initial_balance2);
+        let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+        let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+        assert!((balance1.0 + pool.token0_locked) <= initial_balance1);
+        assert!((balance2.0 + pool.token1_locked) <= (initial_balance2 + 2));
+    }
+    contract.close_position(0, 0);
+    let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(balance1.0 <= initial_balance1);
+    assert!(balance2.0 <= (initial_balance2 + 2));
+}
+
+#[test]
+fn add_and_remove_liquidity1() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        10000.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 101000;
+    let initial_balance2 = 10763056;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(initial_balance1),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(initial_balance2),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 9990.0, 11000.0);
+    contract.remove_liquidity(0, U128(0), Some(U128(10000)), None);
+    contract.add_liquidity(0, U128(0), Some(U128(10000)), None);
+    let pool = &contract.pools[0];
+    let position = &pool.positions.get(&0).unwrap();
+    assert!(position.token0_locked.round() == 100000.0);
+}
+
+#[test]
+fn position_cashflow() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        100,
+        24,
+        24,
+    );
+    for account in [accounts(0), accounts(3)] {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account.clone(),
+            accounts(1),
+            U128(200000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            account,
+            accounts(2),
+            U128(20000000),
+        );
+    }
+    let lp = accounts(0).to_string();
+    let balance0_before = contract.get_balance(&lp, &accounts(1).to_string()).0 as i128;
+    let balance1_before = contract.get_balance(&lp, &accounts(2).to_string()).0 as i128;
+    testing_env!(context
+        .predecessor_account_id(accounts(0))
+        .block_timestamp(10)
+        .build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    testing_env!(context
+        .predecessor_account_id(accounts(0))
+        .block_timestamp(20)
+        .build());
+    contract.remove_liquidity(0, U128(0), Some(U128(10000)), None);
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(30)
+        .build());
+    contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(100000),
+        accounts(1).to_string(),
+        None,
+        None,
+    );
+    let balance0_after = contract.get_balance(&lp, &accounts(1).to_string()).0 as i128;
+    let balance1_after = contract.get_balance(&lp, &accounts(2).to_string()).0 as i128;
+    let (token0, token1) = contract.get_position_cashflow(0, U128(0), 0, 100);
+    assert!(token0.0 == balance0_after - balance0_before);
+    assert!(token1.0 == balance1_after - balance1_before);
+    let (token0, token1) = contract.get_position_cashflow(0, U128(0), 15, 25);
+    assert!(token0.0 == 10000);
+    assert!(token1.0 > 0);
+    // fees are recorded once they are paid out, not when swaps earn them
+    let (token0, token1) = contract.get_position_cashflow(0, U128(0), 25, 35);
+    assert!(token0.0 == 0);
+    assert!(token1.0 == 0);
+    testing_env!(context
+        .predecessor_account_id(accounts(0))
+        .block_timestamp(40)
+        .build());
+    let (fees0, fees1) = contract.collect_position_fees(0, U128(0));
+    let (token0, token1) = contract.get_position_cashflow(0, U128(0), 35, 45);
+    assert!(token0.0 > 0 && token0.0 == fees0.0 as i128);
+    assert!(token1.0 == fees1.0 as i128);
+}
+
+fn setup_paused_pool() -> (VMContextBuilder, Contract) {
+    let (context, mut contract) = setup_funded_pool();
+    contract.set_pool_paused(0, true);
+    (context, contract)
+}
+
+#[test]
+#[should_panic(expected = "Pool is paused")]
+fn swap_paused() {
+    let (mut context, mut contract) = setup_paused_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Pool is paused")]
+fn open_position_paused() {
+    let (mut context, mut contract) = setup_paused_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.open_position(0, Some(U128(1000)), None, 81.0, 121.0);
+}
+
+#[test]
+fn set_pool_fees() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    let fees_before = contract
+        .get_pool(0)
+        .position_with_fees(0)
+        .fees_earned_token1;
+    assert!(fees_before > 0);
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.set_pool_fees(0, 10, 30);
+    let pool = contract.get_pool(0);
+    assert!(pool.protocol_fee == 10 && pool.rewards == 30);
+    assert!(pool.position_with_fees(0).fees_earned_token1 == fees_before);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let expected = contract.get_return(0, &accounts(1).to_string(), U128(1000));
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    let pool = contract.get_pool(0);
+    // the only position gets the whole 30 bps LP part of the new fee
+    let lp_fee = (expected.0 as f64 * 30.0 / 10000.0).round() as u128;
+    // earned fees are rounded down to whole units
+    let earned = pool.position_with_fees(0).fees_earned_token1 - fees_before;
+    assert!(earned.abs_diff(lp_fee) <= 1);
+    assert!(pool.protocol_fees_token1 == (expected.0 as f64 * 10.0 / 10000.0).round() as u128);
+}
+
+#[test]
+#[should_panic(expected = "Only the owner can call this method")]
+fn set_pool_paused_not_owner() {
+    let (mut context, mut contract) = setup_paused_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.set_pool_paused(0, false);
+}
+
+#[test]
+fn admin_setters_by_owner_account() {
+    // the owner is another account than the contract itself
+    let mut context = VMContextBuilder::new();
+    testing_env!(context
+        .current_account_id(accounts(5))
+        .predecessor_account_id(accounts(4))
+        .build());
+    let mut contract = Contract::new(accounts(4).to_string());
+    contract.add_whitelisted_token(accounts(1).to_string());
+    contract.add_whitelisted_token(accounts(2).to_string());
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.set_pool_paused(0, true);
+    contract.set_min_swap_amount(0, Some(U128(1000)));
+    contract.set_compliance_hook(Some(accounts(3).to_string()));
+    let pool = contract.get_pool(0);
+    assert!(pool.is_paused);
+    assert!(pool.min_swap_amount == Some(1000));
+    assert!(contract.compliance_hook == Some(accounts(3).to_string()));
+}
+
+#[test]
+#[should_panic(expected = "Only the owner can call this method")]
+fn set_pool_fees_not_owner() {
+    let (mut context, mut contract) = setup_paused_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.set_pool_fees(0, 10, 30);
+}
+
+#[test]
+#[should_panic(expected = "Protocol fee and rewards must be at most 1000 basis points each")]
+fn set_pool_fees_too_high() {
+    let (_context, mut contract) = setup_paused_pool();
+    contract.set_pool_fees(0, 10, 1001);
+}
+
+#[test]
+fn swap_after_unpause() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let amount: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    assert!(amount > 0);
+}
+
+#[test]
+#[should_panic(expected = "Swap amount is below the pool minimum")]
+fn swap_below_minimum() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.set_min_swap_amount(0, Some(U128(1000)));
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(999),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn swap_at_minimum() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.set_min_swap_amount(0, Some(U128(1000)));
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let amount: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    assert!(amount > 0);
+}
+
+fn ft_transfers_scheduled() -> usize {
+    get_created_receipts()
+        .iter()
+        .filter(|receipt| {
+            serde_json::to_string(receipt)
+                .unwrap()
+                .contains("\"method_name\":\"ft_transfer\"")
+        })
+        .count()
+}
+
+#[test]
+fn protocol_fee_immediate() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.pools[0].protocol_fee = 5;
+    contract.set_protocol_fee_mode(0, ProtocolFeeMode::Immediate);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    for swaps in 1..3 {
+        contract.swap(
+            0,
+            accounts(1).to_string(),
+            U128(1000),
+            accounts(2).to_string(),
+            None,
+            None,
+        );
+        assert!(ft_transfers_scheduled() == swaps);
+    }
+    let pool = contract.get_pool(0);
+    assert!(pool.protocol_fees_token0 == 0 && pool.protocol_fees_token1 == 0);
+}
+
+#[test]
+fn protocol_fee_accrue() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.pools[0].protocol_fee = 5;
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let amount: u128 = unwrap_value(contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    ))
+    .into();
+    assert!(ft_transfers_scheduled() == 0);
+    let protocol_fee = contract.get_pool(0).protocol_fees_token1;
+    // half of the 10 bps total fee
+    assert!(protocol_fee > 0);
+    assert!(protocol_fee == (amount as f64 * 5.0 / 10000.0).round() as u128);
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    assert!(contract.get_pool(0).protocol_fees_token1 > protocol_fee);
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.collect_protocol_fees(0);
+    assert!(ft_transfers_scheduled() == 1);
+    assert!(contract.get_pool(0).protocol_fees_token1 == 0);
+}
+
+#[test]
+fn total_fees_generated() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.pools[0].protocol_fee = 5;
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let lp_balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let lp_balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(2000000),
+        accounts(1).to_string(),
+        None,
+        None,
+    );
+    let pool = contract.get_pool(0);
+    assert!(pool.protocol_fees_token0 > 0 && pool.protocol_fees_token1 > 0);
+    // LP fees are held by the pool, not paid to the LP balance
+    assert!(
+        contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string()) == lp_balance1
+    );
+    assert!(
+        contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string()) == lp_balance2
+    );
+    let (lp_fees0, lp_fees1) = (pool.lp_fees_token0, pool.lp_fees_token1);
+    assert!(lp_fees0 > 0 && lp_fees1 > 0);
+    let (total0, total1) = contract.get_pool_total_fees_generated(0);
+    assert!(total0.0 == pool.protocol_fees_token0 + lp_fees0);
+    assert!(total1.0 == pool.protocol_fees_token1 + lp_fees1);
+    // collecting protocol fees doesn't change the lifetime totals
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.collect_protocol_fees(0);
+    assert!(contract.get_pool_total_fees_generated(0) == (total0, total1));
+}
+
+// accounts(3) swaps in a pool with a protocol fee and the owner collects it, returns the
+// collected fee and the amount of token1 accounted before the collect
+fn setup_protocol_fee_collected() -> (VMContextBuilder, Contract, u128, u128) {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    contract.pools[0].protocol_fee = 5;
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    let protocol_fee = contract.get_pool(0).protocol_fees_token1;
+    let accounted = contract.accounted_amount(&accounts(2).to_string());
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.collect_protocol_fees(0);
+    // the fee stays accounted while its transfer is in flight
+    assert!(contract.accounted_amount(&accounts(2).to_string()) == accounted);
+    (context, contract, protocol_fee, accounted)
+}
+
+#[test]
+fn protocol_fee_transfer_failed() {
+    let (context, mut contract, protocol_fee, accounted) = setup_protocol_fee_collected();
+    testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+    contract.resolve_protocol_fee(0, accounts(2).to_string(), U128(protocol_fee));
+    assert!(contract.get_pool(0).protocol_fees_token1 == protocol_fee);
+    assert!(contract.accounted_amount(&accounts(2).to_string()) == accounted);
+}
+
+#[test]
+fn protocol_fee_transfer_succeeded() {
+    let (context, mut contract, protocol_fee, accounted) = setup_protocol_fee_collected();
+    testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+    contract.resolve_protocol_fee(0, accounts(2).to_string(), U128(protocol_fee));
+    assert!(contract.get_pool(0).protocol_fees_token1 == 0);
+    assert!(contract.accounted_amount(&accounts(2).to_string()) == accounted - protocol_fee);
+}
+
+#[test]
+fn close_position_paused() {
+    let (mut context, mut contract) = setup_paused_pool();
+    let balance_before = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    contract.close_position(0, 0);
+    assert!(contract.get_pool(0).positions.is_empty());
+    let balance_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert!(balance_after.0 > balance_before.0);
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.withdraw(accounts(1).to_string(), balance_after);
+}
+
+fn setup_capped_pool() -> (VMContextBuilder, Contract) {
+    let (context, mut contract) = setup_funded_pool();
+    let total_liquidity = contract.get_total_liquidity(0);
+    contract.set_max_total_liquidity(0, Some(total_liquidity));
+    (context, contract)
+}
+
+#[test]
+#[should_panic(expected = "Pool total liquidity cap reached")]
+fn liquidity_cap_reached() {
+    let (mut context, mut contract) = setup_capped_pool();
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.open_position(0, Some(U128(50000)), None, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(expected = "Pool total liquidity cap reached")]
+fn liquidity_cap_reached_add_liquidity() {
+    let (mut context, mut contract) = setup_capped_pool();
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.add_liquidity(0, U128(0), Some(U128(1000)), None);
+}
+
+#[test]
+fn liquidity_cap_raised() {
+    let (mut context, mut contract) = setup_capped_pool();
+    let total_liquidity = contract.get_total_liquidity(0);
+    contract.set_max_total_liquidity(0, Some(total_liquidity * 2.0));
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let position_id = contract.open_position(0, Some(U128(50000)), None, 81.0, 121.0);
+    assert!(contract.get_total_liquidity(0) <= total_liquidity * 2.0);
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.set_max_total_liquidity(0, None);
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
+        accounts(2),
+        U128(11005078),
+    );
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    contract.add_liquidity(0, U128(position_id), Some(U128(100000)), None);
+    assert!(contract.get_total_liquidity(0) > total_liquidity * 2.0);
+}
+
+#[test]
+fn get_volume_buckets() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(20000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let hour = VOLUME_BUCKET_NS;
+    let mut swapped = Vec::new();
+    for timestamp in [hour + 10, 2 * hour - 1, 2 * hour] {
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(timestamp)
+            .build());
+        let amount_out = unwrap_value(contract.swap(
+            0,
+            accounts(2).to_string(),
+            U128(1000),
+            accounts(1).to_string(),
+            None,
+            None,
+        ));
+        swapped.push(amount_out.0);
+    }
+    let buckets = contract.get_volume_buckets(0, hour, 10);
+    assert!(buckets.len() == 2);
+    assert!(buckets[0].start_ts == hour);
+    assert!(buckets[0].volume_token1 == 2000);
+    assert!(buckets[1].start_ts == 2 * hour);
+    assert!(buckets[1].volume_token1 == 1000);
+    assert!(buckets[1].volume_token0 > 0);
+    // amounts out before the fees are taken
+    assert!(buckets[0].volume_token0 >= swapped[0] + swapped[1]);
+    let buckets = contract.get_volume_buckets(0, 24 * hour, 10);
+    assert!(buckets.len() == 1);
+    assert!(buckets[0].volume_token1 == 3000);
+}
+
+#[test]
+fn suggest_swap_slices() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(100000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(20000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let token = accounts(2).to_string();
+    let slices =
+        contract.suggest_swap_slices(0, token.clone(), U128(1000), SwapDirection::Return, 50);
+    assert!(slices == vec![U128(1000)]);
+    let slices =
+        contract.suggest_swap_slices(0, token.clone(), U128(2000000), SwapDirection::Return, 50);
+    assert!(slices.len() > 1);
+    assert!(slices.iter().map(|slice| slice.0).sum::<u128>() == 2000000);
+    let pool = contract.get_pool(0);
+    let price = pool.get_sqrt_price().powi(2);
+    let new_price = pool
+        .price_after_swap(&token, slices[0].0, SwapDirection::Return)
+        .powi(2);
+    assert!(new_price / price - 1.0 <= 0.005);
+    let new_price = pool
+        .price_after_swap(&token, slices[0].0 + 1, SwapDirection::Return)
+        .powi(2);
+    assert!(new_price / price - 1.0 > 0.005);
+}
+
+#[test]
+fn get_position_net_apr() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        100,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(200000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(20000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let day = 24 * 60 * 60 * 1_000_000_000;
+    assert!(contract.get_position_net_apr(0, U128(0), 0) == 0.0);
+    // trading back and forth earns fees without moving the price
+    for _ in 0..10 {
+        let amount_out = unwrap_value(contract.swap(
+            0,
+            accounts(2).to_string(),
+            U128(100000),
+            accounts(1).to_string(),
+            None,
+            None,
+        ));
+        contract.swap(
+            0,
+            accounts(1).to_string(),
+            amount_out,
+            accounts(2).to_string(),
+            None,
+            None,
+        );
+    }
+    assert!(contract.get_position_net_apr(0, U128(0), day) > 0.0);
+    // a large price move loses more to holding than the fees earn
+    contract.swap(
+        0,
+        accounts(2).to_string(),
+        U128(3000000),
+        accounts(1).to_string(),
+        None,
+        None,
+    );
+    assert!(contract.get_position_net_apr(0, U128(0), day) < 0.0);
+}
+
+#[test]
+fn remove_liquidity_outcome() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10u128.pow(24)),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(2 * 10u128.pow(26)),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(10000)), None, 81.0, 121.0);
+    let removed = contract.remove_liquidity(0, U128(0), Some(U128(3000)), None);
+    assert!(removed.requested == U128(3000));
+    assert!(removed.removed == U128(3000));
+    assert!(removed.token0_out == removed.removed);
+    assert!(removed.token1_out.0 > 0);
+    // at this size f64 can't tell the locked amount with 300 tokens less from the original one
+    let id = contract.open_position(0, Some(U128(10u128.pow(24))), None, 81.0, 121.0);
+    let removed = contract.remove_liquidity(0, U128(id), Some(U128(300)), None);
+    assert!(removed.requested == U128(300));
+    assert!(removed.removed.0 < 300);
+    assert!(removed.token0_out == removed.removed);
+}
+
+#[test]
+fn add_and_remove_liquidity_rounding() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(1000000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(100000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    let account = accounts(0).to_string();
+    let balance = |contract: &Contract| {
+        (
+            contract.get_balance(&account, &accounts(1).to_string()).0,
+            contract.get_balance(&account, &accounts(2).to_string()).0,
+        )
+    };
+    let before = balance(&contract);
+    // every round trip takes at least as much as it gives back
+    for amount in [1, 7, 333, 1001] {
+        contract.add_liquidity(0, U128(0), Some(U128(amount)), None);
+        let removed = contract.remove_liquidity(0, U128(0), Some(U128(amount)), None);
+        let after = balance(&contract);
+        assert!(after.0 <= before.0 && after.1 <= before.1);
+        assert!(removed.token0_out == removed.removed);
+        assert!(removed.removed.0 <= amount);
+    }
+    assert!(balance(&contract).0 + 4 >= before.0);
+}
+
+#[test]
+fn add_and_remove_liquidity2() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        10000.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let initial_balance1 = 101000;
+    let initial_balance2 = 10763056;
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(initial_balance1),
+    );
+    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(initial_balance2),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.open_position(0, None, Some(U128(100000)), 9990.0, 11000.0);
+    contract.remove_liquidity(0, U128(0), None, Some(U128(10000)));
+    contract.add_liquidity(0, U128(0), None, Some(U128(10000)));
+    let pool = &contract.pools[0];
+    let position = &pool.positions.get(&0).unwrap();
+    assert!(position.token1_locked.round() == 100000.0);
+}
+
+#[test]
+fn claim_all_rewards() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.set_reward_tokens_per_second(0, U128(100));
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(2),
+        U128(10000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(1000000),
+    );
+    for token in [accounts(1), accounts(2)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            token,
+            U128(1000000000),
+        );
+    }
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(1_000_000_000)
+        .build());
+    let small = contract.open_position(0, Some(U128(1000)), None, 100.0, 121.0);
+    let large = contract.open_position(0, Some(U128(3000)), None, 100.0, 121.0);
+    // a position of another account takes its share and is left alone
+    testing_env!(context
+        .predecessor_account_id(accounts(0))
+        .block_timestamp(1_000_000_000)
+        .build());
+    let other = contract.open_position(0, Some(U128(4000)), None, 100.0, 121.0);
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(11_000_000_000)
+        .build());
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(contract.claim_all_rewards(0) == U128(500));
+    let new_balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(new_balance.0 == balance.0 + 500);
+    let pool = contract.get_pool(0);
+    assert!(pool.positions.get(&small).unwrap().rewards_for_time == 0);
+    assert!(pool.positions.get(&large).unwrap().rewards_for_time == 0);
+    assert!(pool.positions.get(&other).unwrap().rewards_for_time == 10_000_000_000);
+    // nothing is left to claim at the same time
+    assert!(contract.claim_all_rewards(0) == U128(0));
+}
+
+#[test]
+fn open_many_positions() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    for i in 3..103 {
+        let account = format!("\"{i}.testnet\"");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(1),
+            U128(2000000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(2),
+            U128(3000000),
+        );
+        testing_env!(context
+            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
+            .build());
+        for _ in 0..10 {
+            contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
+        }
+    }
+    let pool = &contract.pools[0];
+    println!("len = {}", pool.positions.len());
+    assert!(pool.positions.len() == 1000);
+}
 
-// #[test]
-// fn fee_test_out() {
-//     let (mut context, mut contract) = setup_contract();
-//     contract.create_pool(
-//         accounts(1).to_string(),
-//         accounts(2).to_string(),
-//         100.0,
-//         100,
-//         100,
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(1)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(0),
-//         accounts(1),
-//         U128(100000),
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(2)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(0),
-//         accounts(2),
-//         U128(11000000),
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(0)).build());
-//     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-//     testing_env!(context.predecessor_account_id(accounts(1)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(3),
-//         accounts(1),
-//         U128(0),
-//     );
-//     testing_env!(context.predecessor_account_id(accounts(2)).build());
-//     deposit_tokens(
-//         &mut context,
-//         &mut contract,
-//         accounts(3),
-//         accounts(2),
-//         U128(11220000),
-//     );
-//     let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
-//     let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
-//     assert!(balance1_before == U128(0));
-//     assert!(balance2_before == U128(11220000));
-//     let amount1 = 100000;
-//     testing_env!(context.predecessor_account_id(accounts(3)).build());
-//     contract.swap_out(
-//         0,
-//         accounts(2).to_string(),
-//         U128(amount1),
-//         accounts(1).to_string(),
-//     );
-//     let balance1_after = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
-//     let balance2_after = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
-//     assert_eq!(balance1_after, U128(amount1));
-//     assert_eq!(balance2_after, U128(0));
-//     let balance1_lp_after =
-//         contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-//     let balance2_lp_after =
-//         contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-//     let balance2_before: u128 = balance2_before.into();
-//     let amount2 = (balance2_before as f64 / 1.02) * 0.01;
-//     assert!(balance1_lp_after == U128(0));
-//     let balance2_lp_after: u128 = balance2_lp_after.into();
-//     assert!((balance2_lp_after as f64 - amount2).abs() < 100.0);
-// }
+#[test]
+fn open_many_positions_with_swap1() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    for i in 3..13 {
+        let account = format!("\"{i}.testnet\"");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(1),
+            U128(2000000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(2),
+            U128(3000000),
+        );
+        testing_env!(context
+            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
+            .build());
+        for _ in 0..10 {
+            contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
+        }
+        let amount = unwrap_value(contract.swap(
+            0,
+            accounts(1).to_string(),
+            U128(10),
+            accounts(2).to_string(),
+            None,
+            None,
+        ));
+        unwrap_value(contract.swap(
+            0,
+            accounts(2).to_string(),
+            amount,
+            accounts(1).to_string(),
+            None,
+            None,
+        ));
+    }
+    let pool = &contract.pools[0];
+    println!("len = {}", pool.positions.len());
+    assert!(pool.positions.len() == 100);
+}
+
+#[test]
+fn open_many_positions_with_swap2() {
+    let (mut context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    for i in 3..153 {
+        let account = format!("\"{i}.testnet\"");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(1),
+            U128(2000000),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            serde_json::from_str(account.as_str()).unwrap(),
+            accounts(2),
+            U128(3000000),
+        );
+        testing_env!(context
+            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
+            .build());
+        contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
+        let amount = unwrap_value(contract.swap(
+            0,
+            accounts(1).to_string(),
+            U128(10),
+            accounts(2).to_string(),
+            None,
+            None,
+        ));
+        unwrap_value(contract.swap(
+            0,
+            accounts(2).to_string(),
+            amount,
+            accounts(1).to_string(),
+            None,
+            None,
+        ));
+    }
+    let pool = &contract.pools[0];
+    println!("len = {}", pool.positions.len());
+    assert!(pool.positions.len() == 150);
+}
+
+#[test]
+fn quote_position_active() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
+        100.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    assert!(contract.quote_position_active(0, 81.0, 121.0));
+    assert!(!contract.quote_position_active(0, 121.0, 144.0));
+    assert!(!contract.quote_position_active(0, 64.0, 81.0));
+}
+
+#[test]
+fn open_position_by_ticks() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let id = contract.open_position_by_ticks(0, Some(U128(10000)), None, 43944, 47958);
+    let position = &contract.get_pool(0).positions[&id];
+    assert!(position.tick_lower_bound_price == 43944);
+    assert!(position.tick_upper_bound_price == 47958);
+    assert!(position.owner_id == accounts(3).to_string());
+}
+
+#[test]
+fn open_full_range_position() {
+    let (mut context, mut contract) = setup_paused_pool();
+    contract.set_pool_paused(0, false);
+    testing_env!(context.predecessor_account_id(accounts(3)).build());
+    let balance_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    let id = contract.open_full_range_position(0, Some(U128(1000)), None);
+    let position = &contract.get_pool(0).positions[&id];
+    assert!(position.tick_lower_bound_price == MIN_TICK);
+    assert!(position.tick_upper_bound_price == MAX_TICK);
+    let balance_after = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(balance_before.0 - balance_after.0 == position.token1_locked.round() as u128);
+}
 
 #[test]
-fn fee_test() {
+#[should_panic(expected = "Ticks must be multiples of the pool tick spacing")]
+fn open_position_by_ticks_not_aligned() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
-        100,
-        100,
-    );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(0),
-        accounts(1),
-        U128(100000),
-    );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(0),
-        accounts(2),
-        U128(11005078),
+        0,
+        30,
+        24,
+        24,
     );
     testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(3),
-        accounts(1),
-        U128(0),
+    contract.open_position_by_ticks(0, Some(U128(100000)), None, 43945, 47960);
+}
+
+#[test]
+fn implied_price_through() {
+    let (mut _context, mut contract) = setup_contract();
+    // A = accounts(1), B = accounts(2), C = accounts(3): 1 A = 2 B, 1 B = 3 C
+    let pool_ab = contract.create_pool(
+        accounts(1).to_string(),
+        accounts(2).to_string(),
+        2.0,
+        0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(3),
-        accounts(2),
-        U128(100000),
+    let pool_bc = contract.create_pool(
+        accounts(3).to_string(),
+        accounts(2).to_string(),
+        1.0 / 3.0,
+        0,
+        5,
+        24,
+        24,
     );
-    let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
-    let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
-    assert!(balance1_before == U128(0));
-    assert!(balance2_before == U128(100000));
-    let amount1 = 100000;
-    testing_env!(context.predecessor_account_id(accounts(3)).build());
-    let result: u128 = contract
-        .swap(
-            0,
-            accounts(2).to_string(),
-            U128(amount1),
-            accounts(1).to_string(),
-        )
-        .into();
-    let balance1_after: u128 = contract
-        .get_balance(&accounts(3).to_string(), &accounts(1).to_string())
-        .into();
-    let balance2_after: u128 = contract
-        .get_balance(&accounts(3).to_string(), &accounts(2).to_string())
-        .into();
-    let amount2 = result as f64 * 0.98;
-    assert!((balance1_after as f64 - amount2).abs() < 10.0);
-    assert!(balance2_after == 0);
-    let balance1_lp_after: u128 = contract
-        .get_balance(&accounts(0).to_string(), &accounts(1).to_string())
-        .into();
-    let balance2_lp_after: u128 = contract
-        .get_balance(&accounts(0).to_string(), &accounts(2).to_string())
-        .into();
-    let amount3 = result as f64 * 0.01;
-    assert!((balance1_lp_after as f64 - amount3).abs() < 10.0);
-    assert!(balance2_lp_after == 0);
+    let pool_ac = contract.create_pool(
+        accounts(1).to_string(),
+        accounts(3).to_string(),
+        6.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    let implied = contract.implied_price_through(pool_ab, pool_bc);
+    let direct = contract.get_spot_price(pool_ac);
+    // within the 5 bps fee of the direct pool
+    assert!((implied / direct - 1.0).abs() < 0.0005);
+    // the other way around it is the price of C in A
+    let implied = contract.implied_price_through(pool_bc, pool_ab);
+    assert!((implied * direct - 1.0).abs() < 0.0005);
 }
 
 #[test]
-fn collected_fee() {
-    let (mut context, mut contract) = setup_contract();
+#[should_panic(expected = "Pools must have exactly one token in common")]
+fn implied_price_through_same_pair() {
+    let (mut _context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
+        2.0,
+        0,
+        5,
+        24,
+        24,
+    );
+    contract.pools.push(Pool::new(
+        accounts(2).to_string(),
+        accounts(1).to_string(),
+        0.5,
+        0,
+        FeeTier::Low,
+        24,
+        24,
+    ));
+    contract.implied_price_through(0, 1);
+}
+
+#[test]
+fn tick_to_price_round_trip() {
+    let (mut _context, mut contract) = setup_contract();
+    contract.create_pool(
+        accounts(0).to_string(),
+        accounts(1).to_string(),
         100.0,
-        100,
-        100,
+        0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    for tick in [-46054, -500, 0, 1, 500, 46054] {
+        let price = contract.tick_to_price(0, tick);
+        assert!(contract.price_to_nearest_tick(0, price) == tick);
+    }
+    for price in [0.001, 0.25, 0.99, 1.0, 7.5, 100.0, 5000.0] {
+        let tick_price = contract.tick_to_price(0, contract.price_to_tick(0, price));
+        assert!(tick_price <= price * (1.0 + 1e-12));
+        assert!(price / tick_price < 1.0001);
+    }
+}
+
+fn setup_flash_pool() -> (VMContextBuilder, Contract) {
+    let (mut context, mut contract) = setup_funded_pool();
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.flash(
+        0,
+        accounts(1).to_string(),
+        U128(10000),
+        "arbitrage".to_string(),
+    );
+    let balance = contract.get_balance(&accounts(4).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(10000));
+    (context, contract)
+}
+
+#[test]
+fn flash_repaid() {
+    let (mut context, mut contract) = setup_flash_pool();
+    // the borrower's callback brings the loan back with the 5 bps fee
     deposit_tokens(
         &mut context,
         &mut contract,
-        accounts(0),
+        accounts(4),
         accounts(1),
-        U128(100000),
+        U128(5),
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(0),
-        accounts(2),
-        U128(11000000),
+    let lp_balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.resolve_flash(accounts(4).to_string());
+    let balance = contract.get_balance(&accounts(4).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(0));
+    // the fee is held by the pool for its positions
+    let new_lp_balance = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert!(new_lp_balance == lp_balance);
+    assert!(contract.get_pool(0).lp_fees_token0 == 5);
+    assert!(
+        contract
+            .get_pool(0)
+            .position_with_fees(0)
+            .fees_earned_token0
+            == 5
     );
+}
+
+#[test]
+#[should_panic(expected = "Flash loan of this account is not repaid")]
+fn flash_shortfall() {
+    let (mut context, mut contract) = setup_flash_pool();
+    // the loan is back but not the fee
     testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, Some(U128(50000)), None, 81.0, 121.0);
-    contract.open_position(0, Some(U128(50000)), None, 91.0, 111.0);
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(3),
-        accounts(1),
-        U128(100000),
+    contract.resolve_flash(accounts(4).to_string());
+}
+
+#[test]
+#[should_panic(expected = "Flash loan of this account is not repaid")]
+fn flash_loan_locked_for_withdraw() {
+    let (mut context, mut contract) = setup_flash_pool();
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.withdraw(accounts(1).to_string(), U128(10000));
+}
+
+#[test]
+#[should_panic(expected = "Flash loan of this account is not repaid")]
+fn flash_loan_locked_for_positions() {
+    let (mut context, mut contract) = setup_flash_pool();
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.open_position(0, Some(U128(10000)), None, 81.0, 121.0);
+}
+
+#[test]
+#[should_panic(expected = "Flash loan of this account is not repaid")]
+fn flash_loan_locked_for_swaps() {
+    let (mut context, mut contract) = setup_flash_pool();
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(1000),
+        accounts(2).to_string(),
+        None,
+        None,
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
+}
+
+#[test]
+fn flash_repaid_later() {
+    let (mut context, mut contract) = setup_flash_pool();
     deposit_tokens(
         &mut context,
         &mut contract,
-        accounts(3),
-        accounts(2),
-        U128(100000),
-    );
-    let balance1_before = contract.get_balance(&accounts(3).to_string(), &accounts(1).to_string());
-    let balance2_before = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
-    assert!(balance1_before == U128(100000));
-    assert!(balance2_before == U128(100000));
-    let amount1 = 100000;
-    testing_env!(context.predecessor_account_id(accounts(3)).build());
-    let _pool = &contract.pools[0];
-    let _result: u128 = contract
-        .swap(
-            0,
-            accounts(2).to_string(),
-            U128(amount1),
-            accounts(1).to_string(),
-        )
-        .into();
-    let _pool = &contract.pools[0];
-    let _result: u128 = contract
-        .swap(
-            0,
-            accounts(1).to_string(),
-            U128(99001),
-            accounts(2).to_string(),
-        )
-        .into();
-    let pool = &contract.pools[0];
-    let position = pool.positions.get(&0).unwrap();
-    assert!(position.fees_earned_token0 == 4);
-    println!(
-        "pool.positions[0].fees_earned_token1 = {}",
-        position.fees_earned_token1
-    );
-    assert!(position.fees_earned_token1 == 46522);
-    println!(
-        "pool.positions[0].fees_earned_token1 = {}",
-        position.fees_earned_token1
-    );
-    let position = pool.positions.get(&1).unwrap();
-    assert!(position.fees_earned_token0 == 6);
-    println!(
-        "pool.positions[1].fees_earned_token1 = {}",
-        position.fees_earned_token1
+        accounts(4),
+        accounts(1),
+        U128(105),
     );
-    assert!(position.fees_earned_token1 == 46007);
+    testing_env!(context.predecessor_account_id(accounts(4)).build());
+    contract.repay_flash_loan();
+    let balance = contract.get_balance(&accounts(4).to_string(), &accounts(1).to_string());
+    assert!(balance == U128(100));
+    contract.withdraw(accounts(1).to_string(), U128(100));
 }
 
 #[test]
-fn value_locked_open_close() {
+fn limit_order_filled_and_closed() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
-        100,
-        100,
+        0,
+        30,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 100000;
-    let initial_balance2 = 11005077;
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
         accounts(1),
-        U128(100000),
+        U128(200000),
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
         accounts(2),
-        U128(11005078),
+        U128(11100000),
     );
     testing_env!(context.predecessor_account_id(accounts(0)).build());
     contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-    let pool = &contract.pools[0];
-    assert!(pool.token0_locked == 100000);
-    assert!(pool.token1_locked == 11005078);
-    contract.close_position(0, 0);
-    let pool = &contract.pools[0];
-    assert!(pool.token0_locked == 0);
-    assert!(pool.token1_locked == 0);
-
-    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
-    let pool = &contract.pools[0];
-    assert!(pool.token0_locked == 100000);
-    assert!(pool.token1_locked == 11005078);
-
-    contract.close_position(0, 1);
-    let pool = &contract.pools[0];
-    assert!(pool.token0_locked == 0);
-    assert!(pool.token1_locked == 0);
-    let final_balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-    let final_balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-    assert!(initial_balance1 == final_balance1.0);
-    assert!(((initial_balance2 as f64).abs() - (final_balance2.0 as f64).abs()) <= 1.0);
+    let order_id = contract.open_limit_order(0, None, Some(U128(10000)), 90.0);
+    assert!(contract.get_filled_limit_orders(0).is_empty());
+    contract.swap(
+        0,
+        accounts(1).to_string(),
+        U128(80000),
+        accounts(2).to_string(),
+        None,
+        None,
+    );
+    assert!(contract.get_filled_limit_orders(0) == vec![U128(order_id)]);
+    let position = contract
+        .get_pool(0)
+        .positions
+        .get(&order_id)
+        .unwrap()
+        .clone();
+    assert!(position.token1_locked == 0.0);
+    let balance0 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    contract.close_position(0, order_id);
+    let new_balance0 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    assert!(new_balance0.0 == balance0.0 + position.token0_locked.round() as u128);
+    assert!(contract.get_filled_limit_orders(0).is_empty());
 }
 
-#[test]
-fn value_locked_swap() {
-    let (mut context, mut contract) = setup_contract();
-    contract.create_pool(
+// create_pool allows a single pool per token pair, the second one is added directly
+fn setup_best_of_pools() -> (VMContextBuilder, Contract) {
+    let (mut context, mut contract) = setup_funded_pool();
+    contract.pools.push(Pool::new(
         accounts(1).to_string(),
         accounts(2).to_string(),
-        100.0,
-        0,
+        110.0,
         0,
-    );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 200000;
-    let initial_balance2 = 11005078;
+        FeeTier::Low,
+        24,
+        24,
+    ));
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
         accounts(1),
-        U128(initial_balance1),
+        U128(1000000000000),
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
         accounts(2),
-        U128(initial_balance2),
+        U128(1000000000000),
     );
     testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, Some(U128(100000)), None, 81.0, 121.0);
+    contract.open_position(1, Some(U128(100000)), None, 81.0, 144.0);
+    (context, contract)
+}
+
+#[test]
+fn batch_swap() {
+    let (_context, mut contract) = setup_best_of_pools();
+    let price0 = contract.get_price(0);
+    let price1 = contract.get_price(1);
+    let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    let expected_out = contract.get_return(0, &accounts(1).to_string(), U128(1000));
+    let expected_in = contract.get_expense(1, &accounts(1).to_string(), U128(500));
+    let results = unwrap_value(contract.batch_swap(vec![
+        (
+            0,
+            accounts(1).to_string(),
+            U128(1000),
+            SwapDirection::Return,
+        ),
+        (
+            1,
+            accounts(1).to_string(),
+            U128(500),
+            SwapDirection::Expense,
+        ),
+    ]));
+    assert!(results.len() == 2);
+    assert!(results[0].amount.round() as u128 == expected_out.0);
+    assert!(results[1].amount.round() as u128 == expected_in.0);
+    // token0 is sold to the first pool and bought from the second one
+    assert!(contract.get_price(0) < price0);
+    assert!(contract.get_price(1) > price1);
+    let balance1_after = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
+    let balance2_after = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(balance1_after.0 == balance1.0 - 1000 + 500);
+    // both fees are paid in token1 and stay in the pools until the positions collect them
+    let lp_fees = contract.get_pool(0).lp_fees_token1 + contract.get_pool(1).lp_fees_token1;
+    let expected_balance2 = balance2.0 + expected_out.0 - expected_in.0 - lp_fees;
+    assert!((balance2_after.0 as i128 - expected_balance2 as i128).abs() <= 2);
+}
+
+// the runtime reverts the swaps before the one that fails
+#[test]
+#[should_panic(expected = "Not enough liquidity in pool to cover this swap")]
+fn batch_swap_rolled_back() {
+    let (_context, mut contract) = setup_best_of_pools();
+    contract.batch_swap(vec![
+        (
+            0,
+            accounts(1).to_string(),
+            U128(1000),
+            SwapDirection::Return,
+        ),
+        // more than the second pool holds
+        (
+            1,
+            accounts(2).to_string(),
+            U128(100000000000),
+            SwapDirection::Return,
+        ),
+    ]);
+}
+
+#[test]
+#[should_panic(expected = "Swap amount is below the pool minimum")]
+fn batch_swap_below_minimum() {
+    let (_context, mut contract) = setup_best_of_pools();
+    // the input of the second swap is about 55000 of token2
+    contract.set_min_swap_amount(1, Some(U128(100000)));
+    contract.batch_swap(vec![
+        (
+            0,
+            accounts(1).to_string(),
+            U128(1000),
+            SwapDirection::Return,
+        ),
+        (
+            1,
+            accounts(1).to_string(),
+            U128(500),
+            SwapDirection::Expense,
+        ),
+    ]);
+}
+
+#[test]
+fn swap_best_of_live_prices() {
+    let (_context, mut contract) = setup_best_of_pools();
+    // token1 is more expensive in the second pool
+    let price0 = contract.get_price(0);
+    let price1 = contract.get_price(1);
+    contract.swap_best_of(vec![0, 1], accounts(1).to_string(), U128(1000), U128(0));
+    assert!(contract.get_price(0) == price0);
+    assert!(contract.get_price(1) < price1);
+    // the price of the second pool drops below the first one
     contract.swap(
-        0,
+        1,
         accounts(1).to_string(),
-        U128(100000),
+        U128(50000),
         accounts(2).to_string(),
+        None,
+        None,
     );
-    contract.close_position(0, 0);
-    let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-    let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-    assert!(balance1.0 == 200000);
-    assert!(balance2.0 == 11005078);
+    assert!(contract.get_price(1) < contract.get_price(0));
+    let price1 = contract.get_price(1);
+    contract.swap_best_of(vec![0, 1], accounts(1).to_string(), U128(1000), U128(0));
+    assert!(contract.get_price(0) < price0);
+    assert!(contract.get_price(1) == price1);
 }
 
 #[test]
-fn value_locked_more_open() {
+#[should_panic(expected = "Output amount is less than min_out")]
+fn swap_best_of_min_out() {
+    let (_context, mut contract) = setup_best_of_pools();
+    contract.swap_best_of(
+        vec![0, 1],
+        accounts(1).to_string(),
+        U128(1000),
+        U128(110000),
+    );
+}
+
+#[test]
+fn claim_rewards_proportional() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
-        100,
-        100,
+        0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 100000;
-    let initial_balance2 = 11005100;
+    contract.set_reward_tokens_per_second(0, U128(100));
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
+        accounts(2),
+        U128(10000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(3),
         accounts(1),
-        U128(initial_balance1),
+        U128(1000000000),
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
     deposit_tokens(
         &mut context,
         &mut contract,
-        accounts(0),
+        accounts(3),
         accounts(2),
-        U128(initial_balance2),
+        U128(1000000000),
     );
-    testing_env!(context.predecessor_account_id(accounts(0)).build());
-    for _ in 0..100 {
-        contract.open_position(0, Some(U128(1000)), None, 81.0, 121.0);
-        let pool = &contract.pools[0];
-        assert!(pool.token0_locked <= initial_balance1);
-        assert!(pool.token1_locked <= initial_balance2);
-    }
-    let pool = &contract.pools[0];
-    assert!(pool.token0_locked == 100000);
-    assert!(pool.token1_locked == 11005078);
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(1_000_000_000)
+        .build());
+    let small = contract.open_position(0, Some(U128(1000)), None, 100.0, 121.0);
+    let large = contract.open_position(0, Some(U128(3000)), None, 100.0, 121.0);
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(11_000_000_000)
+        .build());
+    let balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    let small_reward = contract.claim_rewards(0, U128(small));
+    let large_reward = contract.claim_rewards(0, U128(large));
+    assert!(small_reward == U128(250));
+    assert!(large_reward == U128(750));
+    let new_balance = contract.get_balance(&accounts(3).to_string(), &accounts(2).to_string());
+    assert!(new_balance.0 == balance.0 + 1000);
+    let owner_balance = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(owner_balance == U128(9000));
+    let pool = contract.get_pool(0);
+    assert!(pool.positions.get(&small).unwrap().rewards_for_time == 0);
+    assert!(pool.positions.get(&large).unwrap().rewards_for_time == 0);
 }
 
 #[test]
-fn value_locked_more_swaps() {
+#[should_panic(expected = "Owner's balance of token1 is less than the reward")]
+fn claim_rewards_owner_short() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
-        10000.0,
-        0,
+        100.0,
         0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 101000;
-    let initial_balance2 = 10763056;
+    contract.set_reward_tokens_per_second(0, U128(100));
+    // the owner has a balance of token0 only
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
         accounts(1),
-        U128(initial_balance1),
-    );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(0),
-        accounts(2),
-        U128(initial_balance2),
+        U128(10000),
     );
-    testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, Some(U128(100000)), None, 9990.0, 11000.0);
-    for _ in 0..10 {
-        contract.swap(
-            0,
-            accounts(1).to_string(),
-            U128(100),
-            accounts(2).to_string(),
+    for token in [accounts(1), accounts(2)] {
+        deposit_tokens(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            token,
+            U128(1000000000),
         );
-        let pool = &contract.pools[0];
-        let position = &pool.positions.get(&0).unwrap();
-        assert!(pool.token0_locked == (position.token0_locked.round() as u128));
-        assert!(pool.token1_locked == (position.token1_locked.round() as u128));
-        assert!(pool.token0_locked <= initial_balance1);
-        assert!(pool.token1_locked <= initial_balance2);
-        let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-        let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-        assert!((balance1.0 + pool.token0_locked) <= initial_balance1);
-        assert!((balance2.0 + pool.token1_locked) <= (initial_balance2 + 2));
     }
-    contract.close_position(0, 0);
-    let balance1 = contract.get_balance(&accounts(0).to_string(), &accounts(1).to_string());
-    let balance2 = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
-    assert!(balance1.0 <= initial_balance1);
-    assert!(balance2.0 <= (initial_balance2 + 2));
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(1_000_000_000)
+        .build());
+    let position_id = contract.open_position(0, Some(U128(1000)), None, 100.0, 121.0);
+    testing_env!(context
+        .predecessor_account_id(accounts(3))
+        .block_timestamp(11_000_000_000)
+        .build());
+    contract.claim_rewards(0, U128(position_id));
 }
 
 #[test]
-fn add_and_remove_liquidity1() {
+fn claim_rewards_reward_token() {
     let (mut context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
-        10000.0,
-        0,
+        100.0,
         0,
+        5,
+        24,
+        24,
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 101000;
-    let initial_balance2 = 10763056;
-    deposit_tokens(
-        &mut context,
-        &mut contract,
-        accounts(0),
-        accounts(1),
-        U128(initial_balance1),
-    );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
+    // accounts(0) is the owner funding the budget in accounts(3) tokens
     deposit_tokens(
         &mut context,
         &mut contract,
         accounts(0),
-        accounts(2),
-        U128(initial_balance2),
-    );
-    testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, Some(U128(100000)), None, 9990.0, 11000.0);
-    contract.remove_liquidity(0, U128(0), Some(U128(10000)), None);
-    contract.add_liquidity(0, U128(0), Some(U128(10000)), None);
-    let pool = &contract.pools[0];
-    let position = &pool.positions.get(&0).unwrap();
-    assert!(position.token0_locked.round() == 100000.0);
-}
-
-#[test]
-fn add_and_remove_liquidity2() {
-    let (mut context, mut contract) = setup_contract();
-    contract.create_pool(
-        accounts(1).to_string(),
-        accounts(2).to_string(),
-        10000.0,
-        0,
-        0,
+        accounts(3),
+        U128(1000000),
     );
-    testing_env!(context.predecessor_account_id(accounts(1)).build());
-    let initial_balance1 = 101000;
-    let initial_balance2 = 10763056;
     deposit_tokens(
         &mut context,
         &mut contract,
-        accounts(0),
+        accounts(4),
         accounts(1),
-        U128(initial_balance1),
+        U128(1000000000),
     );
-    testing_env!(context.predecessor_account_id(accounts(2)).build());
     deposit_tokens(
         &mut context,
         &mut contract,
-        accounts(0),
+        accounts(4),
         accounts(2),
-        U128(initial_balance2),
+        U128(1000),
     );
     testing_env!(context.predecessor_account_id(accounts(0)).build());
-    contract.open_position(0, None, Some(U128(100000)), 9990.0, 11000.0);
-    contract.remove_liquidity(0, U128(0), None, Some(U128(10000)));
-    contract.add_liquidity(0, U128(0), None, Some(U128(10000)));
-    let pool = &contract.pools[0];
-    let position = &pool.positions.get(&0).unwrap();
-    assert!(position.token1_locked.round() == 100000.0);
+    contract.set_reward_token(0, accounts(3).to_string(), 0.001, U128(600000));
+    let owner_balance = contract.get_balance(&accounts(0).to_string(), &accounts(3).to_string());
+    assert!(owner_balance == U128(400000));
+    testing_env!(context
+        .predecessor_account_id(accounts(4))
+        .block_timestamp(1_000_000_000)
+        .build());
+    let small = contract.open_position(0, Some(U128(1000)), None, 100.0, 121.0);
+    let large = contract.open_position(0, Some(U128(3000)), None, 100.0, 121.0);
+    testing_env!(context
+        .predecessor_account_id(accounts(4))
+        .block_timestamp(11_000_000_000)
+        .build());
+    let small_reward = contract.claim_rewards(0, U128(small));
+    let large_reward = contract.claim_rewards(0, U128(large));
+    let pool = contract.get_pool(0);
+    let small_liquidity = pool.positions.get(&small).unwrap().liquidity;
+    assert!(small_reward.0 == (10.0 * small_liquidity * 0.001).round() as u128);
+    assert!((large_reward.0 as f64 / small_reward.0 as f64 - 3.0).abs() < 0.01);
+    let balance = contract.get_balance(&accounts(4).to_string(), &accounts(3).to_string());
+    assert!(balance.0 == small_reward.0 + large_reward.0);
+    assert!(pool.reward_budget == 600000 - balance.0);
+    // token1 of the owner is not touched
+    let owner_balance = contract.get_balance(&accounts(0).to_string(), &accounts(2).to_string());
+    assert!(owner_balance == U128(0));
+    // a new budget returns what is left of the old one
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    contract.set_reward_token(0, accounts(3).to_string(), 0.001, U128(0));
+    let owner_balance = contract.get_balance(&accounts(0).to_string(), &accounts(3).to_string());
+    assert!(owner_balance.0 == 1000000 - balance.0);
 }
 
 #[test]
-fn open_many_positions() {
-    let (mut context, mut contract) = setup_contract();
+#[should_panic(expected = "Owner's balance of the reward token is less than the budget")]
+fn set_reward_token_not_enough_budget() {
+    let (_context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
-    for i in 3..103 {
-        let account = format!("\"{i}.testnet\"");
-        testing_env!(context.predecessor_account_id(accounts(1)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(1),
-            U128(2000000),
-        );
-        testing_env!(context.predecessor_account_id(accounts(2)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(2),
-            U128(3000000),
-        );
-        testing_env!(context
-            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
-            .build());
-        for _ in 0..10 {
-            contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
-        }
-    }
-    let pool = &contract.pools[0];
-    println!("len = {}", pool.positions.len());
-    assert!(pool.positions.len() == 1000);
+    contract.set_reward_token(0, accounts(3).to_string(), 0.001, U128(1));
 }
 
 #[test]
-fn open_many_positions_with_swap1() {
+fn pool_tick_base() {
     let (mut context, mut contract) = setup_contract();
-    contract.create_pool(
-        accounts(1).to_string(),
-        accounts(2).to_string(),
-        100.0,
-        0,
-        0,
-    );
-    for i in 3..13 {
-        let account = format!("\"{i}.testnet\"");
-        testing_env!(context.predecessor_account_id(accounts(1)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(1),
-            U128(2000000),
-        );
-        testing_env!(context.predecessor_account_id(accounts(2)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(2),
-            U128(3000000),
-        );
-        testing_env!(context
-            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
-            .build());
-        for _ in 0..10 {
-            contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
-        }
-        let amount = contract.swap(
-            0,
+    for token in [accounts(2), accounts(3)] {
+        contract.create_pool(
             accounts(1).to_string(),
-            U128(10),
-            accounts(2).to_string(),
+            token.to_string(),
+            100.0,
+            0,
+            5,
+            24,
+            24,
         );
-        contract.swap(0, accounts(2).to_string(), amount, accounts(1).to_string());
     }
-    let pool = &contract.pools[0];
-    println!("len = {}", pool.positions.len());
-    assert!(pool.positions.len() == 100);
+    contract.set_pool_tick_base(1, 1.0002);
+    assert!(contract.price_to_tick(0, 100.0) == 46054);
+    assert!(contract.price_to_tick(1, 100.0) == 23028);
+    let price = contract.tick_to_price(1, 23028);
+    assert!(price <= 100.0 && 100.0 < price * 1.0002);
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(1),
+        U128(10000),
+    );
+    deposit_tokens(
+        &mut context,
+        &mut contract,
+        accounts(0),
+        accounts(3),
+        U128(1000000),
+    );
+    testing_env!(context.predecessor_account_id(accounts(0)).build());
+    let position_id = contract.open_position(1, Some(U128(1000)), None, 90.0, 110.0);
+    let pool = contract.get_pool(1);
+    let position = pool.positions.get(&position_id).unwrap();
+    assert!(position.tick_base == 1.0002);
+    assert!(position.tick_lower_bound_price % pool.tick_spacing == 0);
+    assert!(contract.tick_to_price(1, position.tick_lower_bound_price) <= 90.0);
+    let tick_above_upper = position.tick_upper_bound_price + pool.tick_spacing;
+    assert!(contract.tick_to_price(1, tick_above_upper) > 110.0);
+    assert!(contract.quote_position_active(1, 90.0, 110.0));
 }
 
 #[test]
-fn open_many_positions_with_swap2() {
-    let (mut context, mut contract) = setup_contract();
+#[should_panic(expected = "Tick base must be from 1.00001 to 1.0005")]
+fn set_pool_tick_base_out_of_range() {
+    let (_context, mut contract) = setup_contract();
     contract.create_pool(
         accounts(1).to_string(),
         accounts(2).to_string(),
         100.0,
         0,
-        0,
+        5,
+        24,
+        24,
     );
-    for i in 3..153 {
-        let account = format!("\"{i}.testnet\"");
-        testing_env!(context.predecessor_account_id(accounts(1)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(1),
-            U128(2000000),
-        );
-        testing_env!(context.predecessor_account_id(accounts(2)).build());
-        deposit_tokens(
-            &mut context,
-            &mut contract,
-            serde_json::from_str(account.as_str()).unwrap(),
-            accounts(2),
-            U128(3000000),
-        );
-        testing_env!(context
-            .predecessor_account_id(serde_json::from_str(account.as_str()).unwrap())
-            .build());
-        contract.open_position(0, Some(U128(50)), None, 64.0, 121.0);
-        let amount = contract.swap(
-            0,
-            accounts(1).to_string(),
-            U128(10),
-            accounts(2).to_string(),
-        );
-        contract.swap(0, accounts(2).to_string(), amount, accounts(1).to_string());
-    }
-    let pool = &contract.pools[0];
-    println!("len = {}", pool.positions.len());
-    assert!(pool.positions.len() == 150);
+    contract.set_pool_tick_base(0, 1.01);
 }